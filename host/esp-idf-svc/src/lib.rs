@@ -0,0 +1,284 @@
+//! Host stand-ins for the ESP-IDF calls the signing code makes.
+//!
+//! NVS is one in-memory namespace per thread, so tests running side by side never see each
+//! other's values; the RNG reads the OS, and the two buttons replay presses queued with
+//! `hal::gpio::script`.
+
+#![allow(non_camel_case_types, clippy::missing_safety_doc)]
+
+pub mod sys {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::ffi::{c_char, c_void, CStr};
+    use std::io::Read;
+
+    pub type esp_err_t = i32;
+    pub type nvs_handle_t = u32;
+    pub type nvs_open_mode_t = u32;
+
+    pub const ESP_OK: u32 = 0;
+    pub const ESP_ERR_INVALID_SIZE: u32 = 0x104;
+    pub const ESP_ERR_NVS_NOT_FOUND: u32 = 0x1102;
+    pub const ESP_ERR_NVS_TYPE_MISMATCH: u32 = 0x1103;
+    pub const ESP_ERR_NVS_NOT_ENOUGH_SPACE: u32 = 0x1105;
+    pub const ESP_ERR_NVS_INVALID_LENGTH: u32 = 0x110c;
+
+    /// Stored values, tagged with their NVS type so a typed read of the wrong kind fails
+    #[derive(Clone)]
+    enum Value {
+        Str(Vec<u8>),
+        Blob(Vec<u8>),
+        U32(u32),
+    }
+
+    thread_local! {
+        static STORE: RefCell<HashMap<String, Value>> = RefCell::new(HashMap::new());
+    }
+
+    fn with_store<R>(f: impl FnOnce(&mut HashMap<String, Value>) -> R) -> R {
+        STORE.with(|store| f(&mut store.borrow_mut()))
+    }
+
+    unsafe fn key(key: *const c_char) -> String {
+        CStr::from_ptr(key).to_string_lossy().into_owned()
+    }
+
+    /// Copy `value` out the way NVS does: a null buffer only asks for the length
+    unsafe fn read_out(value: &[u8], out: *mut u8, len: *mut usize) -> esp_err_t {
+        if !out.is_null() {
+            if *len < value.len() {
+                return ESP_ERR_NVS_INVALID_LENGTH as esp_err_t;
+            }
+            std::ptr::copy_nonoverlapping(value.as_ptr(), out, value.len());
+        }
+        *len = value.len();
+        0
+    }
+
+    fn not_found_or_mismatch(value: Option<&Value>) -> esp_err_t {
+        match value {
+            Some(_) => ESP_ERR_NVS_TYPE_MISMATCH as esp_err_t,
+            None => ESP_ERR_NVS_NOT_FOUND as esp_err_t,
+        }
+    }
+
+    pub unsafe fn nvs_flash_init() -> esp_err_t {
+        0
+    }
+
+    pub unsafe fn nvs_open(
+        _name: *const c_char,
+        _mode: nvs_open_mode_t,
+        handle: *mut nvs_handle_t,
+    ) -> esp_err_t {
+        *handle = 1;
+        0
+    }
+
+    pub unsafe fn nvs_close(_handle: nvs_handle_t) {}
+
+    pub unsafe fn nvs_commit(_handle: nvs_handle_t) -> esp_err_t {
+        0
+    }
+
+    pub unsafe fn nvs_set_str(
+        _handle: nvs_handle_t,
+        name: *const c_char,
+        value: *const c_char,
+    ) -> esp_err_t {
+        let value = CStr::from_ptr(value).to_bytes_with_nul().to_vec();
+        let name = key(name);
+        with_store(|store| store.insert(name, Value::Str(value)));
+        0
+    }
+
+    pub unsafe fn nvs_get_str(
+        _handle: nvs_handle_t,
+        name: *const c_char,
+        out: *mut c_char,
+        len: *mut usize,
+    ) -> esp_err_t {
+        let name = key(name);
+        with_store(|store| match store.get(&name) {
+            Some(Value::Str(value)) => read_out(value, out as *mut u8, len),
+            other => not_found_or_mismatch(other),
+        })
+    }
+
+    pub unsafe fn nvs_set_blob(
+        _handle: nvs_handle_t,
+        name: *const c_char,
+        value: *const c_void,
+        len: usize,
+    ) -> esp_err_t {
+        let value = std::slice::from_raw_parts(value as *const u8, len).to_vec();
+        let name = key(name);
+        with_store(|store| store.insert(name, Value::Blob(value)));
+        0
+    }
+
+    pub unsafe fn nvs_get_blob(
+        _handle: nvs_handle_t,
+        name: *const c_char,
+        out: *mut c_void,
+        len: *mut usize,
+    ) -> esp_err_t {
+        let name = key(name);
+        with_store(|store| match store.get(&name) {
+            Some(Value::Blob(value)) => read_out(value, out as *mut u8, len),
+            other => not_found_or_mismatch(other),
+        })
+    }
+
+    pub unsafe fn nvs_set_u32(_handle: nvs_handle_t, name: *const c_char, value: u32) -> esp_err_t {
+        let name = key(name);
+        with_store(|store| store.insert(name, Value::U32(value)));
+        0
+    }
+
+    pub unsafe fn nvs_get_u32(
+        _handle: nvs_handle_t,
+        name: *const c_char,
+        out: *mut u32,
+    ) -> esp_err_t {
+        let name = key(name);
+        with_store(|store| match store.get(&name) {
+            Some(Value::U32(value)) => {
+                *out = *value;
+                0
+            }
+            other => not_found_or_mismatch(other),
+        })
+    }
+
+    pub unsafe fn nvs_erase_key(_handle: nvs_handle_t, name: *const c_char) -> esp_err_t {
+        let name = key(name);
+        with_store(|store| match store.remove(&name) {
+            Some(_) => 0,
+            None => ESP_ERR_NVS_NOT_FOUND as esp_err_t,
+        })
+    }
+
+    pub unsafe fn nvs_erase_all(_handle: nvs_handle_t) -> esp_err_t {
+        with_store(|store| store.clear());
+        0
+    }
+
+    /// The host build stands for a production device, so flash encryption is on
+    pub unsafe fn esp_flash_encryption_enabled() -> bool {
+        true
+    }
+
+    pub unsafe fn esp_fill_random(buffer: *mut c_void, len: usize) {
+        let buffer = std::slice::from_raw_parts_mut(buffer as *mut u8, len);
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut urandom| urandom.read_exact(buffer))
+            .expect("Failed to read /dev/urandom");
+    }
+
+    pub unsafe fn bootloader_random_enable() {}
+
+    pub unsafe fn bootloader_random_disable() {}
+}
+
+pub mod hal {
+    pub mod gpio {
+        use std::cell::RefCell;
+        use std::collections::VecDeque;
+        use std::fmt;
+        use std::marker::PhantomData;
+
+        #[derive(Debug)]
+        pub struct EspError;
+
+        impl fmt::Display for EspError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "ESP error")
+            }
+        }
+
+        impl std::error::Error for EspError {}
+
+        pub trait Pin {
+            const NUMBER: u8;
+        }
+
+        pub struct Gpio0;
+        pub struct Gpio35;
+
+        impl Gpio0 {
+            pub unsafe fn new() -> Self {
+                Gpio0
+            }
+        }
+
+        impl Gpio35 {
+            pub unsafe fn new() -> Self {
+                Gpio35
+            }
+        }
+
+        impl Pin for Gpio0 {
+            const NUMBER: u8 = 0;
+        }
+
+        impl Pin for Gpio35 {
+            const NUMBER: u8 = 35;
+        }
+
+        pub struct Input;
+
+        pub enum Pull {
+            Up,
+        }
+
+        thread_local! {
+            /// Queued presses by GPIO number, and reads left before the current press is released
+            static PRESSES: RefCell<(VecDeque<u8>, u8)> = const { RefCell::new((VecDeque::new(), 0)) };
+        }
+
+        /// Queue button presses by GPIO number for this thread: 0 is the left button, 35 the
+        /// right one
+        pub fn script(presses: &[u8]) {
+            PRESSES.with(|queue| *queue.borrow_mut() = (presses.iter().copied().collect(), 0));
+        }
+
+        /// Presses queued with `script` that were not read yet
+        pub fn remaining() -> usize {
+            PRESSES.with(|queue| queue.borrow().0.len())
+        }
+
+        pub struct PinDriver<'a, P, M>(PhantomData<(&'a (), P, M)>);
+
+        impl<P: Pin> PinDriver<'_, P, Input> {
+            pub fn input(_pin: P) -> Result<Self, EspError> {
+                Ok(PinDriver(PhantomData))
+            }
+
+            pub fn set_pull(&mut self, _pull: Pull) -> Result<(), EspError> {
+                Ok(())
+            }
+
+            /// Reads low once for a queued press of this pin, then high while it is released
+            pub fn is_low(&self) -> bool {
+                PRESSES.with(|queue| Self::read(&mut queue.borrow_mut()))
+            }
+
+            fn read(queue: &mut (VecDeque<u8>, u8)) -> bool {
+                if queue.1 > 0 {
+                    queue.1 -= 1;
+                    return false;
+                }
+                match queue.0.front() {
+                    Some(&number) if number == P::NUMBER => {
+                        queue.0.pop_front();
+                        queue.1 = 2;
+                        true
+                    }
+                    Some(_) => false,
+                    None => panic!("waiting for a button press that was never scripted"),
+                }
+            }
+        }
+    }
+}
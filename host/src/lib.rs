@@ -0,0 +1,10 @@
+//! The firmware's signing, storage and security modules, built for the host so their tests run
+//! with `cargo test` in this directory.
+
+#[path = "../../src/bitcoin_mod/mod.rs"]
+pub mod bitcoin_mod;
+#[path = "../../src/nvs/mod.rs"]
+pub mod nvs;
+#[path = "../../src/security/mod.rs"]
+pub mod security;
+pub mod ui;
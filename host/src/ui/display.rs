@@ -0,0 +1,34 @@
+use std::sync::{mpsc, Mutex};
+
+/// Stands in for the ST7789 controller: every screen written is kept, one string per screen
+#[derive(Default)]
+pub struct LcdController {
+    screens: Mutex<Vec<String>>,
+}
+
+impl LcdController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write a message to the LCD screen
+    pub fn write_message(&self, message: &str) -> Result<(), mpsc::SendError<String>> {
+        self.screens.lock().unwrap().push(message.to_string());
+        Ok(())
+    }
+
+    /// Clear the LCD screen
+    pub fn clear(&self) -> Result<(), mpsc::SendError<String>> {
+        Ok(())
+    }
+
+    /// Display a multi-line message
+    pub fn write_lines(&self, lines: &[&str]) -> Result<(), mpsc::SendError<String>> {
+        self.write_message(&lines.join("\n"))
+    }
+
+    /// Every screen shown so far, oldest first
+    pub fn screens(&self) -> Vec<String> {
+        self.screens.lock().unwrap().clone()
+    }
+}
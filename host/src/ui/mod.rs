@@ -0,0 +1,27 @@
+//! The firmware's screens and button handling, drawn on a display that only records its lines
+
+pub mod display;
+#[path = "../../../src/ui/input.rs"]
+pub mod input;
+#[path = "../../../src/ui/screens.rs"]
+pub mod screens;
+
+use esp_idf_svc::hal::gpio::{script, Gpio0, Gpio35};
+
+use display::LcdController;
+use input::Buttons;
+
+/// Right-button presses `approving_review` queues, more than one signing request shows screens
+const APPROVALS: usize = 32;
+
+/// A display and buttons that answer the screens with `presses`, GPIO 0 for left and 35 for right
+pub fn scripted_review(presses: &[u8]) -> (LcdController, Buttons) {
+    script(presses);
+    let buttons = unsafe { Buttons::new(Gpio0::new(), Gpio35::new()) }.unwrap();
+    (LcdController::new(), buttons)
+}
+
+/// A display and buttons that approve every screen
+pub fn approving_review() -> (LcdController, Buttons) {
+    scripted_review(&[35; APPROVALS])
+}
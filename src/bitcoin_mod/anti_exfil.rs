@@ -0,0 +1,413 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::key::Keypair;
+use bitcoin::secp256k1::{
+    constants, ecdsa, schnorr, Message, Parity, PublicKey, Scalar, Secp256k1, SecretKey, Signing,
+    Verification,
+};
+use bitcoin::{Psbt, Txid};
+
+use crate::bitcoin_mod::musig::{scalar_mod_n, tagged_hash};
+use crate::bitcoin_mod::signature::{
+    sign_psbt_with, AntiExfil, KeySource, NonceSlot, SignOptions, SignReport,
+};
+
+// Tags follow the ECDSA sign-to-contract scheme of libsecp256k1-zkp and are reused for BIP-340
+const HOST_COMMITMENT_TAG: &str = "s2c/ecdsa/data";
+const NONCE_TWEAK_TAG: &str = "s2c/ecdsa/point";
+/// Domain of the device nonce that the host randomness is later added to
+const SIGNER_NONCE_TAG: &str = "anti-exfil/nonce";
+
+/// What the host sends first: a commitment to the randomness it reveals later
+pub fn host_commitment(host_data: &[u8; 32]) -> [u8; 32] {
+    tagged_hash(HOST_COMMITMENT_TAG, &[host_data])
+}
+
+/// Device nonce, bound to the key, the message and the host commitment.
+///
+/// It is derived rather than random so both rounds arrive at the same nonce without the device
+/// keeping any state in between.
+fn signer_nonce(
+    secret_key: &SecretKey,
+    message: &Message,
+    host_commitment: &[u8; 32],
+) -> Result<SecretKey> {
+    let hash = tagged_hash(
+        SIGNER_NONCE_TAG,
+        &[
+            &secret_key.secret_bytes(),
+            message.as_ref(),
+            host_commitment,
+        ],
+    );
+    SecretKey::from_slice(&scalar_mod_n(hash).to_be_bytes()).map_err(|_| anyhow!("nonce is zero"))
+}
+
+fn nonce_tweak(signer_commitment: &PublicKey, host_data: &[u8; 32]) -> Scalar {
+    scalar_mod_n(tagged_hash(
+        NONCE_TWEAK_TAG,
+        &[&signer_commitment.serialize(), host_data],
+    ))
+}
+
+/// Point the final signature nonce has to be: the device commitment plus the host tweak
+fn tweaked_nonce_point<C: Verification>(
+    secp: &Secp256k1<C>,
+    signer_commitment: &PublicKey,
+    host_data: &[u8; 32],
+) -> Result<PublicKey> {
+    let tweak = nonce_tweak(signer_commitment, host_data);
+    Ok(signer_commitment.add_exp_tweak(secp, &tweak)?)
+}
+
+/// `k^-1 mod n`, as `k^(n-2)` since the order is prime
+fn invert(k: &SecretKey) -> Result<SecretKey> {
+    let mut exponent = constants::CURVE_ORDER;
+    exponent[31] -= 2;
+    let mut result: Option<SecretKey> = None;
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            if let Some(value) = result {
+                result = Some(value.mul_tweak(&Scalar::from(value))?);
+            }
+            if byte >> bit & 1 == 1 {
+                result = Some(match result {
+                    Some(value) => value.mul_tweak(&Scalar::from(*k))?,
+                    None => *k,
+                });
+            }
+        }
+    }
+    result.ok_or_else(|| anyhow!("nothing to invert"))
+}
+
+/// Nonce commitment for an ECDSA signature, the device's answer in the first round
+pub fn ecdsa_signer_commitment<C: Signing>(
+    secp: &Secp256k1<C>,
+    secret_key: &SecretKey,
+    message: &Message,
+    host_commitment: &[u8; 32],
+) -> Result<PublicKey> {
+    let nonce = signer_nonce(secret_key, message, host_commitment)?;
+    Ok(PublicKey::from_secret_key(secp, &nonce))
+}
+
+/// ECDSA signature whose nonce includes the revealed host randomness.
+///
+/// Returns the signature together with the nonce commitment the host checks it against.
+/// Crate-private: callers go through `sign_psbt_with`, which checks the PIN first.
+pub(crate) fn sign_ecdsa<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    secret_key: &SecretKey,
+    message: &Message,
+    host_data: &[u8; 32],
+) -> Result<(ecdsa::Signature, PublicKey)> {
+    let nonce = signer_nonce(secret_key, message, &host_commitment(host_data))?;
+    let signer_commitment = PublicKey::from_secret_key(secp, &nonce);
+    let k = nonce.add_tweak(&nonce_tweak(&signer_commitment, host_data))?;
+
+    let r_point = PublicKey::from_secret_key(secp, &k);
+    let r = scalar_mod_n(r_point.x_only_public_key().0.serialize());
+    let z = scalar_mod_n(*message.as_ref());
+    // s = k^-1 * (z + r * d)
+    let sum = secret_key.mul_tweak(&r)?.add_tweak(&z)?;
+    let s = invert(&k)?.mul_tweak(&Scalar::from(sum))?;
+
+    let mut compact = [0u8; 64];
+    compact[..32].copy_from_slice(&r.to_be_bytes());
+    compact[32..].copy_from_slice(&s.secret_bytes());
+    let mut signature = ecdsa::Signature::from_compact(&compact)?;
+    // Negating s flips the nonce sign, which leaves x(R) and so the host check unchanged
+    signature.normalize_s();
+
+    let pubkey = PublicKey::from_secret_key(secp, secret_key);
+    secp.verify_ecdsa(message, &signature, &pubkey)
+        .map_err(|_| anyhow!("anti-exfil signature failed verification"))?;
+    Ok((signature, signer_commitment))
+}
+
+/// Nonce commitment for a BIP-340 signature with `keypair`, already tweaked for taproot
+pub fn schnorr_signer_commitment<C: Signing>(
+    secp: &Secp256k1<C>,
+    keypair: &Keypair,
+    message: &Message,
+    host_commitment: &[u8; 32],
+) -> Result<PublicKey> {
+    let nonce = signer_nonce(&keypair.secret_key(), message, host_commitment)?;
+    Ok(PublicKey::from_secret_key(secp, &nonce))
+}
+
+/// BIP-340 signature whose nonce includes the revealed host randomness (see `sign_ecdsa`)
+pub(crate) fn sign_schnorr<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    keypair: &Keypair,
+    message: &Message,
+    host_data: &[u8; 32],
+) -> Result<(schnorr::Signature, PublicKey)> {
+    let nonce = signer_nonce(&keypair.secret_key(), message, &host_commitment(host_data))?;
+    let signer_commitment = PublicKey::from_secret_key(secp, &nonce);
+    let mut k = nonce.add_tweak(&nonce_tweak(&signer_commitment, host_data))?;
+    let (r_x, r_parity) = PublicKey::from_secret_key(secp, &k).x_only_public_key();
+    if r_parity == Parity::Odd {
+        k = k.negate();
+    }
+    let (p_x, p_parity) = keypair.x_only_public_key();
+    let d = match p_parity {
+        Parity::Even => keypair.secret_key(),
+        Parity::Odd => keypair.secret_key().negate(),
+    };
+
+    let e = scalar_mod_n(tagged_hash(
+        "BIP0340/challenge",
+        &[&r_x.serialize(), &p_x.serialize(), message.as_ref()],
+    ));
+    let s = k.add_tweak(&Scalar::from(d.mul_tweak(&e)?))?;
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(&r_x.serialize());
+    bytes[32..].copy_from_slice(&s.secret_bytes());
+    let signature = schnorr::Signature::from_slice(&bytes)?;
+
+    secp.verify_schnorr(&signature, message, &p_x)
+        .map_err(|_| anyhow!("anti-exfil signature failed verification"))?;
+    Ok((signature, signer_commitment))
+}
+
+/// Host side: check an ECDSA signature's nonce includes `host_data`
+pub fn verify_ecdsa_nonce<C: Verification>(
+    secp: &Secp256k1<C>,
+    signature: &ecdsa::Signature,
+    signer_commitment: &PublicKey,
+    host_data: &[u8; 32],
+) -> Result<()> {
+    let r_point = tweaked_nonce_point(secp, signer_commitment, host_data)?;
+    let r = scalar_mod_n(r_point.x_only_public_key().0.serialize());
+    if signature.serialize_compact()[..32] != r.to_be_bytes() {
+        bail!("signature nonce does not include the host randomness");
+    }
+    Ok(())
+}
+
+/// Host side: check a BIP-340 signature's nonce includes `host_data`
+pub fn verify_schnorr_nonce<C: Verification>(
+    secp: &Secp256k1<C>,
+    signature: &schnorr::Signature,
+    signer_commitment: &PublicKey,
+    host_data: &[u8; 32],
+) -> Result<()> {
+    let r_point = tweaked_nonce_point(secp, signer_commitment, host_data)?;
+    if signature.as_ref()[..32] != r_point.x_only_public_key().0.serialize() {
+        bail!("signature nonce does not include the host randomness");
+    }
+    Ok(())
+}
+
+/// Host commitments of a transaction whose nonce commitments went out, waiting for the reveal
+struct PendingRound {
+    txid: Txid,
+    commitments: BTreeMap<NonceSlot, [u8; 32]>,
+}
+
+/// Device side of the two anti-exfil rounds, held in RAM only.
+///
+/// A nonce commitment only goes out for a host commitment the device has taken in first, and
+/// the reveal round only signs with host data that opens exactly those commitments, so the host
+/// cannot pick its randomness after seeing the device nonce.
+#[derive(Default)]
+pub struct AntiExfilRounds {
+    pending: Option<PendingRound>,
+}
+
+impl AntiExfilRounds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// First round: keep the host `commitments` and answer with our nonce commitments.
+    ///
+    /// Nothing is signed, and a round that was waiting for its reveal is dropped.
+    pub fn commit(
+        &mut self,
+        psbt: &Psbt,
+        keys: &KeySource,
+        options: SignOptions,
+        commitments: BTreeMap<NonceSlot, [u8; 32]>,
+    ) -> Result<Vec<(NonceSlot, PublicKey)>> {
+        self.pending = None;
+        let round = AntiExfil::Commit(commitments.clone());
+        let options = SignOptions {
+            anti_exfil: Some(&round),
+            ..options
+        };
+        let report = sign_psbt_with(&mut psbt.clone(), keys, &options);
+        if report.nonce_commitments.is_empty() {
+            bail!("{}", report);
+        }
+        self.pending = Some(PendingRound {
+            txid: psbt.unsigned_tx.compute_txid(),
+            commitments,
+        });
+        Ok(report.nonce_commitments)
+    }
+
+    /// Second round: sign `psbt` with nonces tweaked by `host_data`, which has to open the
+    /// commitments of the first round for this same transaction
+    pub fn reveal(
+        &mut self,
+        psbt: &mut Psbt,
+        keys: &KeySource,
+        options: SignOptions,
+        host_data: BTreeMap<NonceSlot, [u8; 32]>,
+    ) -> Result<SignReport> {
+        let pending = self
+            .pending
+            .take()
+            .ok_or_else(|| anyhow!("no anti-exfil commitments to reveal"))?;
+        if psbt.unsigned_tx.compute_txid() != pending.txid {
+            bail!("PSBT is not the one the commitments were made for");
+        }
+        let opens = host_data.len() == pending.commitments.len()
+            && host_data
+                .iter()
+                .all(|(slot, data)| pending.commitments.get(slot) == Some(&host_commitment(data)));
+        if !opens {
+            bail!("host data does not match its commitments");
+        }
+
+        let round = AntiExfil::Reveal(host_data);
+        let options = SignOptions {
+            anti_exfil: Some(&round),
+            ..options
+        };
+        let report = sign_psbt_with(psbt, keys, &options);
+        if report.signed.is_empty() {
+            bail!("{}", report);
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::pin;
+    use crate::ui::approving_review;
+    use bitcoin::{
+        absolute, transaction, Amount, CompressedPublicKey, NetworkKind, PrivateKey, ScriptBuf,
+        Transaction, TxIn, TxOut,
+    };
+
+    const HOST_DATA: [u8; 32] = [7u8; 32];
+
+    fn setup() -> (Secp256k1<bitcoin::secp256k1::All>, SecretKey, Message) {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        (secp, secret_key, Message::from_digest([9u8; 32]))
+    }
+
+    #[test]
+    fn invert_gives_the_inverse() {
+        let k = SecretKey::from_slice(&[5u8; 32]).unwrap();
+        let one = invert(&k).unwrap().mul_tweak(&Scalar::from(k)).unwrap();
+        let mut expected = [0u8; 32];
+        expected[31] = 1;
+        assert_eq!(one.secret_bytes(), expected);
+    }
+
+    #[test]
+    fn ecdsa_nonce_includes_host_data() {
+        let (secp, secret_key, message) = setup();
+        let commitment =
+            ecdsa_signer_commitment(&secp, &secret_key, &message, &host_commitment(&HOST_DATA))
+                .unwrap();
+        let (signature, revealed) = sign_ecdsa(&secp, &secret_key, &message, &HOST_DATA).unwrap();
+        assert_eq!(revealed, commitment);
+        verify_ecdsa_nonce(&secp, &signature, &commitment, &HOST_DATA).unwrap();
+        assert!(verify_ecdsa_nonce(&secp, &signature, &commitment, &[8u8; 32]).is_err());
+    }
+
+    #[test]
+    fn schnorr_nonce_includes_host_data() {
+        let (secp, secret_key, message) = setup();
+        let keypair = Keypair::from_secret_key(&secp, &secret_key);
+        let commitment =
+            schnorr_signer_commitment(&secp, &keypair, &message, &host_commitment(&HOST_DATA))
+                .unwrap();
+        let (signature, revealed) = sign_schnorr(&secp, &keypair, &message, &HOST_DATA).unwrap();
+        assert_eq!(revealed, commitment);
+        verify_schnorr_nonce(&secp, &signature, &commitment, &HOST_DATA).unwrap();
+        assert!(verify_schnorr_nonce(&secp, &signature, &commitment, &[8u8; 32]).is_err());
+    }
+
+    /// One-input P2WPKH spend of `key`, paying most of it back to the same script
+    fn p2wpkh_psbt(secp: &Secp256k1<bitcoin::secp256k1::All>, key: &PrivateKey) -> Psbt {
+        let pubkey = CompressedPublicKey::from_private_key(secp, key).unwrap();
+        let script_pubkey = ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash());
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey,
+        });
+        psbt
+    }
+
+    #[test]
+    fn reveal_needs_the_committed_host_data() {
+        pin::unlock_for_tests();
+        let (secp, secret_key, _) = setup();
+        let key = PrivateKey::new(secret_key, NetworkKind::Main);
+        let keys = KeySource::Single(key);
+        let psbt = p2wpkh_psbt(&secp, &key);
+        let slot: NonceSlot = (0, None);
+        let commitments = BTreeMap::from([(slot, host_commitment(&HOST_DATA))]);
+        let reveal = |rounds: &mut AntiExfilRounds, host_data: [u8; 32]| {
+            let (lcd, buttons) = approving_review();
+            let options = SignOptions {
+                review: Some((&lcd, &buttons)),
+                ..SignOptions::default()
+            };
+            let mut psbt = psbt.clone();
+            let report = rounds.reveal(
+                &mut psbt,
+                &keys,
+                options,
+                BTreeMap::from([(slot, host_data)]),
+            );
+            report.map(|_| psbt)
+        };
+
+        // No nonce is revealed before the host has committed
+        let mut rounds = AntiExfilRounds::new();
+        assert!(reveal(&mut rounds, HOST_DATA).is_err());
+
+        let nonce_commitments = rounds
+            .commit(&psbt, &keys, SignOptions::default(), commitments.clone())
+            .unwrap();
+        assert_eq!(nonce_commitments.len(), 1);
+        // Host data chosen after the fact does not open the commitment, and ends the round
+        assert!(reveal(&mut rounds, [8u8; 32]).is_err());
+        assert!(reveal(&mut rounds, HOST_DATA).is_err());
+
+        rounds
+            .commit(&psbt, &keys, SignOptions::default(), commitments)
+            .unwrap();
+        let signed = reveal(&mut rounds, HOST_DATA).unwrap();
+        let (_, signature) = signed.inputs[0].partial_sigs.iter().next().unwrap();
+        verify_ecdsa_nonce(
+            &secp,
+            &signature.signature,
+            &nonce_commitments[0].1,
+            &HOST_DATA,
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{bail, Result};
+use bitcoin::psbt::{Input, Output};
+use bitcoin::Psbt;
+
+use crate::bitcoin_mod::psbt_v2::{decode_psbt_any, encode_psbt};
+
+/// Where in a PSBT two copies disagreed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Global,
+    Input(usize),
+    Output(usize),
+}
+
+/// A field two PSBTs set to different values; the value from the earlier PSBT is kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub location: Location,
+    pub field: &'static str,
+    /// Map key the values were stored under, empty for single-value fields
+    pub key: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location {
+            Location::Global => write!(f, "global {}", self.field)?,
+            Location::Input(index) => write!(f, "input {} {}", index, self.field)?,
+            Location::Output(index) => write!(f, "output {} {}", index, self.field)?,
+        }
+        if !self.key.is_empty() {
+            write!(f, " for {}", self.key)?;
+        }
+        write!(f, " differs")
+    }
+}
+
+/// Collects conflicts while the PSBTs are merged field by field
+struct Merger {
+    location: Location,
+    conflicts: Vec<Conflict>,
+}
+
+impl Merger {
+    fn option<T: Clone + PartialEq>(
+        &mut self,
+        field: &'static str,
+        ours: &mut Option<T>,
+        theirs: &Option<T>,
+    ) {
+        match (ours.as_ref(), theirs) {
+            (None, Some(value)) => *ours = Some(value.clone()),
+            (Some(ours), Some(theirs)) if ours != theirs => self.conflicts.push(Conflict {
+                location: self.location,
+                field,
+                key: String::new(),
+            }),
+            _ => {}
+        }
+    }
+
+    fn map<K: Ord + Clone, V: Clone + PartialEq>(
+        &mut self,
+        field: &'static str,
+        ours: &mut BTreeMap<K, V>,
+        theirs: &BTreeMap<K, V>,
+        describe: impl Fn(&K) -> String,
+    ) {
+        for (key, value) in theirs {
+            match ours.get(key) {
+                None => {
+                    ours.insert(key.clone(), value.clone());
+                }
+                Some(existing) if existing != value => self.conflicts.push(Conflict {
+                    location: self.location,
+                    field,
+                    key: describe(key),
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn input(&mut self, ours: &mut Input, theirs: &Input) {
+        self.option(
+            "non_witness_utxo",
+            &mut ours.non_witness_utxo,
+            &theirs.non_witness_utxo,
+        );
+        self.option("witness_utxo", &mut ours.witness_utxo, &theirs.witness_utxo);
+        self.map(
+            "partial_sigs",
+            &mut ours.partial_sigs,
+            &theirs.partial_sigs,
+            |key| key.to_string(),
+        );
+        self.option("sighash_type", &mut ours.sighash_type, &theirs.sighash_type);
+        self.option(
+            "redeem_script",
+            &mut ours.redeem_script,
+            &theirs.redeem_script,
+        );
+        self.option(
+            "witness_script",
+            &mut ours.witness_script,
+            &theirs.witness_script,
+        );
+        self.map(
+            "bip32_derivation",
+            &mut ours.bip32_derivation,
+            &theirs.bip32_derivation,
+            |key| key.to_string(),
+        );
+        self.option(
+            "final_script_sig",
+            &mut ours.final_script_sig,
+            &theirs.final_script_sig,
+        );
+        self.option(
+            "final_script_witness",
+            &mut ours.final_script_witness,
+            &theirs.final_script_witness,
+        );
+        self.map(
+            "ripemd160_preimages",
+            &mut ours.ripemd160_preimages,
+            &theirs.ripemd160_preimages,
+            |key| key.to_string(),
+        );
+        self.map(
+            "sha256_preimages",
+            &mut ours.sha256_preimages,
+            &theirs.sha256_preimages,
+            |key| key.to_string(),
+        );
+        self.map(
+            "hash160_preimages",
+            &mut ours.hash160_preimages,
+            &theirs.hash160_preimages,
+            |key| key.to_string(),
+        );
+        self.map(
+            "hash256_preimages",
+            &mut ours.hash256_preimages,
+            &theirs.hash256_preimages,
+            |key| key.to_string(),
+        );
+        self.option("tap_key_sig", &mut ours.tap_key_sig, &theirs.tap_key_sig);
+        self.map(
+            "tap_script_sigs",
+            &mut ours.tap_script_sigs,
+            &theirs.tap_script_sigs,
+            |(key, leaf_hash)| format!("{} in leaf {}", key, leaf_hash),
+        );
+        self.map(
+            "tap_scripts",
+            &mut ours.tap_scripts,
+            &theirs.tap_scripts,
+            |control_block| hex::encode(control_block.serialize()),
+        );
+        self.map(
+            "tap_key_origins",
+            &mut ours.tap_key_origins,
+            &theirs.tap_key_origins,
+            |key| key.to_string(),
+        );
+        self.option(
+            "tap_internal_key",
+            &mut ours.tap_internal_key,
+            &theirs.tap_internal_key,
+        );
+        self.option(
+            "tap_merkle_root",
+            &mut ours.tap_merkle_root,
+            &theirs.tap_merkle_root,
+        );
+        self.map(
+            "proprietary",
+            &mut ours.proprietary,
+            &theirs.proprietary,
+            |key| format!("{:?}", key),
+        );
+        self.map("unknown", &mut ours.unknown, &theirs.unknown, |key| {
+            key.to_string()
+        });
+    }
+
+    fn output(&mut self, ours: &mut Output, theirs: &Output) {
+        self.option(
+            "redeem_script",
+            &mut ours.redeem_script,
+            &theirs.redeem_script,
+        );
+        self.option(
+            "witness_script",
+            &mut ours.witness_script,
+            &theirs.witness_script,
+        );
+        self.map(
+            "bip32_derivation",
+            &mut ours.bip32_derivation,
+            &theirs.bip32_derivation,
+            |key| key.to_string(),
+        );
+        self.option(
+            "tap_internal_key",
+            &mut ours.tap_internal_key,
+            &theirs.tap_internal_key,
+        );
+        self.option("tap_tree", &mut ours.tap_tree, &theirs.tap_tree);
+        self.map(
+            "tap_key_origins",
+            &mut ours.tap_key_origins,
+            &theirs.tap_key_origins,
+            |key| key.to_string(),
+        );
+        self.map(
+            "proprietary",
+            &mut ours.proprietary,
+            &theirs.proprietary,
+            |key| format!("{:?}", key),
+        );
+        self.map("unknown", &mut ours.unknown, &theirs.unknown, |key| {
+            key.to_string()
+        });
+    }
+}
+
+/// Merge copies of one PSBT that cosigners signed separately (the BIP-174 combiner role).
+///
+/// Every field is unioned. When two copies disagree the first value wins and the clash is
+/// returned, so a cosigner that produced a different signature for the same key stands out.
+/// The result is serialized in the PSBT version of the first copy.
+pub fn combine_psbts(psbts: &[&[u8]]) -> Result<(Vec<u8>, Vec<Conflict>)> {
+    let decoded = psbts
+        .iter()
+        .map(|bytes| decode_psbt_any(bytes))
+        .collect::<Result<Vec<_>>>()?;
+    let Some(((first, format), rest)) = decoded.split_first() else {
+        bail!("nothing to combine");
+    };
+    let rest: Vec<&Psbt> = rest.iter().map(|(psbt, _)| psbt).collect();
+    let (combined, conflicts) = merge(first, &rest)?;
+    Ok((encode_psbt(&combined, format)?, conflicts))
+}
+
+/// Field by field union of decoded PSBTs, see `combine_psbts`
+fn merge(first: &Psbt, rest: &[&Psbt]) -> Result<(Psbt, Vec<Conflict>)> {
+    let mut combined = first.clone();
+    let mut merger = Merger {
+        location: Location::Global,
+        conflicts: Vec::new(),
+    };
+
+    for psbt in rest {
+        if psbt.unsigned_tx != combined.unsigned_tx {
+            bail!(
+                "PSBTs spend different transactions: {} and {}",
+                combined.unsigned_tx.compute_txid(),
+                psbt.unsigned_tx.compute_txid()
+            );
+        }
+        if psbt.version != combined.version {
+            bail!(
+                "PSBT versions {} and {} differ",
+                combined.version,
+                psbt.version
+            );
+        }
+
+        merger.location = Location::Global;
+        merger.map("xpub", &mut combined.xpub, &psbt.xpub, |key| {
+            key.to_string()
+        });
+        merger.map(
+            "proprietary",
+            &mut combined.proprietary,
+            &psbt.proprietary,
+            |key| format!("{:?}", key),
+        );
+        merger.map("unknown", &mut combined.unknown, &psbt.unknown, |key| {
+            key.to_string()
+        });
+        for (index, (ours, theirs)) in combined.inputs.iter_mut().zip(&psbt.inputs).enumerate() {
+            merger.location = Location::Input(index);
+            merger.input(ours, theirs);
+        }
+        for (index, (ours, theirs)) in combined.outputs.iter_mut().zip(&psbt.outputs).enumerate() {
+            merger.location = Location::Output(index);
+            merger.output(ours, theirs);
+        }
+    }
+    Ok((combined, merger.conflicts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::ecdsa;
+    use bitcoin::psbt::PsbtSighashType;
+    use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+    use bitcoin::sighash::EcdsaSighashType;
+    use bitcoin::PublicKey;
+
+    use crate::bitcoin_mod::psbt_v2::PsbtFormat;
+
+    /// One-input, one-output PSBTv2 with nothing signed yet
+    const UNSIGNED_V2: &str = "70736274ff01020402000000010401010105010101fb040200000000010e200101010101010101010101010101010101010101010101010101010101010101010f0400000000000103082823000000000000010416001479b000887626b294a914501a4cd226b58b23598300";
+
+    /// Copy of `UNSIGNED_V2` carrying a signature of the key made from `byte`
+    fn signed_copy(byte: u8) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[byte; 32]).unwrap();
+        let (mut psbt, format) = decode_psbt_any(&hex::decode(UNSIGNED_V2).unwrap()).unwrap();
+        let signature = ecdsa::Signature {
+            signature: secp.sign_ecdsa(&Message::from_digest([1; 32]), &secret_key),
+            sighash_type: EcdsaSighashType::All,
+        };
+        psbt.inputs[0]
+            .partial_sigs
+            .insert(PublicKey::new(secret_key.public_key(&secp)), signature);
+        psbt.inputs[0].sighash_type = Some(PsbtSighashType::from(EcdsaSighashType::All));
+        encode_psbt(&psbt, &format).unwrap()
+    }
+
+    #[test]
+    fn keeps_psbt_v2() {
+        let (first, second) = (signed_copy(1), signed_copy(2));
+        let (combined, conflicts) = combine_psbts(&[&first, &second]).unwrap();
+        assert!(conflicts.is_empty());
+        let (psbt, format) = decode_psbt_any(&combined).unwrap();
+        assert!(matches!(format, PsbtFormat::V2(_)));
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 2);
+    }
+
+    #[test]
+    fn rejects_other_transactions() {
+        let mut other = hex::decode(UNSIGNED_V2).unwrap();
+        // The last byte of the previous txid
+        other[62] ^= 1;
+        assert!(combine_psbts(&[&signed_copy(1), &other]).is_err());
+    }
+}
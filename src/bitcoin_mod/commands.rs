@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose;
+use base64::Engine;
+use bitcoin::bip32::DerivationPath;
+use bitcoin::taproot::TapLeafHash;
+use bitcoin::Address;
+use esp_idf_svc::sys::nvs_handle_t;
+
+use crate::bitcoin_mod::anti_exfil::AntiExfilRounds;
+use crate::bitcoin_mod::combine::combine_psbts;
+use crate::bitcoin_mod::fee_policy::{stored_fee_limits, update_fee_limits, FeeLimits};
+use crate::bitcoin_mod::message::{sign_bip322, sign_legacy_message, verify_bip322, Bip322Format};
+use crate::bitcoin_mod::musig::{register_musig_wallet, registered_musig_wallets, MusigSigner};
+use crate::bitcoin_mod::policy::{register_policy, WalletPolicy};
+use crate::bitcoin_mod::psbt_v2::{decode_psbt_any, encode_psbt};
+use crate::bitcoin_mod::signature::{sign_psbt_with, NonceSlot, SignOptions};
+use crate::bitcoin_mod::transaction::ScriptType;
+use crate::bitcoin_mod::wallet::{register_wallet, registered_wallets};
+use crate::security::entropy;
+use crate::security::key_management::{enter_passphrase, SeedSession};
+use crate::ui::display::LcdController;
+use crate::ui::input::Buttons;
+
+/// `<name> <template> <key>...` of a wallet policy; the name cannot contain spaces here
+fn parse_policy(arguments: &[&str]) -> Result<WalletPolicy> {
+    let [name, template, keys @ ..] = arguments else {
+        bail!("a policy needs a name, a template and its keys");
+    };
+    WalletPolicy::new(
+        name,
+        template,
+        keys.iter().map(|key| key.to_string()).collect(),
+    )
+}
+
+/// Message text sent as base64, so spaces and line breaks arrive as they were typed
+fn parse_message(message_base64: &str) -> Result<String> {
+    let bytes = general_purpose::STANDARD.decode(message_base64)?;
+    String::from_utf8(bytes).map_err(|_| anyhow!("message must be UTF-8 text"))
+}
+
+fn parse_script_type(script_type: &str) -> Result<ScriptType> {
+    match script_type {
+        "p2pkh" => Ok(ScriptType::P2pkh),
+        "p2wpkh" => Ok(ScriptType::P2wpkh),
+        "p2tr" => Ok(ScriptType::P2tr),
+        _ => bail!("unknown address type: {}", script_type),
+    }
+}
+
+fn parse_session_id(session_hex: &str) -> Result<[u8; 32]> {
+    <[u8; 32]>::try_from(hex::decode(session_hex)?.as_slice())
+        .map_err(|_| anyhow!("musig session ID must be 32 bytes"))
+}
+
+fn parse_hmac(hmac_hex: &str) -> Result<[u8; 32]> {
+    <[u8; 32]>::try_from(hex::decode(hmac_hex)?.as_slice())
+        .map_err(|_| anyhow!("policy HMAC must be 32 bytes"))
+}
+
+/// `<input>` or `<input>:<leaf hash>`, the signature an anti-exfil value belongs to
+fn parse_slot(slot: &str) -> Result<NonceSlot> {
+    let (index, leaf_hash) = match slot.split_once(':') {
+        Some((index, leaf_hash)) => (index, Some(TapLeafHash::from_str(leaf_hash)?)),
+        None => (slot, None),
+    };
+    Ok((index.parse()?, leaf_hash))
+}
+
+fn format_slot((index, leaf_hash): &NonceSlot) -> String {
+    match leaf_hash {
+        Some(leaf_hash) => format!("{}:{}", index, leaf_hash),
+        None => index.to_string(),
+    }
+}
+
+/// `<slot>=<hex>` pairs of 32-byte anti-exfil host values
+fn parse_host_values(values: &[&str]) -> Result<BTreeMap<NonceSlot, [u8; 32]>> {
+    values
+        .iter()
+        .map(|value| {
+            let (slot, bytes) = value
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected <slot>=<hex>, got {}", value))?;
+            let bytes = <[u8; 32]>::try_from(hex::decode(bytes)?.as_slice())
+                .map_err(|_| anyhow!("anti-exfil host values must be 32 bytes"))?;
+            Ok((parse_slot(slot)?, bytes))
+        })
+        .collect()
+}
+
+/// Merge base64 PSBTs signed by different cosigners; copies that disagree are refused, so a
+/// cosigner's odd signature is looked at rather than silently dropped
+fn combine(psbts_base64: &[&str]) -> Result<String> {
+    let psbts = psbts_base64
+        .iter()
+        .map(|psbt| general_purpose::STANDARD.decode(psbt))
+        .collect::<Result<Vec<_>, _>>()?;
+    let psbts: Vec<&[u8]> = psbts.iter().map(Vec::as_slice).collect();
+    let (combined, conflicts) = combine_psbts(&psbts)?;
+    if !conflicts.is_empty() {
+        let conflicts: Vec<String> = conflicts.iter().map(ToString::to_string).collect();
+        bail!("PSBTs conflict: {}", conflicts.join("; "));
+    }
+    Ok(general_purpose::STANDARD.encode(combined))
+}
+
+/// First anti-exfil round: take the host commitments in and answer with our nonce commitments,
+/// as `<slot>=<hex>` pairs
+fn anti_exfil_commit(
+    handle: nvs_handle_t,
+    session: &SeedSession,
+    anti_exfil: &mut AntiExfilRounds,
+    psbt_base64: &str,
+    commitments: &[&str],
+) -> Result<String> {
+    let bytes = general_purpose::STANDARD.decode(psbt_base64)?;
+    let (psbt, _) = decode_psbt_any(&bytes)?;
+    let wallets = registered_wallets(handle);
+    let fee_limits = stored_fee_limits(handle);
+    let options = SignOptions {
+        wallets: &wallets,
+        fee_limits: Some(&fee_limits),
+        ..SignOptions::default()
+    };
+    let nonce_commitments = anti_exfil.commit(
+        &psbt,
+        session.keys(),
+        options,
+        parse_host_values(commitments)?,
+    )?;
+    let replies: Vec<String> = nonce_commitments
+        .iter()
+        .map(|(slot, commitment)| format!("{}={}", format_slot(slot), commitment))
+        .collect();
+    Ok(replies.join(" "))
+}
+
+/// Second anti-exfil round: once the transaction is approved on the device, sign with the
+/// revealed host data and hand the PSBT back in the version it came in
+fn anti_exfil_sign(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    session: &SeedSession,
+    anti_exfil: &mut AntiExfilRounds,
+    psbt_base64: &str,
+    host_data: &[&str],
+) -> Result<String> {
+    let bytes = general_purpose::STANDARD.decode(psbt_base64)?;
+    let (mut psbt, format) = decode_psbt_any(&bytes)?;
+    let wallets = registered_wallets(handle);
+    let fee_limits = stored_fee_limits(handle);
+    let options = SignOptions {
+        wallets: &wallets,
+        fee_limits: Some(&fee_limits),
+        review: Some((lcd, buttons)),
+        ..SignOptions::default()
+    };
+    anti_exfil.reveal(
+        &mut psbt,
+        session.keys(),
+        options,
+        parse_host_values(host_data)?,
+    )?;
+    Ok(general_purpose::STANDARD.encode(encode_psbt(&psbt, &format)?))
+}
+
+/// Sign a base64 PSBT for the registered wallets, or the given policy, and hand it back in the
+/// version it came in
+fn sign(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    session: &SeedSession,
+    psbt_base64: &str,
+    policy: Option<(&WalletPolicy, &[u8; 32])>,
+) -> Result<String> {
+    let bytes = general_purpose::STANDARD.decode(psbt_base64)?;
+    let (mut psbt, format) = decode_psbt_any(&bytes)?;
+    let wallets = registered_wallets(handle);
+    let fee_limits = stored_fee_limits(handle);
+    let options = SignOptions {
+        wallets: &wallets,
+        policy,
+        fee_limits: Some(&fee_limits),
+        review: Some((lcd, buttons)),
+        ..SignOptions::default()
+    };
+    let report = sign_psbt_with(&mut psbt, session.keys(), &options);
+    if report.signed.is_empty() {
+        bail!("{}", report);
+    }
+    Ok(general_purpose::STANDARD.encode(encode_psbt(&psbt, &format)?))
+}
+
+/// First musig round: add our nonces to a base64 PSBT under a fresh session, answering with the
+/// hex session ID and the PSBT
+fn musig_nonces(
+    handle: nvs_handle_t,
+    session: &SeedSession,
+    musig: &mut MusigSigner,
+    psbt_base64: &str,
+) -> Result<String> {
+    let bytes = general_purpose::STANDARD.decode(psbt_base64)?;
+    let (mut psbt, format) = decode_psbt_any(&bytes)?;
+    let wallets = registered_musig_wallets(handle);
+    let session_id: [u8; 32] = entropy::random_bytes();
+    let inputs = musig.add_nonces(session_id, &mut psbt, session.keys(), &wallets)?;
+    if inputs.is_empty() {
+        bail!("no input spends from a registered musig wallet");
+    }
+    let psbt = general_purpose::STANDARD.encode(encode_psbt(&psbt, &format)?);
+    Ok(format!("{} {}", hex::encode(session_id), psbt))
+}
+
+/// Second musig round: once the transaction is approved on the device, add our partial
+/// signatures to a PSBT carrying every participant's nonce
+fn musig_sign(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    session: &SeedSession,
+    musig: &mut MusigSigner,
+    session_hex: &str,
+    psbt_base64: &str,
+) -> Result<String> {
+    let session_id = parse_session_id(session_hex)?;
+    let bytes = general_purpose::STANDARD.decode(psbt_base64)?;
+    let (mut psbt, format) = decode_psbt_any(&bytes)?;
+    let wallets = registered_musig_wallets(handle);
+    let fee_limits = stored_fee_limits(handle);
+    let options = SignOptions {
+        fee_limits: Some(&fee_limits),
+        review: Some((lcd, buttons)),
+        ..SignOptions::default()
+    };
+    musig.add_partial_sigs(session_id, &mut psbt, session.keys(), &wallets, &options)?;
+    Ok(general_purpose::STANDARD.encode(encode_psbt(&psbt, &format)?))
+}
+
+/// Sign a message for the address at `path` once it is approved on the device; `format` picks
+/// the BIP-322 encoding, or `legacy` for a BIP-137 `signmessage` signature
+fn sign_message(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    session: &SeedSession,
+    format: &str,
+    script_type: &str,
+    path: &str,
+    message_base64: &str,
+) -> Result<String> {
+    let script_type = parse_script_type(script_type)?;
+    let path = DerivationPath::from_str(path)?;
+    let message = parse_message(message_base64)?;
+    let format = match format {
+        "simple" => Bip322Format::Simple,
+        "full" => Bip322Format::Full,
+        "legacy" => {
+            return sign_legacy_message(lcd, buttons, session.keys(), &path, script_type, &message)
+        }
+        _ => bail!("unknown message signature format: {}", format),
+    };
+    sign_bip322(
+        lcd,
+        buttons,
+        session.keys(),
+        &path,
+        script_type,
+        &message,
+        format,
+    )
+}
+
+/// Run one request line from the host, `<command> <arguments...>`, and return the reply.
+///
+/// `register_wallet <descriptor>` stores a multisig wallet once it is approved on the device;
+/// `register_policy <name> <template> <key>...` answers with the hex HMAC of an approved BIP-388
+/// policy; `sign <psbt>` signs a base64 PSBT for the registered wallets and
+/// `sign_policy <hmac> <psbt> <name> <template> <key>...` for a policy registered earlier.
+///
+/// `register_musig <descriptor>` stores an approved `tr(musig(...))` wallet; `musig_nonces <psbt>`
+/// starts a signing session in `musig` and `musig_sign <session> <psbt>` finishes it.
+///
+/// `combine <psbt> <psbt>...` merges base64 PSBTs from several cosigners.
+///
+/// `anti_exfil_commit <psbt> <slot>=<commitment>...` takes the host's commitments into
+/// `anti_exfil` and answers with the device nonce commitments; `anti_exfil_sign <psbt>
+/// <slot>=<host data>...` then signs with the randomness behind them. A slot is an input index,
+/// followed by `:<leaf hash>` for a tapscript signature.
+///
+/// `set_fee_limits <limits>` replaces the stored fee rules, in the form `FeeLimits::encode`
+/// writes, once they are approved on the device.
+///
+/// `sign_message <simple|full|legacy> <p2pkh|p2wpkh|p2tr> <path> <message>` signs a base64
+/// message with BIP-322, or BIP-137 for `legacy`, once it is approved on the device, and `verify_message <address> <signature> <message>`
+/// checks someone else's BIP-322 signature.
+///
+/// `passphrase` has the user type a BIP-39 passphrase on the device and switches `session` to
+/// the wallet it opens, answering with its fingerprint; typing nothing goes back to the standard
+/// wallet. The passphrase itself never crosses the host link.
+pub fn handle_command(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    session: &mut SeedSession,
+    musig: &mut MusigSigner,
+    anti_exfil: &mut AntiExfilRounds,
+    line: &str,
+) -> Result<String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let arguments: Vec<&str> = words.collect();
+    match (command, arguments.as_slice()) {
+        ("register_wallet", [descriptor]) => {
+            let wallet = register_wallet(lcd, buttons, handle, descriptor, session.keys())?;
+            Ok(wallet.descriptor().to_string())
+        }
+        ("register_musig", [descriptor]) => {
+            let wallet = register_musig_wallet(lcd, buttons, handle, descriptor, session.keys())?;
+            Ok(wallet.descriptor().to_string())
+        }
+        ("passphrase", []) => {
+            let fingerprint = enter_passphrase(lcd, buttons, session)?;
+            Ok(fingerprint.to_string())
+        }
+        ("register_policy", policy) => {
+            let policy = parse_policy(policy)?;
+            let hmac = register_policy(lcd, buttons, &policy, session.keys())?;
+            Ok(hex::encode(hmac))
+        }
+        ("sign", [psbt]) => sign(lcd, buttons, handle, session, psbt, None),
+        ("sign_policy", [hmac, psbt, policy @ ..]) => {
+            let hmac = parse_hmac(hmac)?;
+            let policy = parse_policy(policy)?;
+            sign(lcd, buttons, handle, session, psbt, Some((&policy, &hmac)))
+        }
+        ("set_fee_limits", [limits]) => {
+            let limits = FeeLimits::decode(limits)?;
+            update_fee_limits(lcd, buttons, handle, &limits)?;
+            Ok(limits.encode())
+        }
+        ("sign_message", [format, script_type, path, message]) => {
+            sign_message(lcd, buttons, session, format, script_type, path, message)
+        }
+        ("verify_message", [address, signature, message]) => {
+            let address = Address::from_str(address)?.assume_checked();
+            verify_bip322(&address, &parse_message(message)?, signature)?;
+            Ok("valid".to_string())
+        }
+        ("musig_nonces", [psbt]) => musig_nonces(handle, session, musig, psbt),
+        ("musig_sign", [session_id, psbt]) => {
+            musig_sign(lcd, buttons, handle, session, musig, session_id, psbt)
+        }
+        ("combine", psbts) if psbts.len() >= 2 => combine(psbts),
+        ("anti_exfil_commit", [psbt, commitments @ ..]) if !commitments.is_empty() => {
+            anti_exfil_commit(handle, session, anti_exfil, psbt, commitments)
+        }
+        ("anti_exfil_sign", [psbt, host_data @ ..]) if !host_data.is_empty() => {
+            anti_exfil_sign(lcd, buttons, handle, session, anti_exfil, psbt, host_data)
+        }
+        (
+            "register_wallet" | "register_musig" | "passphrase" | "set_fee_limits" | "sign"
+            | "sign_policy" | "sign_message" | "verify_message" | "musig_nonces" | "musig_sign"
+            | "combine" | "anti_exfil_commit" | "anti_exfil_sign",
+            _,
+        ) => {
+            bail!("wrong number of arguments for {}", command)
+        }
+        _ => bail!("unknown command: {}", command),
+    }
+}
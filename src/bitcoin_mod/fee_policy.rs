@@ -0,0 +1,331 @@
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::{Amount, FeeRate};
+use esp_idf_svc::sys::nvs_handle_t;
+
+use crate::bitcoin_mod::transaction::TxSummary;
+use crate::nvs::memory::{load_fee_limits, save_fee_limits};
+use crate::ui::display::LcdController;
+use crate::ui::input::Buttons;
+use crate::ui::screens::{display_fee_limits, display_fee_violation};
+
+/// What happens when a fee rule is broken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeAction {
+    /// Refuse to sign
+    Block,
+    /// Sign only after the user accepts an on-screen warning
+    Warn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit<T> {
+    pub value: T,
+    pub action: FeeAction,
+}
+
+/// Fee rules checked before signing; a `None` rule is disabled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeLimits {
+    pub max_fee: Option<Limit<Amount>>,
+    pub max_fee_rate: Option<Limit<FeeRate>>,
+    /// Fee over the amount leaving the wallet, in hundredths of a percent
+    pub max_fee_basis_points: Option<Limit<u64>>,
+}
+
+impl Default for FeeLimits {
+    fn default() -> Self {
+        FeeLimits {
+            max_fee: Some(Limit {
+                value: Amount::from_sat(1_000_000),
+                action: FeeAction::Block,
+            }),
+            max_fee_rate: Some(Limit {
+                value: FeeRate::from_sat_per_vb_u32(500),
+                action: FeeAction::Warn,
+            }),
+            max_fee_basis_points: Some(Limit {
+                value: 1_000,
+                action: FeeAction::Warn,
+            }),
+        }
+    }
+}
+
+/// The rule a PSBT broke, with the values that broke it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeeRule {
+    /// Some input amount is missing, so none of the rules can be checked
+    UnknownFee,
+    AbsoluteFee {
+        fee: Amount,
+        limit: Amount,
+    },
+    FeeRate {
+        rate: FeeRate,
+        limit: FeeRate,
+    },
+    FeeShare {
+        basis_points: u64,
+        limit: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeViolation {
+    pub rule: FeeRule,
+    pub action: FeeAction,
+}
+
+impl fmt::Display for FeeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeeRule::UnknownFee => write!(f, "fee unknown, input amounts missing"),
+            FeeRule::AbsoluteFee { fee, limit } => write!(f, "fee {} above {}", fee, limit),
+            FeeRule::FeeRate { rate, limit } => write!(
+                f,
+                "fee rate {} sat/vB above {} sat/vB",
+                rate.to_sat_per_vb_ceil(),
+                limit.to_sat_per_vb_ceil()
+            ),
+            FeeRule::FeeShare {
+                basis_points,
+                limit,
+            } => write!(
+                f,
+                "fee is {}.{:02}% of spend, above {}.{:02}%",
+                basis_points / 100,
+                basis_points % 100,
+                limit / 100,
+                limit % 100
+            ),
+        }
+    }
+}
+
+impl fmt::Display for FeeViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            FeeAction::Block => write!(f, "{} (blocked)", self.rule),
+            FeeAction::Warn => write!(f, "{}", self.rule),
+        }
+    }
+}
+
+fn encode_action(action: FeeAction) -> &'static str {
+    match action {
+        FeeAction::Block => "block",
+        FeeAction::Warn => "warn",
+    }
+}
+
+/// Parse one `value:action` rule, where `value` is an integer
+fn decode_rule(rule: &str) -> Result<(u64, FeeAction)> {
+    let (value, action) = rule
+        .split_once(':')
+        .ok_or_else(|| anyhow!("fee rule without action: {}", rule))?;
+    let action = match action {
+        "block" => FeeAction::Block,
+        "warn" => FeeAction::Warn,
+        _ => bail!("unknown fee action: {}", action),
+    };
+    Ok((value.parse()?, action))
+}
+
+impl FeeLimits {
+    /// Compact form kept in NVS, e.g. `max_fee=1000000:block,max_rate=125000:warn`.
+    ///
+    /// `max_fee` is in satoshis, `max_rate` in sat/kwu (250 sat/kwu make 1 sat/vB, so 125000 is
+    /// 500 sat/vB) and `max_bps` in hundredths of a percent of the spend.
+    pub fn encode(&self) -> String {
+        let mut rules = Vec::new();
+        if let Some(limit) = &self.max_fee {
+            let action = encode_action(limit.action);
+            rules.push(format!("max_fee={}:{}", limit.value.to_sat(), action));
+        }
+        if let Some(limit) = &self.max_fee_rate {
+            let action = encode_action(limit.action);
+            rules.push(format!(
+                "max_rate={}:{}",
+                limit.value.to_sat_per_kwu(),
+                action
+            ));
+        }
+        if let Some(limit) = &self.max_fee_basis_points {
+            let action = encode_action(limit.action);
+            rules.push(format!("max_bps={}:{}", limit.value, action));
+        }
+        rules.join(",")
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let mut limits = FeeLimits {
+            max_fee: None,
+            max_fee_rate: None,
+            max_fee_basis_points: None,
+        };
+        for rule in encoded.split(',').filter(|rule| !rule.is_empty()) {
+            let (name, rule) = rule
+                .split_once('=')
+                .ok_or_else(|| anyhow!("malformed fee rule: {}", rule))?;
+            let (value, action) = decode_rule(rule)?;
+            match name {
+                "max_fee" => {
+                    limits.max_fee = Some(Limit {
+                        value: Amount::from_sat(value),
+                        action,
+                    })
+                }
+                "max_rate" => {
+                    limits.max_fee_rate = Some(Limit {
+                        value: FeeRate::from_sat_per_kwu(value),
+                        action,
+                    })
+                }
+                "max_bps" => limits.max_fee_basis_points = Some(Limit { value, action }),
+                _ => bail!("unknown fee rule: {}", name),
+            }
+        }
+        Ok(limits)
+    }
+
+    /// Every rule `summary` breaks; an empty list means the fee is fine
+    pub fn evaluate(&self, summary: &TxSummary) -> Vec<FeeViolation> {
+        let Some(fee) = summary.fee else {
+            return vec![FeeViolation {
+                rule: FeeRule::UnknownFee,
+                action: FeeAction::Block,
+            }];
+        };
+
+        let mut violations = Vec::new();
+        if let Some(limit) = &self.max_fee {
+            if fee > limit.value {
+                violations.push(FeeViolation {
+                    rule: FeeRule::AbsoluteFee {
+                        fee,
+                        limit: limit.value,
+                    },
+                    action: limit.action,
+                });
+            }
+        }
+        if let Some(limit) = &self.max_fee_rate {
+            let rate = fee.div_by_weight_floor(summary.estimated_weight);
+            if let Some(rate) = rate.filter(|rate| *rate > limit.value) {
+                violations.push(FeeViolation {
+                    rule: FeeRule::FeeRate {
+                        rate,
+                        limit: limit.value,
+                    },
+                    action: limit.action,
+                });
+            }
+        }
+        if let Some(limit) = &self.max_fee_basis_points {
+            let spend = summary.spend_amount().to_sat();
+            // A transfer between our own outputs spends nothing, so there is no share to judge
+            let share = fee.to_sat().saturating_mul(10_000).checked_div(spend);
+            if let Some(basis_points) = share.filter(|share| *share > limit.value) {
+                violations.push(FeeViolation {
+                    rule: FeeRule::FeeShare {
+                        basis_points,
+                        limit: limit.value,
+                    },
+                    action: limit.action,
+                });
+            }
+        }
+        violations
+    }
+}
+
+/// Fee limits from NVS, falling back to the defaults when none are stored or they do not parse
+pub fn stored_fee_limits(handle: nvs_handle_t) -> FeeLimits {
+    match load_fee_limits(handle) {
+        Ok(encoded) => FeeLimits::decode(&encoded).unwrap_or_else(|err| {
+            log::warn!("Ignoring stored fee limits: {}", err);
+            FeeLimits::default()
+        }),
+        Err(_) => FeeLimits::default(),
+    }
+}
+
+pub fn store_fee_limits(handle: nvs_handle_t, limits: &FeeLimits) -> Result<()> {
+    save_fee_limits(handle, &limits.encode())
+        .map_err(|err| anyhow!("Failed to save fee limits: {}", err))
+}
+
+/// Show new fee limits and store them once the user approves; looser limits are a way to drain
+/// the wallet through fees, so the host cannot set them on its own
+pub fn update_fee_limits(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    limits: &FeeLimits,
+) -> Result<()> {
+    display_fee_limits(lcd, limits);
+    if !buttons.confirm() {
+        bail!("Fee limits rejected on device");
+    }
+    store_fee_limits(handle, limits)
+}
+
+/// Walk the user through every violation; fails on a blocking one or a rejected warning
+pub fn review_fee_violations(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    violations: &[FeeViolation],
+) -> Result<()> {
+    for violation in violations {
+        display_fee_violation(lcd, violation);
+        match violation.action {
+            FeeAction::Block => {
+                buttons.wait_for_press();
+                bail!("Fee policy blocked signing: {}", violation.rule);
+            }
+            FeeAction::Warn => {
+                if !buttons.confirm() {
+                    bail!("Fee warning rejected on device: {}", violation.rule);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvs::memory::open_nvs_partition;
+    use crate::ui::scripted_review;
+
+    #[test]
+    fn encodes_rates_in_sat_per_kwu() {
+        let limits = FeeLimits::decode("max_fee=1000000:block,max_rate=125000:warn").unwrap();
+        let max_fee_rate = limits.max_fee_rate.unwrap();
+        assert_eq!(max_fee_rate.value, FeeRate::from_sat_per_vb_u32(500));
+        assert_eq!(max_fee_rate.action, FeeAction::Warn);
+        assert_eq!(limits.max_fee_basis_points, None);
+
+        let defaults = FeeLimits::default();
+        assert_eq!(FeeLimits::decode(&defaults.encode()).unwrap(), defaults);
+        assert!(defaults.encode().contains("max_rate=125000:warn"));
+    }
+
+    #[test]
+    fn stores_only_approved_limits() {
+        let handle = open_nvs_partition().unwrap();
+        let loose = FeeLimits::decode("max_fee=50000000:warn").unwrap();
+
+        let (lcd, buttons) = scripted_review(&[0]);
+        assert!(update_fee_limits(&lcd, &buttons, handle, &loose).is_err());
+        assert_eq!(stored_fee_limits(handle), FeeLimits::default());
+        assert!(lcd.screens()[0].contains("No rate limit"));
+
+        let (lcd, buttons) = scripted_review(&[35]);
+        update_fee_limits(&lcd, &buttons, handle, &loose).unwrap();
+        assert_eq!(stored_fee_limits(handle), loose);
+    }
+}
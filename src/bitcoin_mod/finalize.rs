@@ -0,0 +1,286 @@
+use anyhow::{anyhow, bail, Result};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::psbt::Input;
+use bitcoin::script::{Builder, Instruction, PushBytesBuf};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::taproot::{LeafVersion, TapLeafHash};
+use bitcoin::{
+    ecdsa, CompressedPublicKey, Psbt, PublicKey, Script, ScriptBuf, Transaction, Witness,
+};
+
+use crate::bitcoin_mod::signature::spent_output;
+use crate::bitcoin_mod::verify::{verify_finalized_psbt, TapLeafTemplate};
+
+/// Script sig and witness that complete one input
+type FinalFields = (Option<ScriptBuf>, Option<Witness>);
+
+fn push_signature(builder: Builder, signature: &ecdsa::Signature) -> Result<Builder> {
+    let bytes = PushBytesBuf::try_from(signature.to_vec())?;
+    Ok(builder.push_slice(bytes))
+}
+
+/// The only signature in `input`, together with its key, which has to be the one `spent` pays.
+///
+/// `spent` is the P2PKH scriptPubKey or the P2WPKH witness program.
+fn single_signature(input: &Input, spent: &Script) -> Result<(PublicKey, ecdsa::Signature)> {
+    let mut signatures = input.partial_sigs.iter();
+    let (pubkey, signature) = match (signatures.next(), signatures.next()) {
+        (Some((pubkey, signature)), None) => (*pubkey, *signature),
+        (None, _) => bail!("not signed"),
+        (Some(_), Some(_)) => bail!("more than one signature for a single-key input"),
+    };
+    let matches = if spent.is_p2pkh() {
+        spent == ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()).as_script()
+    } else {
+        CompressedPublicKey::try_from(pubkey)
+            .is_ok_and(|pubkey| spent == ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()).as_script())
+    };
+    if !matches {
+        bail!("signature is for a key the script does not pay to");
+    }
+    Ok((pubkey, signature))
+}
+
+/// Witness for a bare CHECKMULTISIG script, with signatures in the order of its keys
+fn multisig_witness(input: &Input, witness_script: &Script) -> Result<Witness> {
+    if !witness_script.is_multisig() {
+        bail!("witness script is not a multisig");
+    }
+    let instructions: Vec<_> = witness_script
+        .instructions()
+        .collect::<Result<_, _>>()
+        .map_err(|err| anyhow!("malformed witness script: {}", err))?;
+    let threshold = match instructions.first() {
+        Some(Instruction::Op(op)) => match op.classify(ClassifyContext::Legacy) {
+            Class::PushNum(threshold) => threshold as usize,
+            _ => bail!("multisig without threshold"),
+        },
+        _ => bail!("multisig without threshold"),
+    };
+
+    let signatures: Vec<&ecdsa::Signature> = instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::PushBytes(bytes) => PublicKey::from_slice(bytes.as_bytes()).ok(),
+            _ => None,
+        })
+        .filter_map(|pubkey| input.partial_sigs.get(&pubkey))
+        .take(threshold)
+        .collect();
+    if signatures.len() < threshold {
+        bail!("{} of {} signatures", signatures.len(), threshold);
+    }
+
+    let mut witness = Witness::new();
+    // CHECKMULTISIG pops one element more than it uses
+    witness.push([]);
+    for signature in signatures {
+        witness.push(signature.to_vec());
+    }
+    witness.push(witness_script.as_bytes());
+    Ok(witness)
+}
+
+/// Script-path witness through the first leaf in `tap_scripts` we hold enough signatures for.
+///
+/// Timelocks are left to the verifier, which checks them against the transaction on extraction.
+fn tap_script_witness(input: &Input, output_key: XOnlyPublicKey) -> Result<Witness> {
+    let secp = Secp256k1::verification_only();
+    for (control_block, (script, leaf_version)) in &input.tap_scripts {
+        if *leaf_version != LeafVersion::TapScript
+            || !control_block.verify_taproot_commitment(&secp, output_key, script)
+        {
+            continue;
+        }
+        let Some(template) = TapLeafTemplate::parse(script) else {
+            continue;
+        };
+        let leaf_hash = TapLeafHash::from_script(script, *leaf_version);
+
+        // Exactly `threshold` signatures, since the leaf counts every valid one
+        let mut missing = template.threshold;
+        let signatures: Vec<Vec<u8>> = template
+            .keys
+            .iter()
+            .map(|key| match input.tap_script_sigs.get(&(*key, leaf_hash)) {
+                Some(signature) if missing > 0 => {
+                    missing -= 1;
+                    signature.to_vec()
+                }
+                _ => Vec::new(),
+            })
+            .collect();
+        if missing > 0 {
+            continue;
+        }
+
+        let mut witness = Witness::new();
+        // The first key checks the top of the stack
+        for signature in signatures.iter().rev() {
+            witness.push(signature);
+        }
+        witness.push(script.as_bytes());
+        witness.push(control_block.serialize());
+        return Ok(witness);
+    }
+    bail!("no key-path signature and no leaf with enough signatures")
+}
+
+fn final_fields(psbt: &Psbt, index: usize) -> Result<FinalFields> {
+    let input = &psbt.inputs[index];
+    let utxo = spent_output(psbt, index).ok_or_else(|| anyhow!("missing previous output"))?;
+    let script_pubkey = &utxo.script_pubkey;
+
+    if script_pubkey.is_p2pkh() {
+        let (pubkey, signature) = single_signature(input, script_pubkey)?;
+        let script_sig = push_signature(Builder::new(), &signature)?
+            .push_key(&pubkey)
+            .into_script();
+        Ok((Some(script_sig), None))
+    } else if script_pubkey.is_p2wpkh() {
+        let (pubkey, signature) = single_signature(input, script_pubkey)?;
+        Ok((None, Some(Witness::p2wpkh(&signature, &pubkey.inner))))
+    } else if script_pubkey.is_p2sh() {
+        let redeem_script = input
+            .redeem_script
+            .as_ref()
+            .filter(|script| script.is_p2wpkh())
+            .ok_or_else(|| anyhow!("only P2SH-P2WPKH is supported"))?;
+        let (pubkey, signature) = single_signature(input, redeem_script)?;
+        let script_sig = Builder::new()
+            .push_slice(PushBytesBuf::try_from(redeem_script.to_bytes())?)
+            .into_script();
+        Ok((
+            Some(script_sig),
+            Some(Witness::p2wpkh(&signature, &pubkey.inner)),
+        ))
+    } else if script_pubkey.is_p2wsh() {
+        let witness_script = input
+            .witness_script
+            .as_ref()
+            .ok_or_else(|| anyhow!("missing witness_script"))?;
+        Ok((None, Some(multisig_witness(input, witness_script)?)))
+    } else if script_pubkey.is_p2tr() {
+        if let Some(signature) = input.tap_key_sig {
+            return Ok((None, Some(Witness::p2tr_key_spend(&signature))));
+        }
+        let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])?;
+        Ok((None, Some(tap_script_witness(input, output_key)?)))
+    } else {
+        bail!("unsupported script type")
+    }
+}
+
+/// Turn signatures into final script sigs and witnesses.
+///
+/// Either every input ends up final or the PSBT is left untouched.
+pub fn finalize_psbt(psbt: &mut Psbt) -> Result<()> {
+    let mut finals = Vec::new();
+    for index in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[index];
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+        let fields =
+            final_fields(psbt, index).map_err(|err| anyhow!("input {}: {}", index, err))?;
+        finals.push((index, fields));
+    }
+
+    for (index, (script_sig, witness)) in finals {
+        let input = &mut psbt.inputs[index];
+        // BIP-174: a finalized input keeps its UTXO and final fields, nothing else
+        *input = Input {
+            non_witness_utxo: input.non_witness_utxo.take(),
+            witness_utxo: input.witness_utxo.take(),
+            final_script_sig: script_sig,
+            final_script_witness: witness,
+            unknown: std::mem::take(&mut input.unknown),
+            proprietary: std::mem::take(&mut input.proprietary),
+            ..Default::default()
+        };
+    }
+    Ok(())
+}
+
+/// Transaction of a finalized PSBT, verified and ready to broadcast
+pub fn extract_transaction(psbt: &Psbt) -> Result<Transaction> {
+    let unfinalized = psbt
+        .inputs
+        .iter()
+        .position(|input| input.final_script_sig.is_none() && input.final_script_witness.is_none());
+    if let Some(index) = unfinalized {
+        bail!("input {} is not finalized", index);
+    }
+    // Fee limits are enforced by `sign_psbt`, which applies the stored ones before it signs
+    let tx = psbt.clone().extract_tx_unchecked_fee_rate();
+    // A wrong key or sighash caught here never reaches the network
+    verify_finalized_psbt(psbt, &tx)?;
+    Ok(tx)
+}
+
+/// Raw transaction hex the host can broadcast
+pub fn extract_transaction_hex(psbt: &Psbt) -> Result<String> {
+    Ok(serialize_hex(&extract_transaction(psbt)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+    use bitcoin::sighash::EcdsaSighashType;
+    use bitcoin::{absolute, transaction, Amount, TxIn, TxOut};
+
+    fn key(byte: u8) -> (SecretKey, PublicKey) {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[byte; 32]).unwrap();
+        (secret_key, PublicKey::new(secret_key.public_key(&secp)))
+    }
+
+    /// P2WPKH spend of `owner` carrying a signature by `signer`; the signature itself is not
+    /// checked while finalizing, only where it goes
+    fn signed_p2wpkh(owner: u8, signer: u8) -> Psbt {
+        let (_, owner_key) = key(owner);
+        let (signer_secret, signer_key) = key(signer);
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: Vec::new(),
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        let owner_key = CompressedPublicKey::try_from(owner_key).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new_p2wpkh(&owner_key.wpubkey_hash()),
+        });
+        let signature = Secp256k1::new().sign_ecdsa(&Message::from_digest([1; 32]), &signer_secret);
+        psbt.inputs[0].partial_sigs.insert(
+            signer_key,
+            ecdsa::Signature {
+                signature,
+                sighash_type: EcdsaSighashType::All,
+            },
+        );
+        psbt
+    }
+
+    #[test]
+    fn finalizes_signature_of_the_spent_key() {
+        let mut psbt = signed_p2wpkh(1, 1);
+        finalize_psbt(&mut psbt).unwrap();
+        assert_eq!(
+            psbt.inputs[0].final_script_witness.as_ref().unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn rejects_signature_of_another_key() {
+        let mut psbt = signed_p2wpkh(1, 2);
+        let unchanged = psbt.clone();
+        assert!(finalize_psbt(&mut psbt).is_err());
+        assert_eq!(psbt, unchanged);
+    }
+}
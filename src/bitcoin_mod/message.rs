@@ -0,0 +1,416 @@
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use bitcoin::bip32::{self, DerivationPath};
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::opcodes::all::{OP_PUSHBYTES_0, OP_RETURN};
+use bitcoin::psbt::Input;
+use bitcoin::script::Builder;
+use bitcoin::secp256k1::{Message, Secp256k1, Signing, Verification};
+use bitcoin::sign_message::signed_msg_hash;
+use bitcoin::transaction::Version;
+use bitcoin::{
+    absolute, Address, Amount, CompressedPublicKey, Network, OutPoint, PrivateKey, Psbt, Script,
+    ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+
+use crate::bitcoin_mod::finalize::{extract_transaction, finalize_psbt};
+use crate::bitcoin_mod::signature::{sign_approved_psbt, KeySource, SignOptions};
+use crate::bitcoin_mod::transaction::ScriptType;
+use crate::bitcoin_mod::verify::verify_transaction;
+use crate::security::pin;
+use crate::ui::display::LcdController;
+use crate::ui::input::Buttons;
+use crate::ui::screens::display_message_page;
+
+/// Tag of the BIP-340 style hash a BIP-322 message commits to
+const BIP322_TAG: &[u8] = b"BIP0322-signed-message";
+
+/// BIP-137 header base for an uncompressed P2PKH key; the recovery id is added to it
+const BIP137_P2PKH_UNCOMPRESSED: u8 = 27;
+const BIP137_P2PKH_COMPRESSED: u8 = 31;
+/// BIP-137 header base for a native segwit P2WPKH key
+const BIP137_P2WPKH: u8 = 39;
+
+/// Characters that fit on one line of the display
+const MESSAGE_LINE_WIDTH: usize = 24;
+/// Message lines shown per page, below the page title and above the prompt
+const MESSAGE_PAGE_LINES: usize = 4;
+
+/// How a BIP-322 signature is serialized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bip322Format {
+    /// Only the witness of the `to_sign` transaction
+    Simple,
+    /// The whole `to_sign` transaction
+    Full,
+}
+
+fn message_hash(message: &[u8]) -> sha256::Hash {
+    let tag = sha256::Hash::hash(BIP322_TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    engine.input(message);
+    sha256::Hash::from_engine(engine)
+}
+
+/// Virtual transaction whose only output is the address being proven
+fn to_spend(script_pubkey: &Script, message: &[u8]) -> Transaction {
+    let script_sig = Builder::new()
+        .push_opcode(OP_PUSHBYTES_0)
+        .push_slice(message_hash(message).to_byte_array())
+        .into_script();
+    Transaction {
+        version: Version(0),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: Txid::all_zeros(),
+                vout: u32::MAX,
+            },
+            script_sig,
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: script_pubkey.to_owned(),
+        }],
+    }
+}
+
+/// Unsigned virtual transaction spending `to_spend` into an OP_RETURN
+fn to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: to_spend.compute_txid(),
+                vout: 0,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+/// Key at `path` with the origin the signer needs to find it again
+fn signing_key<C: Signing>(
+    secp: &Secp256k1<C>,
+    keys: &KeySource,
+    path: &DerivationPath,
+) -> Result<(PrivateKey, bip32::KeySource)> {
+    let origin = (keys.fingerprint(secp), path.clone());
+    let key = keys
+        .private_key_at(secp, Some(&origin))
+        .ok_or_else(|| anyhow!("Failed to derive key at {}", path))?;
+    Ok((key, origin))
+}
+
+/// Script of a single-key address of the given type
+fn single_key_script<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    key: &PrivateKey,
+    script_type: ScriptType,
+) -> Result<ScriptBuf> {
+    match script_type {
+        ScriptType::P2pkh => Ok(ScriptBuf::new_p2pkh(&key.public_key(secp).pubkey_hash())),
+        ScriptType::P2wpkh => {
+            let pubkey = CompressedPublicKey::from_private_key(secp, key)?;
+            Ok(ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()))
+        }
+        ScriptType::P2tr => {
+            let (internal_key, _) = key.inner.x_only_public_key(secp);
+            Ok(ScriptBuf::new_p2tr(secp, internal_key, None))
+        }
+        _ => bail!(
+            "BIP-322 signing supports p2wpkh and p2tr, not {}",
+            script_type
+        ),
+    }
+}
+
+/// Address the device signs messages for at `path`
+pub fn message_address(
+    keys: &KeySource,
+    path: &DerivationPath,
+    script_type: ScriptType,
+    network: Network,
+) -> Result<Address> {
+    let secp = Secp256k1::new();
+    let (key, _) = signing_key(&secp, keys, path)?;
+    let script_pubkey = single_key_script(&secp, &key, script_type)?;
+    Ok(Address::from_script(&script_pubkey, network)?)
+}
+
+/// Sign `message` for the address at `path` (BIP-322), returned in base64, once the user has
+/// read it on `lcd` and approved it.
+///
+/// The virtual transaction goes through the regular signer, finalizer and verifier, so a
+/// message signature is held to the same checks as a spend.
+pub fn sign_bip322(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    keys: &KeySource,
+    path: &DerivationPath,
+    script_type: ScriptType,
+    message: &str,
+    format: Bip322Format,
+) -> Result<String> {
+    pin::ensure_unlocked()?;
+    if !matches!(script_type, ScriptType::P2wpkh | ScriptType::P2tr) {
+        bail!(
+            "BIP-322 signing supports p2wpkh and p2tr, not {}",
+            script_type
+        );
+    }
+    let secp = Secp256k1::new();
+    let (key, origin) = signing_key(&secp, keys, path)?;
+    let script_pubkey = single_key_script(&secp, &key, script_type)?;
+    let address = Address::from_script(&script_pubkey, Network::Bitcoin)?;
+    review_message(lcd, buttons, &address, message)?;
+    let to_spend = to_spend(&script_pubkey, message.as_bytes());
+
+    let mut input = Input {
+        witness_utxo: Some(to_spend.output[0].clone()),
+        ..Default::default()
+    };
+    if script_type == ScriptType::P2tr {
+        let (internal_key, _) = key.inner.x_only_public_key(&secp);
+        input.tap_internal_key = Some(internal_key);
+        input
+            .tap_key_origins
+            .insert(internal_key, (Vec::new(), origin));
+    } else {
+        input
+            .bip32_derivation
+            .insert(key.public_key(&secp).inner, origin);
+    }
+    let mut psbt = Psbt::from_unsigned_tx(to_sign(&to_spend))?;
+    psbt.inputs[0] = input;
+
+    // The message behind the virtual transaction was just approved, and it moves no coins, so
+    // there is no spend to review and no fee to judge
+    let report = sign_approved_psbt(&mut psbt, keys, &SignOptions::default());
+    if !report.is_complete() {
+        bail!("Failed to sign message: {}", report);
+    }
+    finalize_psbt(&mut psbt)?;
+    let to_sign = extract_transaction(&psbt)?;
+
+    let encoded = match format {
+        Bip322Format::Simple => serialize(&to_sign.input[0].witness),
+        Bip322Format::Full => serialize(&to_sign),
+    };
+    Ok(base64::engine::general_purpose::STANDARD.encode(encoded))
+}
+
+/// Sign `message` the way Bitcoin Core's `signmessage` does, with a BIP-137 header, in base64,
+/// once the user has read it on `lcd` and approved it.
+///
+/// The header tells verifiers which address type the key stands for, so P2WPKH proofs are
+/// not mistaken for P2PKH ones.
+pub fn sign_legacy_message(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    keys: &KeySource,
+    path: &DerivationPath,
+    script_type: ScriptType,
+    message: &str,
+) -> Result<String> {
+    pin::ensure_unlocked()?;
+    let secp = Secp256k1::new();
+    let (key, _) = signing_key(&secp, keys, path)?;
+    let header_base = match script_type {
+        ScriptType::P2pkh if key.compressed => BIP137_P2PKH_COMPRESSED,
+        ScriptType::P2pkh => BIP137_P2PKH_UNCOMPRESSED,
+        ScriptType::P2wpkh if key.compressed => BIP137_P2WPKH,
+        ScriptType::P2wpkh => bail!("p2wpkh needs a compressed key"),
+        _ => bail!("signmessage supports p2pkh and p2wpkh, not {}", script_type),
+    };
+    let script_pubkey = single_key_script(&secp, &key, script_type)?;
+    let address = Address::from_script(&script_pubkey, Network::Bitcoin)?;
+    review_message(lcd, buttons, &address, message)?;
+
+    let message = Message::from_digest(signed_msg_hash(message).to_byte_array());
+    let signature = secp.sign_ecdsa_recoverable(&message, &key.inner);
+
+    // A signature that does not recover our own key must never leave the device
+    let recovered = secp
+        .recover_ecdsa(&message, &signature)
+        .map_err(|err| anyhow!("Failed to recover signing key: {}", err))?;
+    if recovered != key.public_key(&secp).inner {
+        bail!("Message signature does not recover the signing key");
+    }
+
+    let (recovery_id, compact) = signature.serialize_compact();
+    let mut encoded = Vec::with_capacity(65);
+    encoded.push(header_base + recovery_id.to_i32() as u8);
+    encoded.extend_from_slice(&compact);
+    Ok(base64::engine::general_purpose::STANDARD.encode(encoded))
+}
+
+/// Check a BIP-322 signature someone else made over `message` for `address`.
+///
+/// Both the simple and the full encoding are accepted. Only p2wpkh and p2tr key-path proofs
+/// without extra inputs can be checked, the same templates the device signs.
+pub fn verify_bip322(address: &Address, message: &str, signature: &str) -> Result<()> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(signature.trim())
+        .map_err(|err| anyhow!("signature is not base64: {}", err))?;
+    let script_pubkey = address.script_pubkey();
+    if !script_pubkey.is_p2wpkh() && !script_pubkey.is_p2tr() {
+        bail!("only p2wpkh and p2tr addresses can be verified");
+    }
+    let to_spend = to_spend(&script_pubkey, message.as_bytes());
+    let expected = to_sign(&to_spend);
+
+    let to_sign = if let Ok(witness) = deserialize::<Witness>(&bytes) {
+        let mut to_sign = expected;
+        to_sign.input[0].witness = witness;
+        to_sign
+    } else if let Ok(to_sign) = deserialize::<Transaction>(&bytes) {
+        if to_sign.input.len() != 1
+            || to_sign.input[0].previous_output != expected.input[0].previous_output
+        {
+            bail!("signature does not spend the message commitment");
+        }
+        if to_sign.output != expected.output {
+            bail!("signature must pay zero to a bare OP_RETURN");
+        }
+        to_sign
+    } else {
+        bail!("signature is neither a witness nor a transaction");
+    };
+
+    verify_transaction(&to_sign, &to_spend.output)
+        .map_err(|err| anyhow!("Invalid message signature: {}", err))
+}
+
+/// Message text broken into display lines, keeping the sender's own line breaks
+fn message_lines(message: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in message.split('\n') {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        for chunk in chars.chunks(MESSAGE_LINE_WIDTH) {
+            lines.push(chunk.iter().collect());
+        }
+    }
+    lines
+}
+
+/// Show every line of `message` and the address it is signed for; fails unless the user approves
+pub fn review_message(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    address: &Address,
+    message: &str,
+) -> Result<()> {
+    let lines = message_lines(message);
+    let pages: Vec<&[String]> = lines.chunks(MESSAGE_PAGE_LINES).collect();
+    for (page, text) in pages.iter().enumerate() {
+        display_message_page(lcd, page, pages.len(), text);
+        buttons.wait_for_press();
+    }
+
+    let address = address.to_string();
+    let mut screen = vec!["Sign message for".to_string()];
+    screen.extend(message_lines(&address));
+    screen.push("Press OK to sign".to_string());
+    let screen: Vec<&str> = screen.iter().map(String::as_str).collect();
+    lcd.write_lines(&screen)?;
+    if !buttons.confirm() {
+        bail!("Message signing rejected on device");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ui::{approving_review, scripted_review};
+
+    #[test]
+    fn signs_only_approved_messages() {
+        pin::unlock_for_tests();
+        let keys = KeySource::Single(PrivateKey::new(
+            bitcoin::secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
+            bitcoin::NetworkKind::Main,
+        ));
+        let path = DerivationPath::master();
+        let address = message_address(&keys, &path, ScriptType::P2wpkh, Network::Bitcoin).unwrap();
+
+        // One message page, then the final OK is refused
+        let (lcd, buttons) = scripted_review(&[35, 0]);
+        let err = sign_bip322(
+            &lcd,
+            &buttons,
+            &keys,
+            &path,
+            ScriptType::P2wpkh,
+            "Hello World",
+            Bip322Format::Simple,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Message signing rejected on device");
+        let screens = lcd.screens();
+        assert!(screens[0].contains("Hello World"));
+        assert!(screens[1].contains(&address.to_string()[..MESSAGE_LINE_WIDTH]));
+
+        let (lcd, buttons) = approving_review();
+        let signature = sign_bip322(
+            &lcd,
+            &buttons,
+            &keys,
+            &path,
+            ScriptType::P2wpkh,
+            "Hello World",
+            Bip322Format::Simple,
+        )
+        .unwrap();
+        verify_bip322(&address, "Hello World", &signature).unwrap();
+        assert!(verify_bip322(&address, "Hello World!", &signature).is_err());
+    }
+
+    #[test]
+    fn signs_legacy_messages_only_once_approved() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let key = PrivateKey::new(
+            bitcoin::secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
+            bitcoin::NetworkKind::Main,
+        );
+        let keys = KeySource::Single(key);
+        let path = DerivationPath::master();
+
+        let (lcd, buttons) = scripted_review(&[35, 0]);
+        let err = sign_legacy_message(&lcd, &buttons, &keys, &path, ScriptType::P2pkh, "Hello")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Message signing rejected on device");
+        assert!(lcd.screens()[0].contains("Hello"));
+
+        let (lcd, buttons) = approving_review();
+        let signature =
+            sign_legacy_message(&lcd, &buttons, &keys, &path, ScriptType::P2pkh, "Hello").unwrap();
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(signature)
+            .unwrap();
+        // Compressed P2PKH header plus the recovery id
+        assert!((BIP137_P2PKH_COMPRESSED..BIP137_P2WPKH).contains(&bytes[0]));
+        let signature = bitcoin::sign_message::MessageSignature::from_slice(&bytes).unwrap();
+        let recovered = signature
+            .recover_pubkey(&secp, signed_msg_hash("Hello"))
+            .unwrap();
+        assert_eq!(recovered, key.public_key(&secp));
+    }
+}
@@ -1 +1,13 @@
-pub mod signature;
\ No newline at end of file
+pub mod anti_exfil;
+pub mod combine;
+pub mod commands;
+pub mod fee_policy;
+pub mod finalize;
+pub mod message;
+pub mod musig;
+pub mod policy;
+pub mod psbt_v2;
+pub mod signature;
+pub mod transaction;
+pub mod verify;
+pub mod wallet;
@@ -0,0 +1,1082 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::bip32::{self, DerivationPath, Fingerprint};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::psbt::raw;
+use bitcoin::secp256k1::{
+    constants, PublicKey, Scalar, Secp256k1, SecretKey, Signing, Verification,
+};
+use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot::TapTweakHash;
+use bitcoin::{Psbt, ScriptBuf, TxOut, Txid};
+use esp_idf_svc::sys::nvs_handle_t;
+
+use crate::bitcoin_mod::signature::{approve_spend, spent_output, KeySource, SignOptions};
+use crate::nvs::memory::{load_musig_descriptors, save_musig_descriptor};
+use crate::security::entropy;
+use crate::security::pin;
+use crate::ui::display::LcdController;
+use crate::ui::input::Buttons;
+use crate::ui::screens::{display_musig_key, display_musig_wallet};
+
+/// BIP-373 input field listing the participants behind an aggregate key
+pub const PSBT_IN_MUSIG2_PARTICIPANT_PUBKEYS: u8 = 0x1a;
+/// BIP-373 input field holding one participant's public nonce
+pub const PSBT_IN_MUSIG2_PUB_NONCE: u8 = 0x1b;
+/// BIP-373 input field holding one participant's partial signature
+pub const PSBT_IN_MUSIG2_PARTIAL_SIG: u8 = 0x1c;
+
+/// Most participants accepted in one aggregate key
+const MAX_PARTICIPANTS: usize = 16;
+
+pub(crate) fn tagged_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for part in parts {
+        engine.input(part);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// A 256-bit hash reduced modulo the curve order
+pub(crate) fn scalar_mod_n(bytes: [u8; 32]) -> Scalar {
+    if let Ok(scalar) = Scalar::from_be_bytes(bytes) {
+        return scalar;
+    }
+    // Anything at or above the order is below twice the order, so one subtraction is enough
+    let mut reduced = [0u8; 32];
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let mut digit = bytes[i] as i16 - constants::CURVE_ORDER[i] as i16 - borrow;
+        borrow = (digit < 0) as i16;
+        if digit < 0 {
+            digit += 256;
+        }
+        reduced[i] = digit as u8;
+    }
+    Scalar::from_be_bytes(reduced).expect("reduced below the curve order")
+}
+
+/// Sum of curve points, `None` standing for the point at infinity
+fn add_points(points: &[Option<PublicKey>]) -> Option<PublicKey> {
+    let points: Vec<&PublicKey> = points.iter().flatten().collect();
+    if points.is_empty() {
+        return None;
+    }
+    // Combining only fails when the sum is the point at infinity
+    PublicKey::combine_keys(&points).ok()
+}
+
+/// `cbytes_ext` of BIP-327: 33 zero bytes for the point at infinity
+fn point_bytes_ext(point: Option<PublicKey>) -> [u8; 33] {
+    point.map_or([0; 33], |point| point.serialize())
+}
+
+fn has_even_y(point: &PublicKey) -> bool {
+    point.x_only_public_key().1 == bitcoin::secp256k1::Parity::Even
+}
+
+fn generator<C: Signing>(secp: &Secp256k1<C>) -> PublicKey {
+    let one = SecretKey::from_slice(&constants::ONE).expect("one is a valid key");
+    PublicKey::from_secret_key(secp, &one)
+}
+
+/// One participant of a `tr(musig(...))` descriptor, with an origin when it is ours
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MusigKey {
+    pub origin: Option<bip32::KeySource>,
+    pub pubkey: PublicKey,
+}
+
+impl MusigKey {
+    fn parse(key: &str) -> Result<Self> {
+        let (origin, pubkey) = match key.strip_prefix('[') {
+            Some(rest) => {
+                let (origin, pubkey) = rest
+                    .split_once(']')
+                    .ok_or_else(|| anyhow!("unterminated key origin"))?;
+                let (fingerprint, path) = origin.split_once('/').unwrap_or((origin, ""));
+                let origin = (
+                    Fingerprint::from_str(fingerprint)?,
+                    DerivationPath::from_str(path)?,
+                );
+                (Some(origin), pubkey)
+            }
+            None => (None, key),
+        };
+        Ok(MusigKey {
+            origin,
+            pubkey: PublicKey::from_str(pubkey)?,
+        })
+    }
+}
+
+/// A registered `tr(musig(...))` key-path wallet this device cosigns for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MusigWallet {
+    pub keys: Vec<MusigKey>,
+    descriptor: String,
+}
+
+impl MusigWallet {
+    /// Parse a `tr(musig([fp/path]pubkey,...))` descriptor, checksum optional.
+    ///
+    /// BIP-390 `musig()` sorts its keys (BIP-327 KeySort) before aggregating them, so the order
+    /// the descriptor spells them in does not change the aggregate key.
+    pub fn parse(descriptor: &str) -> Result<Self> {
+        let descriptor = descriptor.trim();
+        let body = descriptor.split('#').next().unwrap_or(descriptor);
+        let inner = body
+            .strip_prefix("tr(musig(")
+            .and_then(|rest| rest.strip_suffix("))"))
+            .ok_or_else(|| anyhow!("only tr(musig(...)) descriptors are supported"))?;
+        let mut keys = inner
+            .split(',')
+            .map(MusigKey::parse)
+            .collect::<Result<Vec<_>>>()?;
+        keys.sort_by_key(|key| key.pubkey.serialize());
+        if keys.len() < 2 || keys.len() > MAX_PARTICIPANTS {
+            bail!("musig needs 2 to {} participants", MAX_PARTICIPANTS);
+        }
+        Ok(MusigWallet {
+            keys,
+            descriptor: body.to_string(),
+        })
+    }
+
+    pub fn descriptor(&self) -> &str {
+        &self.descriptor
+    }
+
+    fn pubkeys(&self) -> Vec<PublicKey> {
+        self.keys.iter().map(|key| key.pubkey).collect()
+    }
+
+    /// Untweaked aggregate key (BIP-327 KeyAgg), the taproot internal key of the wallet
+    pub fn aggregate_key<C: Verification>(&self, secp: &Secp256k1<C>) -> Result<PublicKey> {
+        Ok(KeyAggContext::new(secp, &self.pubkeys())?.q)
+    }
+
+    /// Our participant key and the private key behind it
+    fn own_key<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        keys: &KeySource,
+    ) -> Result<(PublicKey, SecretKey)> {
+        self.keys
+            .iter()
+            .find_map(|key| {
+                let private_key = keys.private_key_at(secp, key.origin.as_ref())?;
+                (private_key.public_key(secp).inner == key.pubkey)
+                    .then_some((key.pubkey, private_key.inner))
+            })
+            .ok_or_else(|| anyhow!("musig participants do not include this device's key"))
+    }
+}
+
+/// Validate a musig descriptor against the device key and, once approved on the device, store it
+pub fn register_musig_wallet(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    descriptor: &str,
+    keys: &KeySource,
+) -> Result<MusigWallet> {
+    let secp = Secp256k1::new();
+    let wallet = MusigWallet::parse(descriptor)?;
+    let (own_pubkey, _) = wallet.own_key(&secp, keys)?;
+    wallet.aggregate_key(&secp)?;
+
+    display_musig_wallet(lcd, &wallet);
+    buttons.wait_for_press();
+    for (index, key) in wallet.keys.iter().enumerate() {
+        display_musig_key(lcd, index, wallet.keys.len(), key, key.pubkey == own_pubkey);
+        buttons.wait_for_press();
+    }
+    let summary = format!("{} of {} musig", wallet.keys.len(), wallet.keys.len());
+    lcd.write_lines(&["Register wallet", &summary, "Press OK to approve"])?;
+    if !buttons.confirm() {
+        bail!("Wallet rejected on device");
+    }
+
+    save_musig_descriptor(handle, wallet.descriptor())
+        .map_err(|err| anyhow!("Failed to save wallet descriptor: {}", err))?;
+    Ok(wallet)
+}
+
+/// Musig wallets previously accepted with `register_musig_wallet`
+pub fn registered_musig_wallets(handle: nvs_handle_t) -> Vec<MusigWallet> {
+    load_musig_descriptors(handle)
+        .iter()
+        .filter_map(|descriptor| MusigWallet::parse(descriptor).ok())
+        .collect()
+}
+
+/// Outputs paying back to a registered musig wallet.
+///
+/// An output naming a wallet's aggregate key as its internal key but paying to a different
+/// script would hand funds to whoever built it, so that is an error rather than a payment.
+fn musig_change<C: Verification>(
+    secp: &Secp256k1<C>,
+    psbt: &Psbt,
+    wallets: &[MusigWallet],
+) -> Result<Vec<usize>> {
+    let wallet_keys: Vec<XOnlyPublicKey> = wallets
+        .iter()
+        .filter_map(|wallet| wallet.aggregate_key(secp).ok())
+        .map(|key| key.x_only_public_key().0)
+        .collect();
+    let mut change = Vec::new();
+    for (index, output) in psbt.outputs.iter().enumerate() {
+        let Some(internal_key) = output.tap_internal_key else {
+            continue;
+        };
+        if !wallet_keys.contains(&internal_key) {
+            continue;
+        }
+        // Registered musig wallets are key-path only, so their outputs commit to no script tree
+        let script_pubkey = &psbt.unsigned_tx.output[index].script_pubkey;
+        if *script_pubkey != ScriptBuf::new_p2tr(secp, internal_key, None) {
+            bail!(
+                "output {} is not change of a registered musig wallet",
+                index
+            );
+        }
+        change.push(index);
+    }
+    Ok(change)
+}
+
+/// Aggregate key with the tweaks applied so far (BIP-327 KeyAgg Context)
+struct KeyAggContext {
+    keys: Vec<PublicKey>,
+    list_hash: [u8; 32],
+    q: PublicKey,
+    /// The accumulated `gacc` is -1 rather than 1
+    gacc_negated: bool,
+}
+
+impl KeyAggContext {
+    fn new<C: Verification>(secp: &Secp256k1<C>, keys: &[PublicKey]) -> Result<Self> {
+        let serialized: Vec<u8> = keys.iter().flat_map(|key| key.serialize()).collect();
+        let mut context = KeyAggContext {
+            keys: keys.to_vec(),
+            list_hash: tagged_hash("KeyAgg list", &[&serialized]),
+            q: keys[0],
+            gacc_negated: false,
+        };
+        let terms = keys
+            .iter()
+            .map(|key| Ok(Some(key.mul_tweak(secp, &context.coefficient(key))?)))
+            .collect::<Result<Vec<_>>>()?;
+        context.q = add_points(&terms).ok_or_else(|| anyhow!("aggregate key is infinity"))?;
+        Ok(context)
+    }
+
+    /// KeyAggCoeff: the second distinct key gets 1, which saves one multiplication
+    fn coefficient(&self, key: &PublicKey) -> Scalar {
+        let second = self.keys.iter().find(|other| **other != self.keys[0]);
+        if second == Some(key) {
+            return Scalar::ONE;
+        }
+        scalar_mod_n(tagged_hash(
+            "KeyAgg coefficient",
+            &[&self.list_hash, &key.serialize()],
+        ))
+    }
+
+    /// Apply an x-only tweak, as taproot does to turn the internal key into the output key
+    fn apply_x_only_tweak<C: Verification>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        tweak: &Scalar,
+    ) -> Result<()> {
+        if !has_even_y(&self.q) {
+            self.q = self.q.negate(secp);
+            self.gacc_negated = !self.gacc_negated;
+        }
+        self.q = self.q.add_exp_tweak(secp, tweak)?;
+        Ok(())
+    }
+}
+
+/// Secret half of a nonce pair; only ever kept in RAM and wiped when dropped
+struct SecNonce {
+    k1: SecretKey,
+    k2: SecretKey,
+    pubkey: PublicKey,
+}
+
+impl Drop for SecNonce {
+    fn drop(&mut self) {
+        self.k1.non_secure_erase();
+        self.k2.non_secure_erase();
+    }
+}
+
+/// BIP-327 NonceGen with fresh randomness, bound to our key, the aggregate key and the message
+fn nonce_gen<C: Signing>(
+    secp: &Secp256k1<C>,
+    secret_key: &SecretKey,
+    pubkey: &PublicKey,
+    aggregate_key: &XOnlyPublicKey,
+    message: &[u8; 32],
+) -> Result<(SecNonce, [u8; 66])> {
+    nonce_gen_from(
+        secp,
+        entropy::random_bytes(),
+        secret_key,
+        pubkey,
+        aggregate_key,
+        message,
+        &[],
+    )
+}
+
+/// NonceGen with the caller's `rand'` and `extra_in`; only the test vectors pass their own
+fn nonce_gen_from<C: Signing>(
+    secp: &Secp256k1<C>,
+    mut rand: [u8; 32],
+    secret_key: &SecretKey,
+    pubkey: &PublicKey,
+    aggregate_key: &XOnlyPublicKey,
+    message: &[u8; 32],
+    extra_in: &[u8],
+) -> Result<(SecNonce, [u8; 66])> {
+    // Mixing in the secret key keeps nonces unique even if the RNG repeats itself
+    let aux = tagged_hash("MuSig/aux", &[&rand]);
+    for (byte, (key_byte, aux_byte)) in rand
+        .iter_mut()
+        .zip(secret_key.secret_bytes().iter().zip(aux))
+    {
+        *byte = key_byte ^ aux_byte;
+    }
+
+    let mut nonces = Vec::with_capacity(2);
+    for i in 0..2u8 {
+        let hash = tagged_hash(
+            "MuSig/nonce",
+            &[
+                &rand,
+                &[33],
+                &pubkey.serialize(),
+                &[32],
+                &aggregate_key.serialize(),
+                &[1],
+                &(message.len() as u64).to_be_bytes(),
+                message,
+                &(extra_in.len() as u32).to_be_bytes(),
+                extra_in,
+                &[i],
+            ],
+        );
+        let k = SecretKey::from_slice(&scalar_mod_n(hash).to_be_bytes())
+            .map_err(|_| anyhow!("nonce is zero"))?;
+        nonces.push(k);
+    }
+    rand.fill(0);
+
+    let mut pubnonce = [0u8; 66];
+    pubnonce[..33].copy_from_slice(&PublicKey::from_secret_key(secp, &nonces[0]).serialize());
+    pubnonce[33..].copy_from_slice(&PublicKey::from_secret_key(secp, &nonces[1]).serialize());
+    let secnonce = SecNonce {
+        k1: nonces[0],
+        k2: nonces[1],
+        pubkey: *pubkey,
+    };
+    Ok((secnonce, pubnonce))
+}
+
+fn parse_pubnonce(pubnonce: &[u8]) -> Result<(PublicKey, PublicKey)> {
+    if pubnonce.len() != 66 {
+        bail!("public nonce must be 66 bytes");
+    }
+    Ok((
+        PublicKey::from_slice(&pubnonce[..33])?,
+        PublicKey::from_slice(&pubnonce[33..])?,
+    ))
+}
+
+/// BIP-327 NonceAgg; either half may be the point at infinity
+fn aggregate_nonces(pubnonces: &[Vec<u8>]) -> Result<[Option<PublicKey>; 2]> {
+    let parsed = pubnonces
+        .iter()
+        .map(|pubnonce| parse_pubnonce(pubnonce))
+        .collect::<Result<Vec<_>>>()?;
+    let first: Vec<_> = parsed.iter().map(|(r1, _)| Some(*r1)).collect();
+    let second: Vec<_> = parsed.iter().map(|(_, r2)| Some(*r2)).collect();
+    Ok([add_points(&first), add_points(&second)])
+}
+
+/// BIP-327 Sign, followed by PartialSigVerify on our own result
+fn partial_sign<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    secnonce: SecNonce,
+    secret_key: &SecretKey,
+    context: &KeyAggContext,
+    aggnonce: &[Option<PublicKey>; 2],
+    message: &[u8; 32],
+) -> Result<[u8; 32]> {
+    let pubkey = PublicKey::from_secret_key(secp, secret_key);
+    if pubkey != secnonce.pubkey {
+        bail!("nonce was generated for another key");
+    }
+    let q_bytes = context.q.x_only_public_key().0.serialize();
+    let b = scalar_mod_n(tagged_hash(
+        "MuSig/noncecoef",
+        &[
+            &point_bytes_ext(aggnonce[0]),
+            &point_bytes_ext(aggnonce[1]),
+            &q_bytes,
+            message,
+        ],
+    ));
+    let r2_b = aggnonce[1].map(|r2| r2.mul_tweak(secp, &b)).transpose()?;
+    let r = add_points(&[aggnonce[0], r2_b]).unwrap_or_else(|| generator(secp));
+    let r_bytes = r.x_only_public_key().0.serialize();
+    let e = scalar_mod_n(tagged_hash(
+        "BIP0340/challenge",
+        &[&r_bytes, &q_bytes, message],
+    ));
+
+    let (mut k1, mut k2) = (secnonce.k1, secnonce.k2);
+    if !has_even_y(&r) {
+        k1 = k1.negate();
+        k2 = k2.negate();
+    }
+    let a = context.coefficient(&pubkey);
+    // g * gacc is -1 exactly when one of them is
+    let negate_key = has_even_y(&context.q) == context.gacc_negated;
+    let d = if negate_key {
+        secret_key.negate()
+    } else {
+        *secret_key
+    };
+    let challenge_term = d.mul_tweak(&a)?.mul_tweak(&e)?;
+    let s = k1
+        .add_tweak(&Scalar::from(k2.mul_tweak(&b)?))?
+        .add_tweak(&Scalar::from(challenge_term))?;
+    k1.non_secure_erase();
+    k2.non_secure_erase();
+
+    // s*G must equal R1 + b*R2 (sign-adjusted) + e*a*g*gacc*P; a glitched result never leaves
+    let own_r = PublicKey::combine_keys(&[
+        &PublicKey::from_secret_key(secp, &secnonce.k1),
+        &PublicKey::from_secret_key(secp, &secnonce.k2).mul_tweak(secp, &b)?,
+    ])?;
+    let own_r = if has_even_y(&r) {
+        own_r
+    } else {
+        own_r.negate(secp)
+    };
+    let key_term = pubkey.mul_tweak(secp, &e)?.mul_tweak(secp, &a)?;
+    let key_term = if negate_key {
+        key_term.negate(secp)
+    } else {
+        key_term
+    };
+    let expected = add_points(&[Some(own_r), Some(key_term)]);
+    if expected != Some(PublicKey::from_secret_key(secp, &s)) {
+        bail!("partial signature failed verification");
+    }
+    Ok(s.secret_bytes())
+}
+
+fn participant_field_key(participant: &PublicKey, aggregate_key: &PublicKey) -> Vec<u8> {
+    let mut key = participant.serialize().to_vec();
+    key.extend_from_slice(&aggregate_key.serialize());
+    key
+}
+
+/// One of our key-path musig inputs, with everything signing it needs
+struct MusigInput {
+    index: usize,
+    wallet: MusigWallet,
+    aggregate_key: PublicKey,
+    context: KeyAggContext,
+    message: [u8; 32],
+}
+
+/// Taproot key-path inputs spending from a registered musig wallet
+fn musig_inputs<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    psbt: &Psbt,
+    wallets: &[MusigWallet],
+) -> Result<Vec<MusigInput>> {
+    let prevouts = (0..psbt.inputs.len())
+        .map(|index| spent_output(psbt, index))
+        .collect::<Option<Vec<TxOut>>>()
+        .ok_or_else(|| anyhow!("musig signing needs every previous output"))?;
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+
+    let mut inputs = Vec::new();
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        let Some(internal_key) = input.tap_internal_key else {
+            continue;
+        };
+        let found = wallets.iter().find_map(|wallet| {
+            let aggregate_key = wallet.aggregate_key(secp).ok()?;
+            (aggregate_key.x_only_public_key().0 == internal_key).then_some((wallet, aggregate_key))
+        });
+        let Some((wallet, aggregate_key)) = found else {
+            continue;
+        };
+        let merkle_root = input.tap_merkle_root;
+        if prevouts[index].script_pubkey != ScriptBuf::new_p2tr(secp, internal_key, merkle_root) {
+            bail!(
+                "input {}: output key does not match the musig wallet",
+                index
+            );
+        }
+        let sighash_type = input
+            .taproot_hash_ty()
+            .map_err(|_| anyhow!("input {}: invalid sighash type", index))?;
+        if !matches!(sighash_type, TapSighashType::Default | TapSighashType::All) {
+            bail!("input {}: musig only signs ALL and DEFAULT", index);
+        }
+        let message = cache
+            .taproot_key_spend_signature_hash(index, &Prevouts::All(&prevouts), sighash_type)?
+            .to_byte_array();
+
+        let mut context = KeyAggContext::new(secp, &wallet.pubkeys())?;
+        let tweak = TapTweakHash::from_key_and_tweak(internal_key, merkle_root).to_scalar();
+        context.apply_x_only_tweak(secp, &tweak)?;
+        inputs.push(MusigInput {
+            index,
+            wallet: wallet.clone(),
+            aggregate_key,
+            context,
+            message,
+        });
+    }
+    Ok(inputs)
+}
+
+/// Nonces of one signing session; never written to NVS
+struct Session {
+    txid: Txid,
+    nonces: BTreeMap<usize, SecNonce>,
+}
+
+/// MuSig2 cosigner state: secret nonces per session, held in RAM only.
+///
+/// A session's nonces are consumed by the one signing round that uses them, and a session ID
+/// can never be started twice, so no secret nonce ever signs two messages.
+#[derive(Default)]
+pub struct MusigSigner {
+    sessions: HashMap<[u8; 32], Session>,
+    used: HashSet<[u8; 32]>,
+}
+
+impl MusigSigner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// First round: add our public nonce to every musig input we cosign.
+    ///
+    /// Returns the inputs that got a nonce.
+    pub fn add_nonces(
+        &mut self,
+        session_id: [u8; 32],
+        psbt: &mut Psbt,
+        keys: &KeySource,
+        wallets: &[MusigWallet],
+    ) -> Result<Vec<usize>> {
+        pin::ensure_unlocked()?;
+        if !self.used.insert(session_id) {
+            bail!("musig session {} was already used", hex::encode(session_id));
+        }
+        let secp = Secp256k1::new();
+        let mut session = Session {
+            txid: psbt.unsigned_tx.compute_txid(),
+            nonces: BTreeMap::new(),
+        };
+
+        for musig_input in musig_inputs(&secp, psbt, wallets)? {
+            let (pubkey, secret_key) = musig_input.wallet.own_key(&secp, keys)?;
+            let output_key = musig_input.context.q.x_only_public_key().0;
+            let (secnonce, pubnonce) = nonce_gen(
+                &secp,
+                &secret_key,
+                &pubkey,
+                &output_key,
+                &musig_input.message,
+            )?;
+
+            let input = &mut psbt.inputs[musig_input.index];
+            let participants: Vec<u8> = musig_input
+                .wallet
+                .pubkeys()
+                .iter()
+                .flat_map(|key| key.serialize())
+                .collect();
+            input.unknown.insert(
+                raw::Key {
+                    type_value: PSBT_IN_MUSIG2_PARTICIPANT_PUBKEYS,
+                    key: musig_input.aggregate_key.serialize().to_vec(),
+                },
+                participants,
+            );
+            input.unknown.insert(
+                raw::Key {
+                    type_value: PSBT_IN_MUSIG2_PUB_NONCE,
+                    key: participant_field_key(&pubkey, &musig_input.aggregate_key),
+                },
+                pubnonce.to_vec(),
+            );
+            session.nonces.insert(musig_input.index, secnonce);
+        }
+
+        let indices = session.nonces.keys().copied().collect();
+        self.sessions.insert(session_id, session);
+        Ok(indices)
+    }
+
+    /// Second round: once every participant's nonce is in the PSBT and the spend passes the fee
+    /// rules and review in `options`, the same ones `sign_psbt_with` applies, add our partial
+    /// signatures.
+    ///
+    /// The session ends here whether or not signing succeeds. Every input is signed before any
+    /// signature is written, so on error the PSBT is left untouched.
+    pub fn add_partial_sigs(
+        &mut self,
+        session_id: [u8; 32],
+        psbt: &mut Psbt,
+        keys: &KeySource,
+        wallets: &[MusigWallet],
+        options: &SignOptions,
+    ) -> Result<Vec<usize>> {
+        pin::ensure_unlocked()?;
+        let mut session = self
+            .sessions
+            .remove(&session_id)
+            .ok_or_else(|| anyhow!("no open musig session {}", hex::encode(session_id)))?;
+        if psbt.unsigned_tx.compute_txid() != session.txid {
+            bail!("PSBT is not the one the session nonces were made for");
+        }
+        let secp = Secp256k1::new();
+        let musig_inputs = musig_inputs(&secp, psbt, wallets)?;
+
+        let change = musig_change(&secp, psbt, wallets)?;
+        // Taproot sighashes commit to every spent amount, so the fee shown is the fee signed
+        approve_spend(psbt, keys, &change, true, options)
+            .map_err(|reason| anyhow!("{}", reason))?;
+
+        let mut partial_sigs = Vec::new();
+        for musig_input in musig_inputs {
+            let Some(secnonce) = session.nonces.remove(&musig_input.index) else {
+                continue;
+            };
+            let (pubkey, secret_key) = musig_input.wallet.own_key(&secp, keys)?;
+            let input = &psbt.inputs[musig_input.index];
+            let pubnonces = musig_input
+                .wallet
+                .pubkeys()
+                .iter()
+                .map(|participant| {
+                    let key = raw::Key {
+                        type_value: PSBT_IN_MUSIG2_PUB_NONCE,
+                        key: participant_field_key(participant, &musig_input.aggregate_key),
+                    };
+                    input.unknown.get(&key).cloned().ok_or_else(|| {
+                        anyhow!(
+                            "input {}: missing nonce from {}",
+                            musig_input.index,
+                            participant
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let aggnonce = aggregate_nonces(&pubnonces)?;
+
+            let partial_sig = partial_sign(
+                &secp,
+                secnonce,
+                &secret_key,
+                &musig_input.context,
+                &aggnonce,
+                &musig_input.message,
+            )
+            .map_err(|err| anyhow!("input {}: {}", musig_input.index, err))?;
+            let key = raw::Key {
+                type_value: PSBT_IN_MUSIG2_PARTIAL_SIG,
+                key: participant_field_key(&pubkey, &musig_input.aggregate_key),
+            };
+            partial_sigs.push((musig_input.index, key, partial_sig));
+        }
+
+        let mut signed = Vec::new();
+        for (index, key, partial_sig) in partial_sigs {
+            psbt.inputs[index].unknown.insert(key, partial_sig.to_vec());
+            signed.push(index);
+        }
+        Ok(signed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvs::memory::{load_wallet_descriptors, open_nvs_partition};
+    use crate::ui::{approving_review, scripted_review};
+
+    /// Keys of the BIP-327 KeyAgg vectors
+    const KEY_AGG_KEYS: [&str; 3] = [
+        "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+        "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+        "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+    ];
+
+    fn pubkey(hex_key: &str) -> PublicKey {
+        PublicKey::from_str(hex_key).unwrap()
+    }
+
+    fn secret_key(hex_key: &str) -> SecretKey {
+        SecretKey::from_str(hex_key).unwrap()
+    }
+
+    fn bytes32(hex_bytes: &str) -> [u8; 32] {
+        hex::decode(hex_bytes).unwrap().try_into().unwrap()
+    }
+
+    fn x_only_hex(point: &PublicKey) -> String {
+        hex::encode_upper(point.x_only_public_key().0.serialize())
+    }
+
+    #[test]
+    fn key_agg_vectors() {
+        let secp = Secp256k1::verification_only();
+        let cases: [(&[usize], &str); 4] = [
+            (
+                &[0, 1, 2],
+                "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C",
+            ),
+            (
+                &[2, 1, 0],
+                "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B",
+            ),
+            (
+                &[0, 0, 0],
+                "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935",
+            ),
+            (
+                &[0, 0, 1, 1],
+                "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E",
+            ),
+        ];
+        for (indices, expected) in cases {
+            let keys: Vec<PublicKey> = indices.iter().map(|&i| pubkey(KEY_AGG_KEYS[i])).collect();
+            let context = KeyAggContext::new(&secp, &keys).unwrap();
+            assert_eq!(x_only_hex(&context.q), expected, "keys {:?}", indices);
+        }
+    }
+
+    #[test]
+    fn descriptor_keys_are_sorted() {
+        let secp = Secp256k1::verification_only();
+        let given = MusigWallet::parse(&format!("tr(musig({}))", KEY_AGG_KEYS.join(","))).unwrap();
+        let mut sorted = KEY_AGG_KEYS;
+        sorted.sort();
+        let keys: Vec<PublicKey> = sorted.iter().map(|key| pubkey(key)).collect();
+        assert_eq!(given.pubkeys(), keys);
+
+        let reversed: Vec<&str> = KEY_AGG_KEYS.iter().rev().copied().collect();
+        let reversed = MusigWallet::parse(&format!("tr(musig({}))", reversed.join(","))).unwrap();
+        assert_eq!(
+            given.aggregate_key(&secp).unwrap(),
+            reversed.aggregate_key(&secp).unwrap()
+        );
+        assert_eq!(
+            given.aggregate_key(&secp).unwrap(),
+            KeyAggContext::new(&secp, &keys).unwrap().q
+        );
+    }
+
+    #[test]
+    fn nonce_gen_vector() {
+        let secp = Secp256k1::new();
+        let signer_key =
+            secret_key("0202020202020202020202020202020202020202020202020202020202020202");
+        let own_key = pubkey("024D4B6CD1361032CA9BD2AEB9D900AA4D45D9EAD80AC9423374C451A7254D0766");
+        let aggregate_key = XOnlyPublicKey::from_slice(&[7; 32]).unwrap();
+        let (secnonce, pubnonce) = nonce_gen_from(
+            &secp,
+            [0x0f; 32],
+            &signer_key,
+            &own_key,
+            &aggregate_key,
+            &[1; 32],
+            &[8; 32],
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode_upper(secnonce.k1.secret_bytes()),
+            "B114E502BEAA4E301DD08A50264172C84E41650E6CB726B410C0694D59EFFB64"
+        );
+        assert_eq!(
+            hex::encode_upper(secnonce.k2.secret_bytes()),
+            "95B5CAF28D045B973D63E3C99A44B807BDE375FD6CB39E46DC4A511708D0E9D2"
+        );
+        assert_eq!(secnonce.pubkey, own_key);
+        let (r1, r2) = parse_pubnonce(&pubnonce).unwrap();
+        assert_eq!(r1, PublicKey::from_secret_key(&secp, &secnonce.k1));
+        assert_eq!(r2, PublicKey::from_secret_key(&secp, &secnonce.k2));
+    }
+
+    #[test]
+    fn nonce_agg_vector() {
+        let pubnonces = [
+            "020151C80F435648DF67A22B749CD798CE54E0321D034B92B709B567D60A42E66603BA47FBC1834437B3212E89A84D8425E7BF12E0245D98262268EBDCB385D50641",
+            "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A60248C264CDD57D3C24D79990B0F865674EB62A0F9018277A95011B41BFC193B833",
+        ]
+        .map(|pubnonce| hex::decode(pubnonce).unwrap());
+        let [r1, r2] = aggregate_nonces(&pubnonces).unwrap();
+        assert_eq!(
+            format!("{}{}", r1.unwrap(), r2.unwrap()).to_uppercase(),
+            "035FE1873B4F2967F52FEA4A06AD5A8ECCBE9D0FD73068012C894E2E87CCB5804B024725377345BDE0E9C33AF3C43C0A29A9249F2F2956FA8CFEB55C8573D0262DC8"
+        );
+    }
+
+    /// BIP-327 Sign vectors: one signer, three participants in different orders
+    #[test]
+    fn sign_vectors() {
+        let secp = Secp256k1::new();
+        let signer_key =
+            secret_key("7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671");
+        let keys = [
+            "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661",
+        ]
+        .map(pubkey);
+        let aggnonce = hex::decode("028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9").unwrap();
+        let (r1, r2) = parse_pubnonce(&aggnonce).unwrap();
+        let message = bytes32("F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF");
+
+        let cases: [(&[usize], &str); 3] = [
+            (
+                &[0, 1, 2],
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+            ),
+            (
+                &[1, 0, 2],
+                "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52",
+            ),
+            (
+                &[1, 2, 0],
+                "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900",
+            ),
+        ];
+        for (indices, expected) in cases {
+            let participants: Vec<PublicKey> = indices.iter().map(|&i| keys[i]).collect();
+            let context = KeyAggContext::new(&secp, &participants).unwrap();
+            let secnonce = SecNonce {
+                k1: secret_key("508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61"),
+                k2: secret_key("FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7"),
+                pubkey: keys[0],
+            };
+            let partial_sig = partial_sign(
+                &secp,
+                secnonce,
+                &signer_key,
+                &context,
+                &[Some(r1), Some(r2)],
+                &message,
+            )
+            .unwrap();
+            assert_eq!(
+                hex::encode_upper(partial_sig),
+                expected,
+                "keys {:?}",
+                indices
+            );
+        }
+    }
+
+    /// A two-party wallet of ours with a cosigner, and a PSBT spending two of its outputs
+    fn musig_psbt(secp: &Secp256k1<bitcoin::secp256k1::All>) -> (KeySource, MusigWallet, Psbt) {
+        let own = bitcoin::PrivateKey::new(
+            SecretKey::from_slice(&[3; 32]).unwrap(),
+            bitcoin::NetworkKind::Main,
+        );
+        let cosigner = PublicKey::from_secret_key(secp, &SecretKey::from_slice(&[5; 32]).unwrap());
+        let descriptor = format!("tr(musig({},{}))", own.public_key(secp).inner, cosigner);
+        let wallet = MusigWallet::parse(&descriptor).unwrap();
+        let internal_key = wallet.aggregate_key(secp).unwrap().x_only_public_key().0;
+
+        let utxo = TxOut {
+            value: bitcoin::Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new_p2tr(secp, internal_key, None),
+        };
+        let tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default(), bitcoin::TxIn::default()],
+            output: vec![TxOut {
+                value: bitcoin::Amount::from_sat(19_000),
+                script_pubkey: utxo.script_pubkey.clone(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        for input in &mut psbt.inputs {
+            input.witness_utxo = Some(utxo.clone());
+            input.tap_internal_key = Some(internal_key);
+        }
+        (KeySource::Single(own), wallet, psbt)
+    }
+
+    /// Copy our own nonce in as the cosigner's on `inputs`, enough for the signing round to run
+    fn add_cosigner_nonces(
+        secp: &Secp256k1<bitcoin::secp256k1::All>,
+        psbt: &mut Psbt,
+        inputs: &[usize],
+    ) {
+        let own = PublicKey::from_secret_key(secp, &SecretKey::from_slice(&[3; 32]).unwrap());
+        let cosigner = PublicKey::from_secret_key(secp, &SecretKey::from_slice(&[5; 32]).unwrap());
+        let mut keys = [own, cosigner];
+        keys.sort_by_key(|key| key.serialize());
+        let aggregate_key = KeyAggContext::new(secp, &keys).unwrap().q;
+        for &index in inputs {
+            let nonce_key = raw::Key {
+                type_value: PSBT_IN_MUSIG2_PUB_NONCE,
+                key: participant_field_key(&own, &aggregate_key),
+            };
+            let nonce = psbt.inputs[index].unknown[&nonce_key].clone();
+            psbt.inputs[index].unknown.insert(
+                raw::Key {
+                    type_value: PSBT_IN_MUSIG2_PUB_NONCE,
+                    key: participant_field_key(&cosigner, &aggregate_key),
+                },
+                nonce,
+            );
+        }
+    }
+
+    #[test]
+    fn failed_round_leaves_psbt_untouched() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let (keys, wallet, mut psbt) = musig_psbt(&secp);
+        let wallets = [wallet];
+
+        let mut signer = MusigSigner::new();
+        assert_eq!(
+            signer
+                .add_nonces([1; 32], &mut psbt, &keys, &wallets)
+                .unwrap(),
+            vec![0, 1]
+        );
+        // The cosigner's nonce only reaches the first input
+        add_cosigner_nonces(&secp, &mut psbt, &[0]);
+
+        let unchanged = psbt.clone();
+        let (lcd, buttons) = approving_review();
+        let options = SignOptions {
+            review: Some((&lcd, &buttons)),
+            ..SignOptions::default()
+        };
+        assert!(signer
+            .add_partial_sigs([1; 32], &mut psbt, &keys, &wallets, &options)
+            .is_err());
+        assert_eq!(psbt, unchanged);
+    }
+
+    #[test]
+    fn signs_only_approved_spends() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let (keys, wallet, mut psbt) = musig_psbt(&secp);
+        let wallets = [wallet];
+        let mut signer = MusigSigner::new();
+        signer
+            .add_nonces([2; 32], &mut psbt, &keys, &wallets)
+            .unwrap();
+        add_cosigner_nonces(&secp, &mut psbt, &[0, 1]);
+
+        // Output page, fee page, then the final OK is refused
+        let unchanged = psbt.clone();
+        let (lcd, buttons) = scripted_review(&[35, 35, 0]);
+        let options = SignOptions {
+            review: Some((&lcd, &buttons)),
+            ..SignOptions::default()
+        };
+        let err = signer
+            .add_partial_sigs([2; 32], &mut psbt, &keys, &wallets, &options)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "transaction not approved on device");
+        assert_eq!(psbt, unchanged);
+        assert!(lcd.screens()[0].starts_with("Output 1/1"));
+        // The rejected session is gone, its nonces with it
+        let (lcd, buttons) = approving_review();
+        let options = SignOptions {
+            review: Some((&lcd, &buttons)),
+            ..SignOptions::default()
+        };
+        assert!(signer
+            .add_partial_sigs([2; 32], &mut psbt, &keys, &wallets, &options)
+            .is_err());
+
+        let mut signer = MusigSigner::new();
+        let mut psbt = unchanged;
+        signer
+            .add_nonces([3; 32], &mut psbt, &keys, &wallets)
+            .unwrap();
+        add_cosigner_nonces(&secp, &mut psbt, &[0, 1]);
+        assert_eq!(
+            signer
+                .add_partial_sigs([3; 32], &mut psbt, &keys, &wallets, &options)
+                .unwrap(),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn checks_musig_change() {
+        let secp = Secp256k1::new();
+        let (_, wallet, mut psbt) = musig_psbt(&secp);
+        let wallets = [wallet];
+        let internal_key = psbt.inputs[0].tap_internal_key.unwrap();
+        assert_eq!(
+            musig_change(&secp, &psbt, &wallets).unwrap(),
+            Vec::<usize>::new()
+        );
+
+        psbt.outputs[0].tap_internal_key = Some(internal_key);
+        assert_eq!(musig_change(&secp, &psbt, &wallets).unwrap(), vec![0]);
+
+        // The wallet's key behind a script tree the wallet does not have
+        let merkle_root = Some(bitcoin::TapNodeHash::from_byte_array([7; 32]));
+        psbt.unsigned_tx.output[0].script_pubkey =
+            ScriptBuf::new_p2tr(&secp, internal_key, merkle_root);
+        assert!(musig_change(&secp, &psbt, &wallets).is_err());
+    }
+
+    #[test]
+    fn registration_needs_approval_and_keeps_its_own_slots() {
+        let secp = Secp256k1::new();
+        let (keys, wallet, _) = musig_psbt(&secp);
+        let handle = open_nvs_partition().unwrap();
+
+        // Wallet page, both keys, then the final OK is refused
+        let (lcd, buttons) = scripted_review(&[35, 35, 35, 0]);
+        assert!(register_musig_wallet(&lcd, &buttons, handle, wallet.descriptor(), &keys).is_err());
+        assert!(registered_musig_wallets(handle).is_empty());
+        let ours = lcd
+            .screens()
+            .iter()
+            .filter(|screen| screen.contains("This device"))
+            .count();
+        assert_eq!(ours, 1);
+
+        let (lcd, buttons) = scripted_review(&[35, 35, 35, 35]);
+        register_musig_wallet(&lcd, &buttons, handle, wallet.descriptor(), &keys).unwrap();
+        assert_eq!(registered_musig_wallets(handle), vec![wallet]);
+        assert!(load_wallet_descriptors(handle).is_empty());
+    }
+}
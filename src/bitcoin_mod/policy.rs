@@ -0,0 +1,157 @@
+use anyhow::{anyhow, bail, Result};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::Secp256k1;
+
+use crate::bitcoin_mod::signature::KeySource;
+use crate::bitcoin_mod::wallet::{review_wallet_keys, MultisigWallet};
+use crate::ui::display::LcdController;
+use crate::ui::input::Buttons;
+use crate::ui::screens::display_wallet_policy;
+
+/// Longest wallet name accepted, one line of the display
+const MAX_NAME_LEN: usize = 24;
+
+/// Domain separation for the key that authenticates registered policies
+const POLICY_HMAC_TAG: &[u8] = b"BIP388 wallet policy";
+
+/// A BIP-388 wallet policy: a descriptor template with `@i` placeholders plus its key list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletPolicy {
+    pub name: String,
+    pub template: String,
+    pub keys: Vec<String>,
+}
+
+impl WalletPolicy {
+    pub fn new(name: &str, template: &str, keys: Vec<String>) -> Result<Self> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN || !name.is_ascii() {
+            bail!("wallet name must be 1 to {} ASCII characters", MAX_NAME_LEN);
+        }
+        let policy = WalletPolicy {
+            name: name.to_string(),
+            template: template.to_string(),
+            keys,
+        };
+
+        let used = policy.placeholders()?;
+        for index in 0..policy.keys.len() {
+            if !used.contains(&index) {
+                bail!("key @{} is never used", index);
+            }
+        }
+        for (index, key) in policy.keys.iter().enumerate() {
+            if policy.keys[..index].contains(key) {
+                bail!("key @{} appears twice", index);
+            }
+        }
+        // Only policies the signer can actually spend from may be registered
+        policy.wallet()?;
+        Ok(policy)
+    }
+
+    /// Indices of every `@i` placeholder in the template
+    fn placeholders(&self) -> Result<Vec<usize>> {
+        let mut indices = Vec::new();
+        let mut rest = self.template.as_str();
+        while let Some(position) = rest.find('@') {
+            rest = &rest[position + 1..];
+            let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            let index: usize = rest[..digits]
+                .parse()
+                .map_err(|_| anyhow!("placeholder without key index"))?;
+            if index >= self.keys.len() {
+                bail!("placeholder @{} has no key", index);
+            }
+            indices.push(index);
+            rest = &rest[digits..];
+        }
+        Ok(indices)
+    }
+
+    /// The template with every placeholder replaced by its key
+    pub fn descriptor(&self) -> String {
+        let mut descriptor = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(position) = rest.find('@') {
+            descriptor.push_str(&rest[..position]);
+            rest = &rest[position + 1..];
+            let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            match rest[..digits]
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| self.keys.get(index))
+            {
+                Some(key) => descriptor.push_str(key),
+                None => descriptor.push('@'),
+            }
+            rest = &rest[digits..];
+        }
+        descriptor.push_str(rest);
+        descriptor
+    }
+
+    pub fn wallet(&self) -> Result<MultisigWallet> {
+        MultisigWallet::parse(&self.descriptor())
+    }
+
+    /// Commitment to the whole policy; every field is length prefixed so none can bleed into another
+    pub fn id(&self) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        for field in [&self.name, &self.template] {
+            engine.input(&(field.len() as u32).to_le_bytes());
+            engine.input(field.as_bytes());
+        }
+        engine.input(&(self.keys.len() as u32).to_le_bytes());
+        for key in &self.keys {
+            engine.input(&(key.len() as u32).to_le_bytes());
+            engine.input(key.as_bytes());
+        }
+        sha256::Hash::from_engine(engine)
+    }
+}
+
+/// HMAC over the policy id, keyed from the device secret
+pub fn policy_hmac(policy: &WalletPolicy, keys: &KeySource) -> [u8; 32] {
+    let mut key_engine = HmacEngine::<sha256::Hash>::new(POLICY_HMAC_TAG);
+    key_engine.input(&keys.secret_bytes());
+    let hmac_key = Hmac::<sha256::Hash>::from_engine(key_engine);
+
+    let mut engine = HmacEngine::<sha256::Hash>::new(&hmac_key.to_byte_array());
+    engine.input(policy.id().as_ref());
+    Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+}
+
+/// Check a policy HMAC returned by `register_policy` earlier
+pub fn verify_policy_hmac(policy: &WalletPolicy, hmac: &[u8; 32], keys: &KeySource) -> bool {
+    let expected = policy_hmac(policy, keys);
+    // Compare every byte so the timing does not leak how much of the HMAC was right
+    expected
+        .iter()
+        .zip(hmac.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Show a policy to the user and, once approved, return the HMAC the host keeps for later requests
+pub fn register_policy(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    policy: &WalletPolicy,
+    keys: &KeySource,
+) -> Result<[u8; 32]> {
+    let secp = Secp256k1::new();
+    let wallet = policy.wallet()?;
+    wallet.check_own_key(&secp, keys)?;
+    let own_fingerprint = keys.fingerprint(&secp);
+
+    display_wallet_policy(lcd, policy, &wallet);
+    buttons.wait_for_press();
+    review_wallet_keys(lcd, buttons, &wallet, own_fingerprint);
+
+    lcd.write_lines(&["Register wallet", &policy.name, "Press OK to approve"])?;
+    if !buttons.confirm() {
+        bail!("Wallet policy rejected on device");
+    }
+    Ok(policy_hmac(policy, keys))
+}
@@ -0,0 +1,695 @@
+use anyhow::{anyhow, bail, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::encode::{deserialize_partial, serialize, VarInt};
+use bitcoin::hashes::Hash;
+use bitcoin::sighash::TapSighashType;
+use bitcoin::transaction::Version;
+use bitcoin::{Amount, OutPoint, Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid};
+
+const MAGIC: &[u8] = b"psbt\xff";
+
+const GLOBAL_UNSIGNED_TX: u64 = 0x00;
+const GLOBAL_TX_VERSION: u64 = 0x02;
+const GLOBAL_FALLBACK_LOCKTIME: u64 = 0x03;
+const GLOBAL_INPUT_COUNT: u64 = 0x04;
+const GLOBAL_OUTPUT_COUNT: u64 = 0x05;
+const GLOBAL_TX_MODIFIABLE: u64 = 0x06;
+const GLOBAL_VERSION: u64 = 0xfb;
+
+const IN_PREVIOUS_TXID: u64 = 0x0e;
+const IN_OUTPUT_INDEX: u64 = 0x0f;
+const IN_SEQUENCE: u64 = 0x10;
+const IN_REQUIRED_TIME_LOCKTIME: u64 = 0x11;
+const IN_REQUIRED_HEIGHT_LOCKTIME: u64 = 0x12;
+
+const OUT_AMOUNT: u64 = 0x03;
+const OUT_SCRIPT: u64 = 0x04;
+
+/// Bits of PSBT_GLOBAL_TX_MODIFIABLE
+pub const INPUTS_MODIFIABLE: u8 = 0x01;
+pub const OUTPUTS_MODIFIABLE: u8 = 0x02;
+pub const HAS_SIGHASH_SINGLE: u8 = 0x04;
+
+/// One key-value pair of a PSBT map; the key still starts with its type
+type Record = (Vec<u8>, Vec<u8>);
+
+/// Fields that only exist in PSBTv2, kept aside while the PSBT is handled in the v0 model
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PsbtV2Fields {
+    pub tx_modifiable: u8,
+    global: Vec<Record>,
+    inputs: Vec<Vec<Record>>,
+    outputs: Vec<Vec<Record>>,
+}
+
+/// Serialization a PSBT arrived in, so the signed result can go back the same way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PsbtFormat {
+    V0,
+    V2(PsbtV2Fields),
+}
+
+fn key_type(key: &[u8]) -> Result<u64> {
+    let (VarInt(key_type), _) = deserialize_partial::<VarInt>(key)?;
+    Ok(key_type)
+}
+
+fn read_var_int(bytes: &[u8], position: &mut usize) -> Result<u64> {
+    let rest = bytes
+        .get(*position..)
+        .ok_or_else(|| anyhow!("unexpected end of PSBT"))?;
+    let (VarInt(value), consumed) = deserialize_partial::<VarInt>(rest)?;
+    *position += consumed;
+    Ok(value)
+}
+
+fn read_bytes<'a>(bytes: &'a [u8], position: &mut usize) -> Result<&'a [u8]> {
+    let len = read_var_int(bytes, position)? as usize;
+    let end = position
+        .checked_add(len)
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| anyhow!("unexpected end of PSBT"))?;
+    let data = &bytes[*position..end];
+    *position = end;
+    Ok(data)
+}
+
+fn read_map(bytes: &[u8], position: &mut usize) -> Result<Vec<Record>> {
+    let mut records: Vec<Record> = Vec::new();
+    loop {
+        let key = read_bytes(bytes, position)?;
+        if key.is_empty() {
+            return Ok(records);
+        }
+        let value = read_bytes(bytes, position)?;
+        if records.iter().any(|(existing, _)| existing == key) {
+            bail!("duplicate PSBT key {}", hex::encode(key));
+        }
+        records.push((key.to_vec(), value.to_vec()));
+    }
+}
+
+fn write_map(out: &mut Vec<u8>, records: &mut [Record]) {
+    records.sort();
+    for (key, value) in records.iter() {
+        out.extend(serialize(&VarInt(key.len() as u64)));
+        out.extend(key);
+        out.extend(serialize(&VarInt(value.len() as u64)));
+        out.extend(value);
+    }
+    out.push(0x00);
+}
+
+/// Split off the records whose type is in `types`
+fn take_records(records: &mut Vec<Record>, types: &[u64]) -> Vec<Record> {
+    let (taken, kept) = records
+        .drain(..)
+        .partition(|(key, _)| key_type(key).is_ok_and(|key_type| types.contains(&key_type)));
+    *records = kept;
+    taken
+}
+
+fn find_value(records: &[Record], wanted: u64) -> Option<&[u8]> {
+    records
+        .iter()
+        .find(|(key, _)| key.len() == 1 && key_type(key).ok() == Some(wanted))
+        .map(|(_, value)| value.as_slice())
+}
+
+fn read_u32(records: &[Record], wanted: u64) -> Result<Option<u32>> {
+    find_value(records, wanted)
+        .map(|value| {
+            let bytes: [u8; 4] = value
+                .try_into()
+                .map_err(|_| anyhow!("PSBT field {:#04x} is not 4 bytes", wanted))?;
+            Ok(u32::from_le_bytes(bytes))
+        })
+        .transpose()
+}
+
+fn read_count(records: &[Record], wanted: u64) -> Result<usize> {
+    let value = find_value(records, wanted)
+        .ok_or_else(|| anyhow!("PSBTv2 is missing field {:#04x}", wanted))?;
+    let (VarInt(count), _) = deserialize_partial::<VarInt>(value)?;
+    Ok(count as usize)
+}
+
+/// Locktime of the unsigned transaction, following the BIP-370 rules
+fn compute_locktime(global: &[Record], inputs: &[Vec<Record>]) -> Result<LockTime> {
+    let mut times = Vec::new();
+    let mut heights = Vec::new();
+    let mut all_support_time = true;
+    let mut all_support_height = true;
+    for input in inputs {
+        let time = read_u32(input, IN_REQUIRED_TIME_LOCKTIME)?;
+        let height = read_u32(input, IN_REQUIRED_HEIGHT_LOCKTIME)?;
+        // A time below the threshold would read as a height, and a height above it as a time
+        if let Some(time) = time {
+            LockTime::from_time(time)?;
+        }
+        if let Some(height) = height {
+            LockTime::from_height(height)?;
+        }
+        if time.is_none() && height.is_none() {
+            continue;
+        }
+        all_support_time &= time.is_some();
+        all_support_height &= height.is_some();
+        times.extend(time);
+        heights.extend(height);
+    }
+
+    if times.is_empty() && heights.is_empty() {
+        let fallback = read_u32(global, GLOBAL_FALLBACK_LOCKTIME)?.unwrap_or(0);
+        return Ok(LockTime::from_consensus(fallback));
+    }
+    // Height wins when every input with a locktime accepts both
+    if all_support_height {
+        let height = heights.into_iter().max().unwrap_or(0);
+        Ok(LockTime::from_height(height)?)
+    } else if all_support_time {
+        let time = times.into_iter().max().unwrap_or(0);
+        Ok(LockTime::from_time(time)?)
+    } else {
+        bail!("inputs require incompatible locktime types");
+    }
+}
+
+fn previous_output(input: &[Record]) -> Result<OutPoint> {
+    let txid = find_value(input, IN_PREVIOUS_TXID)
+        .ok_or_else(|| anyhow!("PSBTv2 input without previous txid"))?;
+    let txid: [u8; 32] = txid
+        .try_into()
+        .map_err(|_| anyhow!("previous txid is not 32 bytes"))?;
+    let vout = read_u32(input, IN_OUTPUT_INDEX)?
+        .ok_or_else(|| anyhow!("PSBTv2 input without output index"))?;
+    Ok(OutPoint::new(Txid::from_byte_array(txid), vout))
+}
+
+fn output(output: &[Record]) -> Result<TxOut> {
+    let amount =
+        find_value(output, OUT_AMOUNT).ok_or_else(|| anyhow!("PSBTv2 output without amount"))?;
+    let amount: [u8; 8] = amount
+        .try_into()
+        .map_err(|_| anyhow!("output amount is not 8 bytes"))?;
+    let amount = i64::from_le_bytes(amount);
+    if amount < 0 {
+        bail!("negative output amount");
+    }
+    let script =
+        find_value(output, OUT_SCRIPT).ok_or_else(|| anyhow!("PSBTv2 output without script"))?;
+    Ok(TxOut {
+        value: Amount::from_sat(amount as u64),
+        script_pubkey: ScriptBuf::from_bytes(script.to_vec()),
+    })
+}
+
+/// Decode a PSBTv2 into the v0 model, keeping the v2-only fields for re-encoding
+fn decode_v2(
+    bytes: &[u8],
+    mut global: Vec<Record>,
+    mut position: usize,
+) -> Result<(Psbt, PsbtV2Fields)> {
+    if find_value(&global, GLOBAL_UNSIGNED_TX).is_some() {
+        bail!("PSBTv2 must not carry an unsigned transaction");
+    }
+    let input_count = read_count(&global, GLOBAL_INPUT_COUNT)?;
+    let output_count = read_count(&global, GLOBAL_OUTPUT_COUNT)?;
+    let mut inputs = (0..input_count)
+        .map(|_| read_map(bytes, &mut position))
+        .collect::<Result<Vec<_>>>()?;
+    let mut outputs = (0..output_count)
+        .map(|_| read_map(bytes, &mut position))
+        .collect::<Result<Vec<_>>>()?;
+    if position != bytes.len() {
+        bail!("trailing bytes after PSBT");
+    }
+
+    let version = read_u32(&global, GLOBAL_TX_VERSION)?
+        .ok_or_else(|| anyhow!("PSBTv2 is missing the transaction version"))?;
+    let tx = Transaction {
+        version: Version(version as i32),
+        lock_time: compute_locktime(&global, &inputs)?,
+        input: inputs
+            .iter()
+            .map(|input| {
+                let sequence = read_u32(input, IN_SEQUENCE)?.map_or(Sequence::MAX, Sequence);
+                Ok(TxIn {
+                    previous_output: previous_output(input)?,
+                    sequence,
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<_>>()?,
+        output: outputs
+            .iter()
+            .map(|record| output(record))
+            .collect::<Result<_>>()?,
+    };
+
+    let tx_modifiable = find_value(&global, GLOBAL_TX_MODIFIABLE)
+        .and_then(|value| value.first().copied())
+        .unwrap_or(0);
+    let fields = PsbtV2Fields {
+        tx_modifiable,
+        global: take_records(
+            &mut global,
+            &[
+                GLOBAL_TX_VERSION,
+                GLOBAL_FALLBACK_LOCKTIME,
+                GLOBAL_INPUT_COUNT,
+                GLOBAL_OUTPUT_COUNT,
+                GLOBAL_TX_MODIFIABLE,
+                GLOBAL_VERSION,
+            ],
+        ),
+        inputs: inputs
+            .iter_mut()
+            .map(|input| {
+                take_records(
+                    input,
+                    &[
+                        IN_PREVIOUS_TXID,
+                        IN_OUTPUT_INDEX,
+                        IN_SEQUENCE,
+                        IN_REQUIRED_TIME_LOCKTIME,
+                        IN_REQUIRED_HEIGHT_LOCKTIME,
+                    ],
+                )
+            })
+            .collect(),
+        outputs: outputs
+            .iter_mut()
+            .map(|output| take_records(output, &[OUT_AMOUNT, OUT_SCRIPT]))
+            .collect(),
+    };
+
+    // What is left is plain v0 data; let the bitcoin crate parse it
+    let mut v0 = MAGIC.to_vec();
+    global.push((vec![GLOBAL_UNSIGNED_TX as u8], serialize(&tx)));
+    write_map(&mut v0, &mut global);
+    for input in inputs.iter_mut().chain(outputs.iter_mut()) {
+        write_map(&mut v0, input);
+    }
+    Ok((Psbt::deserialize(&v0)?, fields))
+}
+
+/// Decode a PSBT of either version
+pub fn decode_psbt_any(bytes: &[u8]) -> Result<(Psbt, PsbtFormat)> {
+    let rest = bytes
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| anyhow!("not a PSBT"))?;
+    let mut position = 0;
+    let global = read_map(rest, &mut position)?;
+    match read_u32(&global, GLOBAL_VERSION)? {
+        None | Some(0) => {
+            let v2_only = [
+                GLOBAL_TX_VERSION,
+                GLOBAL_FALLBACK_LOCKTIME,
+                GLOBAL_INPUT_COUNT,
+                GLOBAL_OUTPUT_COUNT,
+                GLOBAL_TX_MODIFIABLE,
+            ];
+            if v2_only
+                .iter()
+                .any(|field| find_value(&global, *field).is_some())
+            {
+                bail!("PSBTv0 must not carry PSBTv2 fields");
+            }
+            Ok((Psbt::deserialize(bytes)?, PsbtFormat::V0))
+        }
+        Some(2) => {
+            let (psbt, fields) = decode_v2(rest, global, position)?;
+            Ok((psbt, PsbtFormat::V2(fields)))
+        }
+        Some(version) => bail!("unsupported PSBT version {}", version),
+    }
+}
+
+/// Serialize `psbt` in the format it was decoded from
+pub fn encode_psbt(psbt: &Psbt, format: &PsbtFormat) -> Result<Vec<u8>> {
+    let PsbtFormat::V2(fields) = format else {
+        return Ok(psbt.serialize());
+    };
+    if fields.inputs.len() != psbt.inputs.len() || fields.outputs.len() != psbt.outputs.len() {
+        bail!("PSBT inputs or outputs changed since it was decoded");
+    }
+
+    let v0 = psbt.serialize();
+    let rest = &v0[MAGIC.len()..];
+    let mut position = 0;
+    let mut global = read_map(rest, &mut position)?;
+    take_records(&mut global, &[GLOBAL_UNSIGNED_TX]);
+    global.extend(
+        fields
+            .global
+            .iter()
+            .filter(|(key, _)| key_type(key).ok() != Some(GLOBAL_TX_MODIFIABLE))
+            .cloned(),
+    );
+    let tx_modifiable = updated_tx_modifiable(fields.tx_modifiable, psbt);
+    if find_value(&fields.global, GLOBAL_TX_MODIFIABLE).is_some() || tx_modifiable != 0 {
+        global.push((vec![GLOBAL_TX_MODIFIABLE as u8], vec![tx_modifiable]));
+    }
+
+    let mut out = MAGIC.to_vec();
+    write_map(&mut out, &mut global);
+    for saved in fields.inputs.iter().chain(fields.outputs.iter()) {
+        let mut records = read_map(rest, &mut position)?;
+        records.extend(saved.iter().cloned());
+        write_map(&mut out, &mut records);
+    }
+    Ok(out)
+}
+
+/// Apply the BIP-370 signer rules for PSBT_GLOBAL_TX_MODIFIABLE to every signature present
+fn updated_tx_modifiable(mut flags: u8, psbt: &Psbt) -> u8 {
+    let mut sighash_types = Vec::new();
+    for input in &psbt.inputs {
+        for signature in input.partial_sigs.values() {
+            // ECDSA sighash bytes line up with the taproot ones apart from DEFAULT
+            let sighash_type = TapSighashType::from_consensus_u8(signature.sighash_type as u8);
+            sighash_types.extend(sighash_type.ok());
+        }
+        let tap_signatures = input
+            .tap_key_sig
+            .iter()
+            .chain(input.tap_script_sigs.values());
+        sighash_types.extend(tap_signatures.map(|signature| signature.sighash_type));
+    }
+
+    for sighash_type in sighash_types {
+        let anyone_can_pay = matches!(
+            sighash_type,
+            TapSighashType::AllPlusAnyoneCanPay
+                | TapSighashType::NonePlusAnyoneCanPay
+                | TapSighashType::SinglePlusAnyoneCanPay
+        );
+        let none = matches!(
+            sighash_type,
+            TapSighashType::None | TapSighashType::NonePlusAnyoneCanPay
+        );
+        let single = matches!(
+            sighash_type,
+            TapSighashType::Single | TapSighashType::SinglePlusAnyoneCanPay
+        );
+        if !anyone_can_pay {
+            flags &= !INPUTS_MODIFIABLE;
+        }
+        if !none {
+            flags &= !OUTPUTS_MODIFIABLE;
+        }
+        if single {
+            flags |= HAS_SIGHASH_SINGLE;
+        }
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::ecdsa;
+    use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+    use bitcoin::sighash::EcdsaSighashType;
+
+    fn record(key_type: u64, value: &[u8]) -> Record {
+        (vec![key_type as u8], value.to_vec())
+    }
+
+    /// Global map of a PSBTv2 with `inputs` and `outputs`, before any optional field
+    fn v2_global(inputs: usize, outputs: usize) -> Vec<Record> {
+        vec![
+            record(GLOBAL_TX_VERSION, &2u32.to_le_bytes()),
+            record(GLOBAL_INPUT_COUNT, &[inputs as u8]),
+            record(GLOBAL_OUTPUT_COUNT, &[outputs as u8]),
+            record(GLOBAL_VERSION, &2u32.to_le_bytes()),
+        ]
+    }
+
+    fn v2_input(vout: u32, extra: &[Record]) -> Vec<Record> {
+        let mut input = vec![
+            record(IN_PREVIOUS_TXID, &[vout as u8 + 1; 32]),
+            record(IN_OUTPUT_INDEX, &vout.to_le_bytes()),
+        ];
+        input.extend_from_slice(extra);
+        input
+    }
+
+    fn v2_output() -> Vec<Record> {
+        vec![
+            record(OUT_AMOUNT, &50_000i64.to_le_bytes()),
+            record(
+                OUT_SCRIPT,
+                &[
+                    0x00, 0x14, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb,
+                    0xcc, 0xdd, 0xee, 0xff, 0x00, 0x11, 0x22, 0x33, 0x44,
+                ],
+            ),
+        ]
+    }
+
+    fn serialize_v2(mut global: Vec<Record>, mut maps: Vec<Vec<Record>>) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        write_map(&mut out, &mut global);
+        for map in &mut maps {
+            write_map(&mut out, map);
+        }
+        out
+    }
+
+    fn time(value: u32) -> Record {
+        record(IN_REQUIRED_TIME_LOCKTIME, &value.to_le_bytes())
+    }
+
+    fn height(value: u32) -> Record {
+        record(IN_REQUIRED_HEIGHT_LOCKTIME, &value.to_le_bytes())
+    }
+
+    fn locktime(global: &[Record], inputs: &[Vec<Record>]) -> Result<u32> {
+        Ok(compute_locktime(global, inputs)?.to_consensus_u32())
+    }
+
+    /// The cases of the BIP-370 locktime determination
+    #[test]
+    fn determines_locktime() {
+        let fallback = [record(GLOBAL_FALLBACK_LOCKTIME, &1_000u32.to_le_bytes())];
+        // No input requires a locktime: the fallback, or 0 without one
+        assert_eq!(locktime(&[], &[Vec::new()]).unwrap(), 0);
+        assert_eq!(locktime(&fallback, &[Vec::new()]).unwrap(), 1_000);
+        // The largest height, or time, of the inputs that require one
+        assert_eq!(
+            locktime(&fallback, &[vec![height(10_000)], vec![height(10_001)]]).unwrap(),
+            10_001
+        );
+        assert_eq!(
+            locktime(
+                &[],
+                &[vec![time(500_000_001)], Vec::new(), vec![time(500_000_002)]]
+            )
+            .unwrap(),
+            500_000_002
+        );
+        // Height wins when every input with a locktime takes either
+        assert_eq!(
+            locktime(
+                &[],
+                &[
+                    vec![time(500_000_001), height(10_000)],
+                    vec![height(10_001)]
+                ]
+            )
+            .unwrap(),
+            10_001
+        );
+        assert_eq!(
+            locktime(
+                &[],
+                &[
+                    vec![time(500_000_001), height(10_000)],
+                    vec![time(500_000_002)]
+                ]
+            )
+            .unwrap(),
+            500_000_002
+        );
+        assert_eq!(
+            locktime(&[], &[vec![time(500_000_001), height(10_000)]]).unwrap(),
+            10_000
+        );
+        // One input wants a height, another a time: no locktime satisfies both
+        assert!(locktime(&[], &[vec![height(10_000)], vec![time(500_000_001)]]).is_err());
+        // A time under the threshold and a height above it are invalid
+        assert!(locktime(&[], &[vec![time(499_999_999)]]).is_err());
+        assert!(locktime(&[], &[vec![time(500_000_001), height(500_000_000)]]).is_err());
+        // Locktime fields are 4 bytes
+        assert!(locktime(&[], &[vec![record(IN_REQUIRED_HEIGHT_LOCKTIME, &[1, 0])]]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_psbts() {
+        let valid = || (v2_global(1, 1), vec![v2_input(0, &[]), v2_output()]);
+        let (global, maps) = valid();
+        assert!(decode_psbt_any(&serialize_v2(global, maps)).is_ok());
+
+        // Each required field left out in turn
+        for (map, field) in [
+            (None, GLOBAL_TX_VERSION),
+            (None, GLOBAL_INPUT_COUNT),
+            (None, GLOBAL_OUTPUT_COUNT),
+            (Some(0), IN_PREVIOUS_TXID),
+            (Some(0), IN_OUTPUT_INDEX),
+            (Some(1), OUT_AMOUNT),
+            (Some(1), OUT_SCRIPT),
+        ] {
+            let (mut global, mut maps) = valid();
+            let records = match map {
+                None => &mut global,
+                Some(index) => &mut maps[index],
+            };
+            take_records(records, &[field]);
+            assert!(
+                decode_psbt_any(&serialize_v2(global, maps)).is_err(),
+                "{:#04x}",
+                field
+            );
+        }
+
+        // A v2 PSBT with a v0 unsigned transaction
+        let (mut global, maps) = valid();
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+        global.push(record(GLOBAL_UNSIGNED_TX, &serialize(&tx)));
+        assert!(decode_psbt_any(&serialize_v2(global, maps)).is_err());
+
+        // A v0 PSBT with v2 global fields
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap().serialize();
+        psbt.truncate(psbt.len() - 1);
+        psbt.extend([
+            0x01,
+            GLOBAL_TX_VERSION as u8,
+            0x04,
+            0x02,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ]);
+        assert!(decode_psbt_any(&psbt).is_err());
+
+        // Fewer maps than the counts promise, and more
+        let (global, mut maps) = valid();
+        maps.pop();
+        assert!(decode_psbt_any(&serialize_v2(global, maps)).is_err());
+        let (global, mut maps) = valid();
+        maps.push(v2_output());
+        assert!(decode_psbt_any(&serialize_v2(global, maps)).is_err());
+
+        // A negative output amount
+        let (global, mut maps) = valid();
+        maps[1][0] = record(OUT_AMOUNT, &(-1i64).to_le_bytes());
+        assert!(decode_psbt_any(&serialize_v2(global, maps)).is_err());
+    }
+
+    #[test]
+    fn round_trips_through_v0() {
+        let mut global = v2_global(2, 1);
+        global.push(record(GLOBAL_FALLBACK_LOCKTIME, &1_000u32.to_le_bytes()));
+        global.push(record(
+            GLOBAL_TX_MODIFIABLE,
+            &[INPUTS_MODIFIABLE | OUTPUTS_MODIFIABLE],
+        ));
+        // A proprietary field must come back untouched as well
+        let proprietary = (vec![0xfc, 0x03, b'f', b'o', b'o', 0x00], vec![0x2a]);
+        let first = v2_input(
+            0,
+            &[
+                height(800_000),
+                record(IN_SEQUENCE, &0xfffffffdu32.to_le_bytes()),
+            ],
+        );
+        let mut second = v2_input(1, &[time(500_000_001), height(799_000)]);
+        second.push(proprietary);
+        let original = serialize_v2(global, vec![first, second, v2_output()]);
+
+        let (psbt, format) = decode_psbt_any(&original).unwrap();
+        assert_eq!(psbt.unsigned_tx.lock_time.to_consensus_u32(), 800_000);
+        assert_eq!(psbt.unsigned_tx.input[0].sequence, Sequence(0xfffffffd));
+        assert_eq!(psbt.unsigned_tx.input[1].sequence, Sequence::MAX);
+
+        // Through v0 bytes and back, as the signer sees it
+        let (v0, v0_format) = decode_psbt_any(&psbt.serialize()).unwrap();
+        assert_eq!(v0_format, PsbtFormat::V0);
+        assert_eq!(encode_psbt(&v0, &format).unwrap(), original);
+    }
+
+    /// PSBT with one signature per entry of `sighash_types`, each on its own input
+    fn signed_psbt(sighash_types: &[EcdsaSighashType]) -> Psbt {
+        let secp = Secp256k1::new();
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default(); sighash_types.len()],
+            output: Vec::new(),
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let pubkey = bitcoin::PublicKey::new(secret_key.public_key(&secp));
+        let signature = secp.sign_ecdsa(&Message::from_digest([2; 32]), &secret_key);
+        for (input, sighash_type) in psbt.inputs.iter_mut().zip(sighash_types) {
+            let signature = ecdsa::Signature {
+                signature,
+                sighash_type: *sighash_type,
+            };
+            input.partial_sigs.insert(pubkey, signature);
+        }
+        psbt
+    }
+
+    /// The BIP-370 signer rules for PSBT_GLOBAL_TX_MODIFIABLE
+    #[test]
+    fn signatures_lock_what_they_commit_to() {
+        let both = INPUTS_MODIFIABLE | OUTPUTS_MODIFIABLE;
+        let flags = |sighash_types: &[EcdsaSighashType]| {
+            updated_tx_modifiable(both, &signed_psbt(sighash_types))
+        };
+        assert_eq!(flags(&[]), both);
+        assert_eq!(flags(&[EcdsaSighashType::All]), 0);
+        assert_eq!(
+            flags(&[EcdsaSighashType::AllPlusAnyoneCanPay]),
+            INPUTS_MODIFIABLE
+        );
+        assert_eq!(flags(&[EcdsaSighashType::None]), OUTPUTS_MODIFIABLE);
+        assert_eq!(flags(&[EcdsaSighashType::NonePlusAnyoneCanPay]), both);
+        assert_eq!(flags(&[EcdsaSighashType::Single]), HAS_SIGHASH_SINGLE);
+        assert_eq!(
+            flags(&[EcdsaSighashType::SinglePlusAnyoneCanPay]),
+            INPUTS_MODIFIABLE | HAS_SIGHASH_SINGLE
+        );
+        // Every signature takes away what it commits to
+        assert_eq!(
+            flags(&[
+                EcdsaSighashType::NonePlusAnyoneCanPay,
+                EcdsaSighashType::AllPlusAnyoneCanPay
+            ]),
+            INPUTS_MODIFIABLE
+        );
+
+        // A signature that pins the inputs clears the flag in the re-encoded v2
+        let psbt = signed_psbt(&[EcdsaSighashType::All]);
+        let format = PsbtFormat::V2(PsbtV2Fields {
+            tx_modifiable: both,
+            global: vec![record(GLOBAL_TX_MODIFIABLE, &[both])],
+            inputs: vec![Vec::new()],
+            outputs: Vec::new(),
+        });
+        let encoded = encode_psbt(&psbt, &format).unwrap();
+        let rest = &encoded[MAGIC.len()..];
+        let global = read_map(rest, &mut 0).unwrap();
+        assert_eq!(find_value(&global, GLOBAL_TX_MODIFIABLE), Some(&[0u8][..]));
+    }
+}
@@ -41,44 +41,1375 @@
 // }
 
 use base64;
-use base64::Engine;
-use base64::{
-    alphabet,
-    engine::{self, general_purpose},
-    Engine as _,
-};
-use bitcoin::consensus::encode;
+use base64::Engine as _;
+use bitcoin::bip32::{self, DerivationPath, Fingerprint, Xpub};
+use bitcoin::ecdsa;
 //use bitcoin::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::key::{Keypair, TapTweak, XOnlyPublicKey};
+use bitcoin::opcodes::all::{OP_CHECKSIGADD, OP_CLTV, OP_CSV};
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::{self, Message, Secp256k1, Signing, Verification};
+use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot::{self, TapLeafHash};
 use bitcoin::Psbt;
 use bitcoin::Transaction;
+use bitcoin::{
+    Amount, CompressedPublicKey, Network, PrivateKey, PublicKey, Script, ScriptBuf, TxOut,
+};
+use esp_idf_svc::sys::nvs_handle_t;
 use hex;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::bitcoin_mod::anti_exfil;
+use crate::bitcoin_mod::fee_policy::{
+    review_fee_violations, stored_fee_limits, FeeAction, FeeLimits, FeeRule, FeeViolation,
+};
+use crate::bitcoin_mod::finalize::{extract_transaction_hex, finalize_psbt};
+use crate::bitcoin_mod::policy::{verify_policy_hmac, WalletPolicy};
+use crate::bitcoin_mod::psbt_v2::{decode_psbt_any, encode_psbt, PsbtFormat};
+use crate::bitcoin_mod::transaction::{review_transaction, ScriptType, TxSummary};
+use crate::bitcoin_mod::wallet::MultisigWallet;
+use crate::security::entropy;
+use crate::security::key_management::{KeyTree, SeedSession};
+use crate::security::pin;
+use crate::ui::display::LcdController;
+use crate::ui::input::Buttons;
+use crate::ui::screens::{display_sighash_warning, display_taproot_leaf};
 
-fn read_psbt_from_string(psbt_data: &[u8]) -> Result<Psbt, Box<dyn std::error::Error>> {
+fn read_psbt_from_string(
+    psbt_data: &[u8],
+) -> Result<(Psbt, PsbtFormat), Box<dyn std::error::Error>> {
     //let b64 = general_purpose::STANDARD.decode(psbt_data).unwrap();
     // let psbt: Psbt = Psbt::deserialize(&psbt_bytes)?;
-    let psbt = Psbt::deserialize(psbt_data)?;
+    let (psbt, format) = decode_psbt_any(psbt_data)?;
+
+    Ok((psbt, format))
+}
+
+fn decode_psbt(psbt: &Psbt, network: Network, keys: &KeySource) -> String {
+    match TxSummary::from_psbt(psbt, network, keys) {
+        Ok(summary) => summary.to_string(),
+        Err(e) => format!("{:?} ({})", psbt, e),
+    }
+}
+
+/// Private key material the signer can match PSBT inputs against
+pub enum KeySource {
+    /// A single key, matched directly against the spent script
+    Single(PrivateKey),
+    /// A BIP-32 key tree, matched through the inputs' key origins
+    Master(KeyTree),
+}
+
+impl KeySource {
+    /// Fingerprint that key origins in a PSBT must carry to belong to this device
+    pub fn fingerprint<C: Signing>(&self, secp: &Secp256k1<C>) -> Fingerprint {
+        match self {
+            KeySource::Single(key) => {
+                let hash = key.public_key(secp).pubkey_hash();
+                let mut bytes = [0u8; 4];
+                bytes.copy_from_slice(&hash[..4]);
+                Fingerprint::from(bytes)
+            }
+            KeySource::Master(tree) => tree.fingerprint(),
+        }
+    }
+
+    /// Raw private key bytes, used to derive device-local secrets such as policy HMAC keys
+    pub(crate) fn secret_bytes(&self) -> [u8; 32] {
+        match self {
+            KeySource::Single(key) => key.inner.secret_bytes(),
+            KeySource::Master(tree) => tree.master().private_key.secret_bytes(),
+        }
+    }
+
+    /// Extended public key at `path`, only available with a master key
+    pub fn xpub_at<C: Signing>(&self, secp: &Secp256k1<C>, path: &DerivationPath) -> Option<Xpub> {
+        match self {
+            KeySource::Single(_) => None,
+            KeySource::Master(tree) => tree.xpub_at(secp, path).ok(),
+        }
+    }
+
+    /// Private key a PSBT key origin points at, if the origin belongs to this device
+    pub(crate) fn private_key_at<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        origin: Option<&bip32::KeySource>,
+    ) -> Option<PrivateKey> {
+        match (self, origin) {
+            (KeySource::Single(key), _) => Some(*key),
+            (KeySource::Master(tree), Some((fingerprint, path))) => {
+                if *fingerprint != tree.fingerprint() {
+                    return None;
+                }
+                Some(tree.derive_priv(secp, path).ok()?.to_priv())
+            }
+            (KeySource::Master(_), None) => None,
+        }
+    }
+
+    /// Look up the private key behind `pubkey`, using its PSBT key origin when there is one
+    fn private_key_for<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        pubkey: &secp256k1::PublicKey,
+        origin: Option<&bip32::KeySource>,
+    ) -> Option<PrivateKey> {
+        let key = self.private_key_at(secp, origin)?;
+        // Never trust the origin alone: the derived key must be the one the PSBT names
+        if key.public_key(secp).inner == *pubkey {
+            Some(key)
+        } else {
+            None
+        }
+    }
+
+    /// Same as `private_key_for`, for the x-only keys used by taproot
+    fn private_key_for_x_only<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        pubkey: &XOnlyPublicKey,
+        origin: Option<&bip32::KeySource>,
+    ) -> Option<PrivateKey> {
+        let key = self.private_key_at(secp, origin)?;
+        let (x_only, _) = key.inner.x_only_public_key(secp);
+        if x_only == *pubkey {
+            Some(key)
+        } else {
+            None
+        }
+    }
+
+    /// Public keys that may unlock an input, taken from its key origins plus our own single key
+    fn candidate_keys<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        input: &bitcoin::psbt::Input,
+    ) -> Vec<PublicKey> {
+        let mut keys: Vec<PublicKey> = input
+            .bip32_derivation
+            .keys()
+            .map(|key| PublicKey::new(*key))
+            .collect();
+        if let KeySource::Single(key) = self {
+            let own = key.public_key(secp);
+            if !keys.contains(&own) {
+                keys.push(own);
+            }
+        }
+        keys
+    }
+}
+
+/// Why an input was left unsigned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Neither `witness_utxo` nor `non_witness_utxo` is present
+    MissingUtxo,
+    /// The spent script is not a type this signer handles
+    UnsupportedScript,
+    /// None of our keys can unlock the input
+    NotOwned,
+    /// A PSBT field needed to sign this input is absent
+    MissingField(&'static str),
+    /// The full previous transaction does not match the outpoint being spent
+    UtxoMismatch,
+    /// A script supplied by the host does not hash to the spent output
+    ScriptMismatch,
+    /// The multisig script does not belong to any registered wallet
+    UnregisteredWallet,
+    /// An output claims to be multisig change but does not match the registered wallet
+    ChangeMismatch(usize),
+    /// The wallet policy sent with the request was not registered on this device
+    InvalidPolicy,
+    /// The input asks for a sighash type that cannot be used here
+    InvalidSighashType,
+    /// Computing the sighash failed
+    Sighash(String),
+    /// The fee breaks a blocking rule of the fee policy, or a warning was not confirmed
+    FeeLimit(FeeViolation),
+    /// The input asks for a sighash type the request did not opt in to
+    SighashNotAllowed(TapSighashType),
+    /// The anti-exfil request carries no host randomness for this input
+    MissingHostData,
+    /// Signing with the host-tweaked nonce failed
+    AntiExfil(String),
+    /// A fresh signature did not verify against our own key, a sign of a fault while signing
+    FaultySignature,
+    /// The device has not been unlocked with its PIN
+    Locked,
+    /// The transaction was not approved on the device, or there was no screen to show it on
+    NotApproved,
+}
 
-    Ok(psbt)
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::MissingUtxo => write!(f, "missing previous output"),
+            SkipReason::UnsupportedScript => write!(f, "unsupported script type"),
+            SkipReason::NotOwned => write!(f, "not owned by this device"),
+            SkipReason::MissingField(field) => write!(f, "missing {}", field),
+            SkipReason::UtxoMismatch => write!(f, "previous transaction mismatch"),
+            SkipReason::ScriptMismatch => write!(f, "script does not match previous output"),
+            SkipReason::UnregisteredWallet => write!(f, "wallet not registered"),
+            SkipReason::ChangeMismatch(output) => {
+                write!(f, "output {} is not change of a registered wallet", output)
+            }
+            SkipReason::InvalidPolicy => write!(f, "wallet policy not registered"),
+            SkipReason::InvalidSighashType => write!(f, "invalid sighash type"),
+            SkipReason::Sighash(err) => write!(f, "sighash error: {}", err),
+            SkipReason::FeeLimit(violation) => write!(f, "{}", violation),
+            SkipReason::SighashNotAllowed(sighash_type) => {
+                write!(f, "{} not allowed for this request", sighash_type)
+            }
+            SkipReason::MissingHostData => write!(f, "no anti-exfil host data"),
+            SkipReason::AntiExfil(err) => write!(f, "anti-exfil signing failed: {}", err),
+            SkipReason::FaultySignature => write!(f, "signature failed self-verification"),
+            SkipReason::Locked => write!(f, "device is locked"),
+            SkipReason::NotApproved => write!(f, "transaction not approved on device"),
+        }
+    }
+}
+
+/// A tapscript leaf the device produced a script-path signature for
+#[derive(Debug, Clone)]
+pub struct SignedLeaf {
+    pub input: usize,
+    pub leaf_hash: TapLeafHash,
+    pub script: ScriptBuf,
+}
+
+impl SignedLeaf {
+    /// Short human readable name of the spending condition, for the review screen
+    pub fn label(&self) -> &'static str {
+        let mut label = "Single key";
+        for instruction in self.script.instructions().flatten() {
+            match instruction {
+                Instruction::Op(OP_CSV) => return "Relative timelock",
+                Instruction::Op(OP_CLTV) => return "Absolute timelock",
+                Instruction::Op(OP_CHECKSIGADD) => label = "Multisig",
+                _ => {}
+            }
+        }
+        label
+    }
+}
+
+/// Something the user should see before approving, even though signing went ahead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The output carries one of our key origins but does not pay to that key
+    UnverifiedChange(usize),
+    /// The fee breaks a rule the fee policy only warns about, and the user accepted it
+    FeeLimit(FeeViolation),
+    /// The input was signed with an opted-in sighash type other than ALL or DEFAULT
+    NonStandardSighash(usize, TapSighashType),
 }
 
-fn decode_psbt(psbt: &Psbt) -> String {
-    format!("{:?}", psbt)
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::UnverifiedChange(output) => {
+                write!(
+                    f,
+                    "output {} claims to be change but does not pay to us",
+                    output
+                )
+            }
+            Warning::FeeLimit(violation) => write!(f, "{}", violation),
+            Warning::NonStandardSighash(input, sighash_type) => {
+                write!(f, "input {} signed with {}", input, sighash_type)
+            }
+        }
+    }
 }
 
-fn add_info_to_transaction(tx: &mut Transaction, info: &str) {
-    // This is a placeholder function. You can add your logic to modify the transaction here.
-    // For example, you might want to add an output or modify an input.
-    println!("Adding info: {} to transaction: {:?}", info, tx);
+/// Outcome of a `sign_psbt` call, one entry per input
+#[derive(Debug, Default)]
+pub struct SignReport {
+    pub signed: Vec<usize>,
+    pub skipped: Vec<(usize, SkipReason)>,
+    pub leaves: Vec<SignedLeaf>,
+    /// Outputs verified as paying back to this device or a registered multisig wallet
+    pub change: Vec<usize>,
+    pub warnings: Vec<Warning>,
+    /// Anti-exfil nonce commitments, one per signature, in signing order
+    pub nonce_commitments: Vec<(NonceSlot, secp256k1::PublicKey)>,
 }
 
-fn transaction_to_psbt(tx: &Transaction) -> Result<Psbt, Box<dyn std::error::Error>> {
-    let psbt = Psbt::from_unsigned_tx(tx.clone())?;
-    Ok(psbt)
+impl SignReport {
+    pub fn is_complete(&self) -> bool {
+        self.skipped.is_empty()
+    }
 }
 
-pub fn sig_example() {
+impl fmt::Display for SignReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signed {:?}", self.signed)?;
+        for (index, reason) in &self.skipped {
+            write!(f, ", input {} skipped ({})", index, reason)?;
+        }
+        for warning in &self.warnings {
+            write!(f, ", warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+/// Previous output spent by a PSBT input, as far as the PSBT tells us
+pub(crate) fn spent_output(psbt: &Psbt, index: usize) -> Option<TxOut> {
+    let input = &psbt.inputs[index];
+    if let Some(utxo) = &input.witness_utxo {
+        return Some(utxo.clone());
+    }
+    let prevout = psbt.unsigned_tx.input[index].previous_output;
+    input
+        .non_witness_utxo
+        .as_ref()
+        .and_then(|tx| tx.output.get(prevout.vout as usize).cloned())
+}
+
+/// Scripts our key could appear in as change, one per script type the inputs spend
+fn change_scripts<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    key: &PrivateKey,
+    script_types: &[ScriptType],
+) -> Vec<ScriptBuf> {
+    let Ok(compressed) = CompressedPublicKey::from_private_key(secp, key) else {
+        return Vec::new();
+    };
+    script_types
+        .iter()
+        .filter_map(|script_type| match script_type {
+            ScriptType::P2pkh => Some(ScriptBuf::new_p2pkh(&key.public_key(secp).pubkey_hash())),
+            ScriptType::P2wpkh => Some(ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash())),
+            ScriptType::P2shP2wpkh => {
+                let program = ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash());
+                Some(ScriptBuf::new_p2sh(&program.script_hash()))
+            }
+            ScriptType::P2tr => {
+                let (internal_key, _) = key.inner.x_only_public_key(secp);
+                Some(ScriptBuf::new_p2tr(secp, internal_key, None))
+            }
+            ScriptType::P2wsh | ScriptType::P2shP2wsh | ScriptType::Unknown => None,
+        })
+        .collect()
+}
+
+/// Single-key outputs proven to pay back to this device.
+///
+/// An output only counts as change when the key its origin points at is re-derived from our
+/// own key and the script built from it for one of the spent script types is the output script.
+/// Outputs that carry our fingerprint but fail that check come back as warnings.
+pub fn verify_change<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    psbt: &Psbt,
+    keys: &KeySource,
+) -> (Vec<usize>, Vec<Warning>) {
+    let fingerprint = keys.fingerprint(secp);
+    let mut script_types: Vec<ScriptType> = Vec::new();
+    for index in 0..psbt.inputs.len() {
+        if let Some(utxo) = spent_output(psbt, index) {
+            let script_type = ScriptType::of(&utxo.script_pubkey, &psbt.inputs[index]);
+            if !script_types.contains(&script_type) {
+                script_types.push(script_type);
+            }
+        }
+    }
+
+    let mut change = Vec::new();
+    let mut warnings = Vec::new();
+    for (index, output) in psbt.outputs.iter().enumerate() {
+        // Multisig change is checked against the registered wallets instead
+        if output.witness_script.is_some() {
+            continue;
+        }
+        let ecdsa_keys = output
+            .bip32_derivation
+            .iter()
+            .filter(|(_, (origin_fingerprint, _))| *origin_fingerprint == fingerprint)
+            .map(|(pubkey, origin)| keys.private_key_for(secp, pubkey, Some(origin)));
+        // Keys that sit in a tapscript leaf do not make the output ours through the key path
+        let taproot_keys = output
+            .tap_key_origins
+            .iter()
+            .filter(|(_, (leaf_hashes, (origin_fingerprint, _)))| {
+                *origin_fingerprint == fingerprint && leaf_hashes.is_empty()
+            })
+            .map(|(pubkey, (_, origin))| keys.private_key_for_x_only(secp, pubkey, Some(origin)));
+        let claims: Vec<Option<PrivateKey>> = ecdsa_keys.chain(taproot_keys).collect();
+        if claims.is_empty() {
+            continue;
+        }
+
+        let script_pubkey = &psbt.unsigned_tx.output[index].script_pubkey;
+        let verified = claims.iter().flatten().any(|key| {
+            change_scripts(secp, key, &script_types)
+                .iter()
+                .any(|script| script == script_pubkey)
+        });
+        if verified {
+            change.push(index);
+        } else {
+            warnings.push(Warning::UnverifiedChange(index));
+        }
+    }
+    (change, warnings)
+}
+
+/// Sighash type an input asks for, in taproot terms; the ECDSA types share their byte values
+fn requested_sighash(input: &bitcoin::psbt::Input) -> Result<TapSighashType, SkipReason> {
+    let Some(sighash_type) = input.sighash_type else {
+        return Ok(TapSighashType::Default);
+    };
+    u8::try_from(sighash_type.to_u32())
+        .ok()
+        .and_then(|byte| TapSighashType::from_consensus_u8(byte).ok())
+        .ok_or(SkipReason::InvalidSighashType)
+}
+
+/// ALL and DEFAULT commit to every input and output, so they never need an opt-in
+fn is_standard_sighash(sighash_type: TapSighashType) -> bool {
+    matches!(sighash_type, TapSighashType::All | TapSighashType::Default)
+}
+
+/// Inputs asking for anything other than ALL or DEFAULT, to be shown before signing
+pub fn non_standard_sighashes(psbt: &Psbt) -> Vec<(usize, TapSighashType)> {
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, input)| {
+            let sighash_type = requested_sighash(input).ok()?;
+            (!is_standard_sighash(sighash_type)).then_some((index, sighash_type))
+        })
+        .collect()
+}
+
+/// Warn about every opted-in sighash type the PSBT uses; `sign_psbt_with` drops the opt-in
+/// unless this passes
+pub fn review_sighash_types(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    psbt: &Psbt,
+    opt_in: &[TapSighashType],
+) -> anyhow::Result<()> {
+    for (index, sighash_type) in non_standard_sighashes(psbt) {
+        // Types without an opt-in are refused by the signer anyway
+        if !opt_in.contains(&sighash_type) {
+            continue;
+        }
+        display_sighash_warning(lcd, index, sighash_type);
+        if !buttons.confirm() {
+            anyhow::bail!("{} on input {} rejected on device", sighash_type, index);
+        }
+    }
+    Ok(())
+}
+
+/// Prevouts a taproot sighash has to commit to
+fn taproot_prevouts<'a>(
+    index: usize,
+    utxo: &TxOut,
+    prevouts: Option<&'a [TxOut]>,
+    sighash_type: TapSighashType,
+) -> Result<Prevouts<'a, TxOut>, SkipReason> {
+    // ANYONECANPAY commits to our own prevout only; everything else needs all of them
+    match sighash_type {
+        TapSighashType::AllPlusAnyoneCanPay
+        | TapSighashType::NonePlusAnyoneCanPay
+        | TapSighashType::SinglePlusAnyoneCanPay => Ok(Prevouts::One(index, utxo.clone())),
+        _ => prevouts.map(Prevouts::All).ok_or(SkipReason::MissingUtxo),
+    }
+}
+
+/// Whether `script` pushes the serialized public key `key`
+fn script_has_key(script: &Script, key: &[u8]) -> bool {
+    script.instructions().flatten().any(
+        |instruction| matches!(instruction, Instruction::PushBytes(push) if push.as_bytes() == key),
+    )
+}
+
+/// How much a segwit v0 input has to prove about the amount it spends.
+///
+/// Segwit v0 sighashes only commit to the amount of the input being signed, so a host can
+/// lie about amounts across two signing sessions and have the difference burnt as fee. The
+/// full previous transaction in `non_witness_utxo` pins the amount down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UtxoStrictness {
+    /// Trust `witness_utxo` alone
+    Off,
+    /// Check `non_witness_utxo` when the coordinator sends it
+    #[default]
+    VerifyIfPresent,
+    /// Refuse inputs without a matching `non_witness_utxo`
+    Require,
+}
+
+/// Check `non_witness_utxo` of a segwit v0 input against its outpoint and `witness_utxo`
+fn check_non_witness_utxo(
+    psbt: &Psbt,
+    index: usize,
+    strictness: UtxoStrictness,
+) -> Result<(), SkipReason> {
+    let input = &psbt.inputs[index];
+    let prev_tx = match (&input.non_witness_utxo, strictness) {
+        (_, UtxoStrictness::Off) => return Ok(()),
+        (Some(prev_tx), _) => prev_tx,
+        (None, UtxoStrictness::VerifyIfPresent) => return Ok(()),
+        (None, UtxoStrictness::Require) => {
+            return Err(SkipReason::MissingField("non_witness_utxo"))
+        }
+    };
+
+    let prevout = psbt.unsigned_tx.input[index].previous_output;
+    if prev_tx.compute_txid() != prevout.txid {
+        return Err(SkipReason::UtxoMismatch);
+    }
+    let utxo = prev_tx
+        .output
+        .get(prevout.vout as usize)
+        .ok_or(SkipReason::UtxoMismatch)?;
+    match &input.witness_utxo {
+        Some(witness_utxo) if witness_utxo != utxo => Err(SkipReason::UtxoMismatch),
+        _ => Ok(()),
+    }
+}
+
+/// Extra context for a signing request beyond the key material
+#[derive(Default)]
+pub struct SignOptions<'a> {
+    /// Multisig wallets registered on the device; P2WSH inputs are only signed for these
+    pub wallets: &'a [MultisigWallet],
+    /// A BIP-388 wallet policy and the HMAC the device returned when it was registered
+    pub policy: Option<(&'a WalletPolicy, &'a [u8; 32])>,
+    /// Fee rules to check before anything is signed; `sign_psbt` passes the stored ones
+    pub fee_limits: Option<&'a FeeLimits>,
+    /// Sighash types besides ALL and DEFAULT this request explicitly allows, each confirmed on
+    /// the `review` screen before signing
+    pub sighash_opt_in: &'a [TapSighashType],
+    /// What segwit v0 inputs must prove with `non_witness_utxo`
+    pub utxo_strictness: UtxoStrictness,
+    /// Host randomness every signature nonce has to include
+    pub anti_exfil: Option<&'a AntiExfil>,
+    /// Mix hardware randomness into otherwise deterministic nonces
+    pub nonce_entropy: bool,
+    /// Screen and buttons to review the transaction and confirm warnings on; without them
+    /// nothing is signed
+    pub review: Option<(&'a LcdController, &'a Buttons)>,
+}
+
+/// Per-input host randomness for the two anti-exfil rounds.
+///
+/// The host commits to 32 random bytes per input, the device answers with a commitment to
+/// each nonce, then the host reveals its bytes and the device signs with its nonce tweaked by
+/// them. The host checks every signature against the commitments, so firmware cannot leak
+/// key material through the nonces it picks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AntiExfil {
+    /// First round, from `anti_exfil::host_commitment`. Nothing is signed; `signed` in the
+    /// report lists the inputs that got nonce commitments.
+    Commit(BTreeMap<NonceSlot, [u8; 32]>),
+    /// Second round: the host data behind the commitments
+    Reveal(BTreeMap<NonceSlot, [u8; 32]>),
+}
+
+/// The signature an anti-exfil nonce belongs to: its input, plus the leaf for script-path ones
+pub type NonceSlot = (usize, Option<TapLeafHash>);
+
+/// Where signature nonces come from, and the check every signature passes before it is used.
+///
+/// Without anti-exfil, ECDSA nonces follow RFC6979 and BIP-340 nonces the BIP's own derivation,
+/// both a function of key and message. With `entropy` set, 32 bytes from `security::entropy` go
+/// in as RFC6979 extra data or BIP-340 auxiliary randomness, so a broken RNG can never make a
+/// nonce worse than the deterministic one. Every signature is verified against our public key
+/// afterwards: a fault-injected signature can reveal the key, so it must never leave the device.
+struct Nonces<'a> {
+    anti_exfil: Option<&'a AntiExfil>,
+    entropy: bool,
+    commitments: Vec<(NonceSlot, secp256k1::PublicKey)>,
+}
+
+impl Nonces<'_> {
+    /// ECDSA signature for input `index`, or `None` when only its nonce commitment is wanted
+    fn sign_ecdsa(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        index: usize,
+        message: &Message,
+        private_key: &PrivateKey,
+    ) -> Result<Option<secp256k1::ecdsa::Signature>, SkipReason> {
+        let anti_exfil_error = |err: anyhow::Error| SkipReason::AntiExfil(err.to_string());
+        let slot = (index, None);
+        let signature = match self.anti_exfil {
+            None if self.entropy => {
+                let extra: [u8; 32] = entropy::random_bytes();
+                secp.sign_ecdsa_with_noncedata(message, &private_key.inner, &extra)
+            }
+            None => secp.sign_ecdsa(message, &private_key.inner),
+            Some(AntiExfil::Commit(commitments)) => {
+                let host_commitment = commitments.get(&slot).ok_or(SkipReason::MissingHostData)?;
+                let commitment = anti_exfil::ecdsa_signer_commitment(
+                    secp,
+                    &private_key.inner,
+                    message,
+                    host_commitment,
+                )
+                .map_err(anti_exfil_error)?;
+                self.commitments.push((slot, commitment));
+                return Ok(None);
+            }
+            Some(AntiExfil::Reveal(host_data)) => {
+                let host_data = host_data.get(&slot).ok_or(SkipReason::MissingHostData)?;
+                let (signature, commitment) =
+                    anti_exfil::sign_ecdsa(secp, &private_key.inner, message, host_data)
+                        .map_err(anti_exfil_error)?;
+                self.commitments.push((slot, commitment));
+                signature
+            }
+        };
+
+        let pubkey = private_key.inner.public_key(secp);
+        secp.verify_ecdsa(message, &signature, &pubkey)
+            .map_err(|_| SkipReason::FaultySignature)?;
+        Ok(Some(signature))
+    }
+
+    /// BIP-340 counterpart of `sign_ecdsa`; `slot` names the leaf of a script-path signature
+    fn sign_schnorr(
+        &mut self,
+        secp: &Secp256k1<secp256k1::All>,
+        slot: NonceSlot,
+        message: &Message,
+        keypair: &Keypair,
+    ) -> Result<Option<secp256k1::schnorr::Signature>, SkipReason> {
+        let anti_exfil_error = |err: anyhow::Error| SkipReason::AntiExfil(err.to_string());
+        let signature = match self.anti_exfil {
+            None if self.entropy => {
+                let aux_rand: [u8; 32] = entropy::random_bytes();
+                secp.sign_schnorr_with_aux_rand(message, keypair, &aux_rand)
+            }
+            None => secp.sign_schnorr_no_aux_rand(message, keypair),
+            Some(AntiExfil::Commit(commitments)) => {
+                let host_commitment = commitments.get(&slot).ok_or(SkipReason::MissingHostData)?;
+                let commitment =
+                    anti_exfil::schnorr_signer_commitment(secp, keypair, message, host_commitment)
+                        .map_err(anti_exfil_error)?;
+                self.commitments.push((slot, commitment));
+                return Ok(None);
+            }
+            Some(AntiExfil::Reveal(host_data)) => {
+                let host_data = host_data.get(&slot).ok_or(SkipReason::MissingHostData)?;
+                let (signature, commitment) =
+                    anti_exfil::sign_schnorr(secp, keypair, message, host_data)
+                        .map_err(anti_exfil_error)?;
+                self.commitments.push((slot, commitment));
+                signature
+            }
+        };
+
+        let (pubkey, _) = keypair.x_only_public_key();
+        secp.verify_schnorr(&signature, message, &pubkey)
+            .map_err(|_| SkipReason::FaultySignature)?;
+        Ok(Some(signature))
+    }
+}
+
+/// State shared by every input of one `sign_psbt` call
+struct Signer<'a> {
+    secp: Secp256k1<secp256k1::All>,
+    cache: SighashCache<&'a Transaction>,
+    prevouts: Option<Vec<TxOut>>,
+    keys: &'a KeySource,
+    /// Registered wallets plus the one behind a verified wallet policy
+    wallets: Vec<MultisigWallet>,
+    nonces: Nonces<'a>,
+    /// Screen to approve each tapscript leaf on before it is signed
+    review: Option<(&'a LcdController, &'a Buttons)>,
+}
+
+impl<'a> Signer<'a> {
+    /// Sign a P2WPKH witness program with every matching key, returning whether a signature was added.
+    ///
+    /// `program` is the spent scriptPubKey for native inputs and the redeem script for nested ones.
+    fn sign_p2wpkh_input(
+        &mut self,
+        psbt: &mut Psbt,
+        index: usize,
+        program: &Script,
+        value: Amount,
+    ) -> Result<bool, SkipReason> {
+        let sighash_type = psbt.inputs[index]
+            .ecdsa_hash_ty()
+            .map_err(|_| SkipReason::InvalidSighashType)?;
+        self.sign_ecdsa_keys(
+            psbt,
+            index,
+            sighash_type,
+            |pubkey| {
+                CompressedPublicKey::try_from(*pubkey).is_ok_and(|compressed| {
+                    program == ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash()).as_script()
+                })
+            },
+            |cache| cache.p2wpkh_signature_hash(index, program, value, sighash_type),
+        )
+    }
+
+    /// Sign a nested P2SH-P2WPKH input once its redeem script is proven to be the spent one
+    fn sign_p2sh_input(
+        &mut self,
+        psbt: &mut Psbt,
+        index: usize,
+        utxo: &TxOut,
+    ) -> Result<bool, SkipReason> {
+        let redeem_script = psbt.inputs[index]
+            .redeem_script
+            .clone()
+            .ok_or(SkipReason::MissingField("redeem_script"))?;
+        if utxo.script_pubkey != ScriptBuf::new_p2sh(&redeem_script.script_hash()) {
+            return Err(SkipReason::ScriptMismatch);
+        }
+        if !redeem_script.is_p2wpkh() {
+            return Err(SkipReason::UnsupportedScript);
+        }
+        self.sign_p2wpkh_input(psbt, index, &redeem_script, utxo.value)
+    }
+
+    /// Sign a legacy P2PKH input, which is only safe with the full previous transaction
+    fn sign_p2pkh_input(&mut self, psbt: &mut Psbt, index: usize) -> Result<bool, SkipReason> {
+        let input = &psbt.inputs[index];
+        let prevout = psbt.unsigned_tx.input[index].previous_output;
+        let prev_tx = input
+            .non_witness_utxo
+            .as_ref()
+            .ok_or(SkipReason::MissingField("non_witness_utxo"))?;
+        if prev_tx.compute_txid() != prevout.txid {
+            return Err(SkipReason::UtxoMismatch);
+        }
+        let utxo = prev_tx
+            .output
+            .get(prevout.vout as usize)
+            .ok_or(SkipReason::UtxoMismatch)?;
+        let sighash_type = input
+            .ecdsa_hash_ty()
+            .map_err(|_| SkipReason::InvalidSighashType)?;
+        // Without an output at our index the legacy sighash is the constant 1, and a signature
+        // over it spends this coin in any transaction; no opt-in makes that acceptable
+        let single = matches!(
+            sighash_type,
+            EcdsaSighashType::Single | EcdsaSighashType::SinglePlusAnyoneCanPay
+        );
+        if single && index >= psbt.unsigned_tx.output.len() {
+            return Err(SkipReason::InvalidSighashType);
+        }
+
+        let script_pubkey = utxo.script_pubkey.clone();
+        self.sign_ecdsa_keys(
+            psbt,
+            index,
+            sighash_type,
+            |pubkey| script_pubkey == ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()),
+            |cache| cache.legacy_signature_hash(index, &script_pubkey, sighash_type.to_u32()),
+        )
+    }
+
+    /// Sign a P2WSH multisig input, but only for a wallet the user registered beforehand
+    fn sign_p2wsh_input(
+        &mut self,
+        psbt: &mut Psbt,
+        index: usize,
+        utxo: &TxOut,
+    ) -> Result<bool, SkipReason> {
+        let input = &psbt.inputs[index];
+        let witness_script = input
+            .witness_script
+            .as_ref()
+            .ok_or(SkipReason::MissingField("witness_script"))?;
+        if utxo.script_pubkey != ScriptBuf::new_p2wsh(&witness_script.wscript_hash()) {
+            return Err(SkipReason::ScriptMismatch);
+        }
+        let registered = self.wallets.iter().any(|wallet| {
+            wallet
+                .locate(&self.secp, witness_script, &input.bip32_derivation)
+                .is_some()
+        });
+        if !registered {
+            return Err(SkipReason::UnregisteredWallet);
+        }
+        let sighash_type = input
+            .ecdsa_hash_ty()
+            .map_err(|_| SkipReason::InvalidSighashType)?;
+
+        let witness_script = witness_script.clone();
+        self.sign_ecdsa_keys(
+            psbt,
+            index,
+            sighash_type,
+            |pubkey| script_has_key(&witness_script, &pubkey.to_bytes()),
+            |cache| cache.p2wsh_signature_hash(index, &witness_script, utxo.value, sighash_type),
+        )
+    }
+
+    /// Sign input `index` with every candidate key `owns` accepts, returning whether one matched.
+    ///
+    /// The ECDSA script types only differ in which keys belong to the script and in the sighash.
+    fn sign_ecdsa_keys<H, E>(
+        &mut self,
+        psbt: &mut Psbt,
+        index: usize,
+        sighash_type: EcdsaSighashType,
+        owns: impl Fn(&PublicKey) -> bool,
+        sighash: impl Fn(&mut SighashCache<&'a Transaction>) -> Result<H, E>,
+    ) -> Result<bool, SkipReason>
+    where
+        Message: From<H>,
+        E: fmt::Display,
+    {
+        let input = &psbt.inputs[index];
+        let mut signatures = Vec::new();
+        let mut matched = false;
+        for pubkey in self.keys.candidate_keys(&self.secp, input) {
+            if !owns(&pubkey) {
+                continue;
+            }
+            let origin = input.bip32_derivation.get(&pubkey.inner);
+            let Some(private_key) = self.keys.private_key_for(&self.secp, &pubkey.inner, origin)
+            else {
+                continue;
+            };
+
+            let sighash =
+                sighash(&mut self.cache).map_err(|err| SkipReason::Sighash(err.to_string()))?;
+            let message = Message::from(sighash);
+            matched = true;
+            let signature = self
+                .nonces
+                .sign_ecdsa(&self.secp, index, &message, &private_key)?;
+            if let Some(signature) = signature {
+                signatures.push((
+                    pubkey,
+                    ecdsa::Signature {
+                        signature,
+                        sighash_type,
+                    },
+                ));
+            }
+        }
+
+        psbt.inputs[index].partial_sigs.extend(signatures);
+        Ok(matched)
+    }
+
+    /// Outputs that are multisig change of a registered wallet.
+    ///
+    /// An output carrying one of our key origins next to a witness script claims to be change;
+    /// if no registered wallet produces that exact script, its index is returned as the error.
+    fn multisig_change(&self, psbt: &Psbt) -> Result<Vec<usize>, usize> {
+        let fingerprint = self.keys.fingerprint(&self.secp);
+        let mut change = Vec::new();
+        for (index, output) in psbt.outputs.iter().enumerate() {
+            let Some(witness_script) = &output.witness_script else {
+                continue;
+            };
+            let ours = output
+                .bip32_derivation
+                .values()
+                .any(|(origin_fingerprint, _)| *origin_fingerprint == fingerprint);
+            if !ours {
+                continue;
+            }
+            let script_pubkey = &psbt.unsigned_tx.output[index].script_pubkey;
+            let verified = *script_pubkey == ScriptBuf::new_p2wsh(&witness_script.wscript_hash())
+                && self.wallets.iter().any(|wallet| {
+                    wallet
+                        .locate(&self.secp, witness_script, &output.bip32_derivation)
+                        .is_some()
+                });
+            if !verified {
+                return Err(index);
+            }
+            change.push(index);
+        }
+        Ok(change)
+    }
+
+    /// Sign a P2TR input through the key path and every tapscript leaf we appear in
+    fn sign_taproot_input(
+        &mut self,
+        psbt: &mut Psbt,
+        index: usize,
+        utxo: &TxOut,
+        leaves: &mut Vec<SignedLeaf>,
+    ) -> Result<bool, SkipReason> {
+        // Script-path only PSBTs may leave out the internal key or the merkle root
+        let key_path = match self.sign_taproot_key_path(psbt, index, utxo) {
+            Err(SkipReason::MissingField(_) | SkipReason::UnsupportedScript)
+                if !psbt.inputs[index].tap_scripts.is_empty() =>
+            {
+                false
+            }
+            result => result?,
+        };
+        let script_path = self.sign_taproot_script_path(psbt, index, utxo, leaves)?;
+        Ok(key_path || script_path)
+    }
+
+    /// Sign a P2TR input through the key path, tweaking the internal key with the PSBT merkle root
+    fn sign_taproot_key_path(
+        &mut self,
+        psbt: &mut Psbt,
+        index: usize,
+        utxo: &TxOut,
+    ) -> Result<bool, SkipReason> {
+        let secp = &self.secp;
+        let input = &psbt.inputs[index];
+        let sighash_type = input
+            .taproot_hash_ty()
+            .map_err(|_| SkipReason::InvalidSighashType)?;
+        let internal_key = input
+            .tap_internal_key
+            .ok_or(SkipReason::MissingField("tap_internal_key"))?;
+        let merkle_root = input.tap_merkle_root;
+
+        if utxo.script_pubkey != ScriptBuf::new_p2tr(secp, internal_key, merkle_root) {
+            return Err(SkipReason::UnsupportedScript);
+        }
+        let origin = input
+            .tap_key_origins
+            .get(&internal_key)
+            .map(|(_, origin)| origin);
+        let Some(private_key) = self
+            .keys
+            .private_key_for_x_only(secp, &internal_key, origin)
+        else {
+            return Ok(false);
+        };
+
+        let prevouts = taproot_prevouts(index, utxo, self.prevouts.as_deref(), sighash_type)?;
+        let sighash = self
+            .cache
+            .taproot_key_spend_signature_hash(index, &prevouts, sighash_type)
+            .map_err(|err| SkipReason::Sighash(err.to_string()))?;
+
+        let keypair = Keypair::from(
+            Keypair::from_secret_key(secp, &private_key.inner).tap_tweak(secp, merkle_root),
+        );
+        let message = Message::from(sighash);
+        let signature = self
+            .nonces
+            .sign_schnorr(&self.secp, (index, None), &message, &keypair)?;
+        if let Some(signature) = signature {
+            psbt.inputs[index].tap_key_sig = Some(taproot::Signature {
+                signature,
+                sighash_type,
+            });
+        }
+        Ok(true)
+    }
+
+    /// Sign every tapscript leaf of a P2TR input that one of our keys appears in, adding the
+    /// leaves that got a signature to `leaves`
+    fn sign_taproot_script_path(
+        &mut self,
+        psbt: &mut Psbt,
+        index: usize,
+        utxo: &TxOut,
+        leaves: &mut Vec<SignedLeaf>,
+    ) -> Result<bool, SkipReason> {
+        let secp = &self.secp;
+        let input = &psbt.inputs[index];
+        if input.tap_scripts.is_empty() {
+            return Ok(false);
+        }
+        let sighash_type = input
+            .taproot_hash_ty()
+            .map_err(|_| SkipReason::InvalidSighashType)?;
+        let output_key = XOnlyPublicKey::from_slice(&utxo.script_pubkey.as_bytes()[2..])
+            .map_err(|_| SkipReason::UnsupportedScript)?;
+        let prevouts = taproot_prevouts(index, utxo, self.prevouts.as_deref(), sighash_type)?;
+
+        let mut candidates: Vec<(XOnlyPublicKey, Option<&bip32::KeySource>, &[TapLeafHash])> =
+            input
+                .tap_key_origins
+                .iter()
+                .map(|(key, (leaves, origin))| (*key, Some(origin), leaves.as_slice()))
+                .collect();
+        if let KeySource::Single(key) = self.keys {
+            candidates.push((key.inner.x_only_public_key(secp).0, None, &[]));
+        }
+
+        let mut signatures = Vec::new();
+        let mut matched = false;
+        for (control_block, (script, leaf_version)) in &input.tap_scripts {
+            // The host could hand us a leaf that is not part of the output being spent
+            if !control_block.verify_taproot_commitment(secp, output_key, script) {
+                continue;
+            }
+            let leaf = SignedLeaf {
+                input: index,
+                leaf_hash: TapLeafHash::from_script(script, *leaf_version),
+                script: script.clone(),
+            };
+            let leaf_hash = leaf.leaf_hash;
+
+            let mut reviewed = false;
+            let mut leaf_signed = false;
+            for (pubkey, origin, key_leaves) in &candidates {
+                let listed = match origin {
+                    Some(_) => key_leaves.contains(&leaf_hash),
+                    None => script_has_key(script, &pubkey.serialize()),
+                };
+                if !listed {
+                    continue;
+                }
+                let Some(private_key) = self.keys.private_key_for_x_only(secp, pubkey, *origin)
+                else {
+                    continue;
+                };
+                // Each leaf is approved once, before its first signature
+                if !reviewed {
+                    if let Some((lcd, buttons)) = self.review {
+                        display_taproot_leaf(lcd, &leaf);
+                        if !buttons.confirm() {
+                            break;
+                        }
+                    }
+                    reviewed = true;
+                }
+
+                let sighash = self
+                    .cache
+                    .taproot_script_spend_signature_hash(index, &prevouts, leaf_hash, sighash_type)
+                    .map_err(|err| SkipReason::Sighash(err.to_string()))?;
+                let keypair = Keypair::from_secret_key(secp, &private_key.inner);
+                let message = Message::from(sighash);
+                matched = true;
+                let slot = (index, Some(leaf_hash));
+                let signature = self.nonces.sign_schnorr(secp, slot, &message, &keypair)?;
+                if let Some(signature) = signature {
+                    signatures.push((
+                        (*pubkey, leaf_hash),
+                        taproot::Signature {
+                            signature,
+                            sighash_type,
+                        },
+                    ));
+                    leaf_signed = true;
+                }
+            }
+            // The anti-exfil commit round only hands out nonce commitments
+            if leaf_signed {
+                leaves.push(leaf);
+            }
+        }
+
+        psbt.inputs[index].tap_script_sigs.extend(signatures);
+        Ok(matched)
+    }
+}
+
+/// What the user is asked to approve, with our own change and the multisig `change` marked;
+/// `None` when the amounts do not add up to a transaction.
+///
+/// A fee from amounts that did not check out is no fee to show or judge, so it is left out
+/// unless `amounts_verified`.
+fn spend_summary(
+    psbt: &Psbt,
+    keys: &KeySource,
+    change: &[usize],
+    amounts_verified: bool,
+) -> Option<TxSummary> {
+    // Addresses are shown for mainnet, the network every key of this device is on
+    let mut summary = TxSummary::from_psbt(psbt, Network::Bitcoin, keys).ok()?;
+    summary.mark_change(change);
+    if !amounts_verified {
+        summary.fee = None;
+        summary.fee_rate = None;
+    }
+    Some(summary)
+}
+
+/// Fee rules of `options`; warnings need the user's OK on `options.review`, not just a report line
+fn check_fee_limits(
+    summary: Option<&TxSummary>,
+    options: &SignOptions,
+) -> Result<Vec<Warning>, SkipReason> {
+    let Some(limits) = options.fee_limits else {
+        return Ok(Vec::new());
+    };
+    let violations = match summary {
+        Some(summary) => limits.evaluate(summary),
+        // Amounts that do not add up to a transaction
+        None => vec![FeeViolation {
+            rule: FeeRule::UnknownFee,
+            action: FeeAction::Block,
+        }],
+    };
+    if violations.is_empty() {
+        return Ok(Vec::new());
+    }
+    let approved = match options.review {
+        Some((lcd, buttons)) => review_fee_violations(lcd, buttons, &violations).is_ok(),
+        None => false,
+    };
+    if !approved {
+        let violation = violations
+            .iter()
+            .find(|violation| violation.action == FeeAction::Block)
+            .unwrap_or(&violations[0]);
+        return Err(SkipReason::FeeLimit(violation.clone()));
+    }
+    Ok(violations.into_iter().map(Warning::FeeLimit).collect())
+}
+
+/// Outputs, fee and change shown on `options.review`; without a screen nothing is approved
+fn approve_summary(summary: Option<&TxSummary>, options: &SignOptions) -> Result<(), SkipReason> {
+    match (options.review, summary) {
+        (Some((lcd, buttons)), Some(summary)) => {
+            review_transaction(lcd, buttons, summary).map_err(|_| SkipReason::NotApproved)
+        }
+        _ => Err(SkipReason::NotApproved),
+    }
+}
+
+/// The fee rules and on-device review `sign_psbt_with` runs before signing, for signers that
+/// find their own inputs and change, such as musig.
+///
+/// Returns the fee warnings the user accepted.
+pub(crate) fn approve_spend(
+    psbt: &Psbt,
+    keys: &KeySource,
+    change: &[usize],
+    amounts_verified: bool,
+    options: &SignOptions,
+) -> Result<Vec<Warning>, SkipReason> {
+    let summary = spend_summary(psbt, keys, change, amounts_verified);
+    let warnings = check_fee_limits(summary.as_ref(), options)?;
+    approve_summary(summary.as_ref(), options)?;
+    Ok(warnings)
+}
+
+/// Sign every input of `psbt` that one of our keys can unlock, within `fee_limits`, once the
+/// user approves the transaction on `lcd`.
+///
+/// Signatures are added to the inputs' `partial_sigs`, `tap_key_sig` and `tap_script_sigs`;
+/// nothing is finalized.
+pub fn sign_psbt(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    psbt: &mut Psbt,
+    keys: &KeySource,
+    fee_limits: &FeeLimits,
+) -> SignReport {
+    let options = SignOptions {
+        fee_limits: Some(fee_limits),
+        review: Some((lcd, buttons)),
+        ..SignOptions::default()
+    };
+    sign_psbt_with(psbt, keys, &options)
+}
+
+/// Same as `sign_psbt`, with registered wallets and other request context.
+///
+/// Before any signature is produced, the outputs, fee and change are shown on `options.review`
+/// and must be approved; without a screen nothing is signed.
+pub fn sign_psbt_with(psbt: &mut Psbt, keys: &KeySource, options: &SignOptions) -> SignReport {
+    sign_inputs(psbt, keys, options, true)
+}
+
+/// Sign a PSBT whose meaning the caller has already had approved on the device, such as the
+/// virtual BIP-322 transaction behind a reviewed message
+pub(crate) fn sign_approved_psbt(
+    psbt: &mut Psbt,
+    keys: &KeySource,
+    options: &SignOptions,
+) -> SignReport {
+    sign_inputs(psbt, keys, options, false)
+}
+
+fn sign_inputs(
+    psbt: &mut Psbt,
+    keys: &KeySource,
+    options: &SignOptions,
+    summary_review: bool,
+) -> SignReport {
+    let mut report = SignReport::default();
+    if !pin::is_unlocked() {
+        report.skipped = (0..psbt.inputs.len())
+            .map(|index| (index, SkipReason::Locked))
+            .collect();
+        return report;
+    }
+    let mut wallets = options.wallets.to_vec();
+    if let Some((policy, hmac)) = options.policy {
+        match policy.wallet() {
+            Ok(wallet) if verify_policy_hmac(policy, hmac, keys) => wallets.push(wallet),
+            _ => {
+                report.skipped = (0..psbt.inputs.len())
+                    .map(|index| (index, SkipReason::InvalidPolicy))
+                    .collect();
+                return report;
+            }
+        }
+    }
+
+    let tx = psbt.unsigned_tx.clone();
+    let mut signer = Signer {
+        secp: Secp256k1::new(),
+        cache: SighashCache::new(&tx),
+        prevouts: (0..psbt.inputs.len())
+            .map(|index| spent_output(psbt, index))
+            .collect(),
+        keys,
+        wallets,
+        nonces: Nonces {
+            anti_exfil: options.anti_exfil,
+            entropy: options.nonce_entropy,
+            commitments: Vec::new(),
+        },
+        review: options.review,
+    };
+
+    // A swapped cosigner key in a change output would hand funds to the host; sign nothing
+    match signer.multisig_change(psbt) {
+        Ok(change) => report.change = change,
+        Err(output) => {
+            report.skipped = (0..psbt.inputs.len())
+                .map(|index| (index, SkipReason::ChangeMismatch(output)))
+                .collect();
+            return report;
+        }
+    }
+    let (change, warnings) = verify_change(&signer.secp, psbt, keys);
+    report.change.extend(change);
+    report.change.sort_unstable();
+    report.warnings = warnings;
+
+    // The fee rules read the amounts in `witness_utxo`, so those are checked first
+    let utxo_checks: Vec<Result<(), SkipReason>> = (0..psbt.inputs.len())
+        .map(|index| {
+            let Some(utxo) = spent_output(psbt, index) else {
+                return Ok(());
+            };
+            match ScriptType::of(&utxo.script_pubkey, &psbt.inputs[index]) {
+                ScriptType::P2wpkh
+                | ScriptType::P2shP2wpkh
+                | ScriptType::P2wsh
+                | ScriptType::P2shP2wsh => {
+                    check_non_witness_utxo(psbt, index, options.utxo_strictness)
+                }
+                _ => Ok(()),
+            }
+        })
+        .collect();
+
+    let amounts_verified = utxo_checks.iter().all(Result::is_ok);
+    let summary = spend_summary(psbt, keys, &report.change, amounts_verified);
+    match check_fee_limits(summary.as_ref(), options) {
+        Ok(warnings) => report.warnings.extend(warnings),
+        Err(reason) => {
+            report.skipped = (0..psbt.inputs.len())
+                .map(|index| (index, reason.clone()))
+                .collect();
+            return report;
+        }
+    }
+
+    // The anti-exfil commit round only hands out nonce commitments; its reveal round is reviewed
+    let signs = !matches!(options.anti_exfil, Some(AntiExfil::Commit(_)));
+    if summary_review && signs {
+        if let Err(reason) = approve_summary(summary.as_ref(), options) {
+            report.skipped = (0..psbt.inputs.len())
+                .map(|index| (index, reason.clone()))
+                .collect();
+            return report;
+        }
+    }
+
+    // An opt-in only counts once the user has seen and accepted every type it lets through
+    let opted_in = non_standard_sighashes(psbt)
+        .iter()
+        .any(|(_, sighash_type)| options.sighash_opt_in.contains(sighash_type));
+    let sighash_opt_in = match options.review {
+        _ if !opted_in => options.sighash_opt_in,
+        Some((lcd, buttons))
+            if review_sighash_types(lcd, buttons, psbt, options.sighash_opt_in).is_ok() =>
+        {
+            options.sighash_opt_in
+        }
+        _ => &[],
+    };
+
+    for (index, utxo_check) in utxo_checks.into_iter().enumerate() {
+        let Some(utxo) = spent_output(psbt, index) else {
+            report.skipped.push((index, SkipReason::MissingUtxo));
+            continue;
+        };
+        // A host asking for NONE or ANYONECANPAY could rewrite the transaction after we sign
+        let sighash_type = match requested_sighash(&psbt.inputs[index]) {
+            Ok(sighash_type) => sighash_type,
+            Err(reason) => {
+                report.skipped.push((index, reason));
+                continue;
+            }
+        };
+        if let Err(reason) = utxo_check {
+            report.skipped.push((index, reason));
+            continue;
+        }
+        let standard = is_standard_sighash(sighash_type);
+        if !standard && !sighash_opt_in.contains(&sighash_type) {
+            report
+                .skipped
+                .push((index, SkipReason::SighashNotAllowed(sighash_type)));
+            continue;
+        }
+
+        let script_pubkey = &utxo.script_pubkey;
+        let result = if script_pubkey.is_p2wpkh() {
+            signer.sign_p2wpkh_input(psbt, index, script_pubkey, utxo.value)
+        } else if script_pubkey.is_p2sh() {
+            signer.sign_p2sh_input(psbt, index, &utxo)
+        } else if script_pubkey.is_p2pkh() {
+            signer.sign_p2pkh_input(psbt, index)
+        } else if script_pubkey.is_p2wsh() {
+            signer.sign_p2wsh_input(psbt, index, &utxo)
+        } else if script_pubkey.is_p2tr() {
+            signer.sign_taproot_input(psbt, index, &utxo, &mut report.leaves)
+        } else {
+            Err(SkipReason::UnsupportedScript)
+        };
+
+        match result {
+            Ok(true) => {
+                report.signed.push(index);
+                if !standard {
+                    report
+                        .warnings
+                        .push(Warning::NonStandardSighash(index, sighash_type));
+                }
+            }
+            Ok(false) => report.skipped.push((index, SkipReason::NotOwned)),
+            Err(reason) => report.skipped.push((index, reason)),
+        }
+    }
+
+    report.nonce_commitments = signer.nonces.commitments;
+    report
+}
+
+/// BIP-174 sample PSBT: one P2PKH input, with the full previous transaction
+pub(crate) const SAMPLE_PSBT: &str = "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab300000000000000";
+
+pub fn sig_example(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    session: &SeedSession,
+) {
     // Example usage
-    let psbt_data = "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab300000000000000";
+    let psbt_data = SAMPLE_PSBT;
     
     // Decode hex string to bytes
     let bytes = match hex::decode(psbt_data) {
@@ -90,24 +1421,485 @@ pub fn sig_example() {
     };
     
     // Now use the decoded bytes
-    let psbt = match read_psbt_from_string(&bytes) {
-        Ok(psbt) => psbt,
+    let (mut psbt, format) = match read_psbt_from_string(&bytes) {
+        Ok(decoded) => decoded,
         Err(e) => {
             eprintln!("Failed to read PSBT: {}", e);
             return;
         }
     };
-    
-    println!("Decoded PSBT: {}", decode_psbt(&psbt));
-    let mut tx = psbt.unsigned_tx.clone();
-    add_info_to_transaction(&mut tx, "example info");
-    let new_psbt = match transaction_to_psbt(&tx) {
-        Ok(psbt) => psbt,
+
+    println!(
+        "Decoded PSBT: {}",
+        decode_psbt(&psbt, Network::Bitcoin, session.keys())
+    );
+
+    let fee_limits = stored_fee_limits(handle);
+    let report = sign_psbt(lcd, buttons, &mut psbt, session.keys(), &fee_limits);
+    println!("Sign report: {}", report);
+
+    // Hand the PSBT back in the version the coordinator sent
+    let signed_psbt = match encode_psbt(&psbt, &format) {
+        Ok(signed_psbt) => signed_psbt,
         Err(e) => {
-            eprintln!("Failed to create PSBT: {}", e);
+            eprintln!("Failed to encode PSBT: {}", e);
             return;
         }
     };
-    let new_psbt_base64 = base64::engine::general_purpose::STANDARD.encode(&new_psbt.serialize());
-    println!("New PSBT: {}", new_psbt_base64);
+    let signed_psbt_base64 = base64::engine::general_purpose::STANDARD.encode(signed_psbt);
+    println!("Signed PSBT: {}", signed_psbt_base64);
+
+    // Once every input is signed the host also gets a transaction it can broadcast
+    if report.is_complete() {
+        match finalize_psbt(&mut psbt).and_then(|_| extract_transaction_hex(&psbt)) {
+            Ok(raw_tx) => println!("Raw transaction: {}", raw_tx),
+            Err(e) => eprintln!("Failed to finalize PSBT: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::key_management::Mnemonic;
+    use crate::ui::{approving_review, scripted_review};
+    use bitcoin::consensus::encode::deserialize_hex;
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::{Address, NetworkKind};
+
+    /// Unsigned transaction of the BIP-143 P2SH-P2WPKH example
+    const BIP143_P2SH_P2WPKH_TX: &str = "0100000001db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a54770100000000feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac92040000";
+
+    fn private_key(hex_key: &str) -> PrivateKey {
+        let secret_key = SecretKey::from_slice(&hex::decode(hex_key).unwrap()).unwrap();
+        PrivateKey::new(secret_key, NetworkKind::Main)
+    }
+
+    fn script(hex_script: &str) -> ScriptBuf {
+        ScriptBuf::from_hex(hex_script).unwrap()
+    }
+
+    /// Sign with no other options, on a screen that approves everything
+    fn sign_approved(psbt: &mut Psbt, keys: &KeySource) -> SignReport {
+        let (lcd, buttons) = approving_review();
+        let options = SignOptions {
+            review: Some((&lcd, &buttons)),
+            ..SignOptions::default()
+        };
+        sign_psbt_with(psbt, keys, &options)
+    }
+
+    fn partial_sig(psbt: &Psbt, index: usize) -> String {
+        let (_, signature) = psbt.inputs[index].partial_sigs.iter().next().unwrap();
+        hex::encode(signature.to_vec())
+    }
+
+    /// Native P2WPKH example from BIP-143; its first input is a P2PK we do not sign
+    #[test]
+    fn signs_bip143_p2wpkh() {
+        pin::unlock_for_tests();
+        let tx: Transaction = deserialize_hex("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000").unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(625_000_000),
+            script_pubkey: script(
+                "2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac",
+            ),
+        });
+        psbt.inputs[1].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(600_000_000),
+            script_pubkey: script("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1"),
+        });
+        let keys = KeySource::Single(private_key(
+            "619c335025c7f4012e556c2a58b2506e30b8511b53ade95ea316fd8c3286feb9",
+        ));
+
+        // The example burns most of its inputs as fee, so no fee limits here
+        let report = sign_approved(&mut psbt, &keys);
+        assert_eq!(report.signed, vec![1]);
+        assert_eq!(report.skipped, vec![(0, SkipReason::UnsupportedScript)]);
+        assert_eq!(
+            partial_sig(&psbt, 1),
+            "304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee01"
+        );
+    }
+
+    /// P2SH-P2WPKH example from BIP-143, within the default fee limits
+    #[test]
+    fn signs_bip143_p2sh_p2wpkh() {
+        pin::unlock_for_tests();
+        let tx: Transaction = deserialize_hex(BIP143_P2SH_P2WPKH_TX).unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(1_000_000_000),
+            script_pubkey: script("a9144733f37cf4db86fbc2efed2500b4f4e49f31202387"),
+        });
+        psbt.inputs[0].redeem_script = Some(script("001479091972186c449eb1ded22b78e40d009bdf0089"));
+        let keys = KeySource::Single(private_key(
+            "eb696a065ef48a2192da5b28b694f87544b30fae8327c4510137a922f32c6dcf",
+        ));
+
+        let (lcd, buttons) = approving_review();
+        let report = sign_psbt(&lcd, &buttons, &mut psbt, &keys, &FeeLimits::default());
+        assert!(report.is_complete(), "{}", report);
+        assert_eq!(
+            partial_sig(&psbt, 0),
+            "3044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f0220217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb01"
+        );
+    }
+
+    /// Legacy P2PKH spend of `key`, with the full previous transaction
+    fn p2pkh_psbt(secp: &Secp256k1<secp256k1::All>, key: &PrivateKey) -> Psbt {
+        let script_pubkey = ScriptBuf::new_p2pkh(&key.public_key(secp).pubkey_hash());
+        // The BIP-143 P2SH-P2WPKH transaction, with its first output paying to our key
+        let mut prev_tx: Transaction = deserialize_hex(BIP143_P2SH_P2WPKH_TX).unwrap();
+        prev_tx.output[0].script_pubkey = script_pubkey;
+        let tx = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn {
+                previous_output: bitcoin::OutPoint::new(prev_tx.compute_txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(199_990_000),
+                script_pubkey: script("0014a457b684d7f0d539a46a45bbc043f35b59d0d963"),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx);
+        psbt
+    }
+
+    #[test]
+    fn signs_p2pkh() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let key = private_key("0101010101010101010101010101010101010101010101010101010101010101");
+        let script_pubkey = ScriptBuf::new_p2pkh(&key.public_key(&secp).pubkey_hash());
+        let mut psbt = p2pkh_psbt(&secp, &key);
+
+        let other = private_key("0202020202020202020202020202020202020202020202020202020202020202");
+        let report = sign_approved(&mut psbt.clone(), &KeySource::Single(other));
+        assert_eq!(report.skipped, vec![(0, SkipReason::NotOwned)]);
+
+        let report = sign_approved(&mut psbt, &KeySource::Single(key));
+        assert!(report.is_complete(), "{}", report);
+        let sighash = SighashCache::new(&psbt.unsigned_tx)
+            .legacy_signature_hash(0, &script_pubkey, 1)
+            .unwrap();
+        let (pubkey, signature) = psbt.inputs[0].partial_sigs.iter().next().unwrap();
+        secp.verify_ecdsa(&Message::from(sighash), &signature.signature, &pubkey.inner)
+            .unwrap();
+        // RFC6979 makes the signature deterministic, so it is pinned as well
+        assert_eq!(
+            partial_sig(&psbt, 0),
+            "3045022100e66c3b043ee06add818e0b7b8301f986ad9c55b70483108d76e0ce2991ebc8050220552e126bd6c15158cf4974f79ee9f081b92ccab3fd1c7e4a1e979a308ab0bbe201"
+        );
+    }
+
+    #[test]
+    fn opt_in_needs_review() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let key = private_key("0101010101010101010101010101010101010101010101010101010101010101");
+        let mut psbt = p2pkh_psbt(&secp, &key);
+        psbt.inputs[0].sighash_type = Some(EcdsaSighashType::None.into());
+
+        // The transaction is approved, the NONE warning after it is not
+        let (lcd, buttons) = scripted_review(&[35, 35, 35, 0]);
+        let options = SignOptions {
+            sighash_opt_in: &[TapSighashType::None],
+            review: Some((&lcd, &buttons)),
+            ..SignOptions::default()
+        };
+        let report = sign_psbt_with(&mut psbt, &KeySource::Single(key), &options);
+        assert_eq!(
+            report.skipped,
+            vec![(0, SkipReason::SighashNotAllowed(TapSighashType::None))]
+        );
+    }
+
+    #[test]
+    fn signs_nothing_the_user_did_not_approve() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let key = private_key("0101010101010101010101010101010101010101010101010101010101010101");
+        let keys = KeySource::Single(key);
+        let mut psbt = p2pkh_psbt(&secp, &key);
+
+        let report = sign_psbt_with(&mut psbt, &keys, &SignOptions::default());
+        assert_eq!(report.skipped, vec![(0, SkipReason::NotApproved)]);
+
+        // Output page, fee page, then left on the final screen
+        let (lcd, buttons) = scripted_review(&[35, 35, 0]);
+        let options = SignOptions {
+            review: Some((&lcd, &buttons)),
+            ..SignOptions::default()
+        };
+        let report = sign_psbt_with(&mut psbt, &keys, &options);
+        assert_eq!(report.skipped, vec![(0, SkipReason::NotApproved)]);
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+
+        let screens = lcd.screens();
+        assert_eq!(screens.len(), 3);
+        assert!(screens[0].contains("1.99990000 BTC"), "{}", screens[0]);
+        let address =
+            Address::from_script(&psbt.unsigned_tx.output[0].script_pubkey, Network::Bitcoin);
+        let address = address.unwrap().to_string();
+        assert!(screens[0].contains(&address[..22]), "{}", screens[0]);
+        assert!(screens[0].contains(&address[22..]), "{}", screens[0]);
+        assert!(screens[1].contains("Fee 0.00006600 BTC"), "{}", screens[1]);
+        assert!(screens[1].contains("No change"), "{}", screens[1]);
+    }
+
+    /// SIGHASH_SINGLE past the last output signs the constant 1, opted in or not
+    #[test]
+    fn refuses_single_without_output() {
+        let secp = Secp256k1::new();
+        let key = private_key("0101010101010101010101010101010101010101010101010101010101010101");
+        let mut psbt = p2pkh_psbt(&secp, &key);
+        psbt.unsigned_tx.output.clear();
+        psbt.outputs.clear();
+        psbt.inputs[0].sighash_type = Some(EcdsaSighashType::Single.into());
+
+        let keys = KeySource::Single(key);
+        let tx = psbt.unsigned_tx.clone();
+        let mut signer = Signer {
+            secp,
+            cache: SighashCache::new(&tx),
+            prevouts: None,
+            keys: &keys,
+            wallets: Vec::new(),
+            nonces: Nonces {
+                anti_exfil: None,
+                entropy: false,
+                commitments: Vec::new(),
+            },
+            review: None,
+        };
+        assert_eq!(
+            signer.sign_p2pkh_input(&mut psbt, 0),
+            Err(SkipReason::InvalidSighashType)
+        );
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+    }
+
+    /// A previous transaction that contradicts `witness_utxo` also rules out the fee check
+    #[test]
+    fn checks_p2sh_p2wsh_utxo_before_fees() {
+        pin::unlock_for_tests();
+        let tx: Transaction = deserialize_hex(BIP143_P2SH_P2WPKH_TX).unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(tx.clone()).unwrap();
+        let program = ScriptBuf::new_p2wsh(&script("51").wscript_hash());
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(1_000_000_000),
+            script_pubkey: ScriptBuf::new_p2sh(&program.script_hash()),
+        });
+        psbt.inputs[0].redeem_script = Some(program);
+        // Not the transaction the input spends from
+        psbt.inputs[0].non_witness_utxo = Some(tx);
+        let keys = KeySource::Single(private_key(
+            "eb696a065ef48a2192da5b28b694f87544b30fae8327c4510137a922f32c6dcf",
+        ));
+
+        let report = sign_approved(&mut psbt, &keys);
+        assert_eq!(report.skipped, vec![(0, SkipReason::UtxoMismatch)]);
+
+        let limits = FeeLimits::default();
+        let options = SignOptions {
+            fee_limits: Some(&limits),
+            ..SignOptions::default()
+        };
+        let report = sign_psbt_with(&mut psbt, &keys, &options);
+        let unknown_fee = FeeViolation {
+            rule: FeeRule::UnknownFee,
+            action: FeeAction::Block,
+        };
+        assert_eq!(report.skipped, vec![(0, SkipReason::FeeLimit(unknown_fee))]);
+    }
+
+    /// Key-path spend of the first BIP-86 address of the "abandon ... about" test mnemonic.
+    ///
+    /// Internal and output key are the ones BIP-86 lists. The sighash and signature were
+    /// computed apart from this code, with the BIP-340 reference signer and no auxiliary
+    /// randomness, which is what this signer uses without `nonce_entropy`.
+    #[test]
+    fn signs_bip86_key_path() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let mnemonic = Mnemonic::parse(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        )
+        .unwrap();
+        let keys = KeySource::Master(KeyTree::from_mnemonic(&mnemonic, "").unwrap());
+        let path: DerivationPath = "m/86'/0'/0'/0/0".parse().unwrap();
+        let internal_key = keys
+            .private_key_at(&secp, Some(&(keys.fingerprint(&secp), path.clone())))
+            .unwrap()
+            .inner
+            .x_only_public_key(&secp)
+            .0;
+        assert_eq!(
+            internal_key.to_string(),
+            "cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115"
+        );
+        let (output_key, _) = internal_key.tap_tweak(&secp, None);
+        assert_eq!(
+            output_key.to_string(),
+            "a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c"
+        );
+
+        let tx: Transaction = deserialize_hex(BIP143_P2SH_P2WPKH_TX).unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(1_000_000_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(output_key),
+        });
+        psbt.inputs[0].tap_internal_key = Some(internal_key);
+        psbt.inputs[0]
+            .tap_key_origins
+            .insert(internal_key, (Vec::new(), (keys.fingerprint(&secp), path)));
+
+        let prevouts = [psbt.inputs[0].witness_utxo.clone().unwrap()];
+        let sighash = SighashCache::new(&psbt.unsigned_tx)
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(&prevouts), TapSighashType::Default)
+            .unwrap();
+        assert_eq!(
+            sighash.to_string(),
+            "37f832a6d074278bb1a8333cf36be9353531cf14ccb179a0898c9c95f82decd4"
+        );
+
+        let report = sign_approved(&mut psbt, &keys);
+        assert!(report.is_complete(), "{}", report);
+        let signature = psbt.inputs[0].tap_key_sig.unwrap();
+        assert_eq!(signature.sighash_type, TapSighashType::Default);
+        assert_eq!(
+            signature.signature.to_string(),
+            "0a31a1bd6fc94e830af82c6e4d640f378d9077c92feeb4bc8468b84f407dba878ef1b382b276f2190af82cddd5399adc7be57459eeeae0a7cf5ef8ef8f1a94a1"
+        );
+    }
+
+    /// P2TR spend with one `<key> OP_CHECKSIG` leaf per entry of `locktimes`, each prefixed by
+    /// its own CLTV so the leaves differ; `tap_merkle_root` is left out
+    fn script_path_psbt(
+        secp: &Secp256k1<secp256k1::All>,
+        key: &PrivateKey,
+        locktimes: &[i64],
+    ) -> Psbt {
+        let internal_key =
+            private_key("0202020202020202020202020202020202020202020202020202020202020202")
+                .inner
+                .x_only_public_key(secp)
+                .0;
+        let leaf_scripts: Vec<ScriptBuf> = locktimes
+            .iter()
+            .map(|locktime| {
+                bitcoin::script::Builder::new()
+                    .push_int(*locktime)
+                    .push_opcode(OP_CLTV)
+                    .push_opcode(bitcoin::opcodes::all::OP_DROP)
+                    .push_x_only_key(&key.inner.x_only_public_key(secp).0)
+                    .push_opcode(bitcoin::opcodes::all::OP_CHECKSIG)
+                    .into_script()
+            })
+            .collect();
+        let mut builder = taproot::TaprootBuilder::new();
+        let depth = if leaf_scripts.len() > 1 { 1 } else { 0 };
+        for leaf_script in &leaf_scripts {
+            builder = builder.add_leaf(depth, leaf_script.clone()).unwrap();
+        }
+        let spend_info = builder.finalize(secp, internal_key).unwrap();
+
+        let tx: Transaction = deserialize_hex(BIP143_P2SH_P2WPKH_TX).unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(1_000_000_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        });
+        psbt.inputs[0].tap_internal_key = Some(internal_key);
+        for leaf_script in leaf_scripts {
+            let leaf = (leaf_script, taproot::LeafVersion::TapScript);
+            let control_block = spend_info.control_block(&leaf).unwrap();
+            psbt.inputs[0].tap_scripts.insert(control_block, leaf);
+        }
+        psbt
+    }
+
+    /// Without `tap_merkle_root` the key path cannot match, which must not hide the script path
+    #[test]
+    fn signs_script_path_without_merkle_root() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let key = private_key("0101010101010101010101010101010101010101010101010101010101010101");
+        let mut psbt = script_path_psbt(&secp, &key, &[100]);
+
+        let report = sign_approved(&mut psbt, &KeySource::Single(key));
+        assert_eq!(report.signed, vec![0]);
+        assert_eq!(report.leaves.len(), 1);
+        assert_eq!(psbt.inputs[0].tap_script_sigs.len(), 1);
+        assert!(psbt.inputs[0].tap_key_sig.is_none());
+
+        // What `sig_example` does next: the leaf signature finalizes into a valid spend
+        finalize_psbt(&mut psbt).unwrap();
+        extract_transaction_hex(&psbt).unwrap();
+    }
+
+    /// Each leaf gets its own nonce, and only the reveal round reports signed leaves
+    #[test]
+    fn anti_exfil_rounds_per_leaf() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let key = private_key("0101010101010101010101010101010101010101010101010101010101010101");
+        let keys = KeySource::Single(key);
+        let mut psbt = script_path_psbt(&secp, &key, &[100, 200]);
+        let slots: Vec<NonceSlot> = psbt.inputs[0]
+            .tap_scripts
+            .values()
+            .map(|(script, version)| (0, Some(TapLeafHash::from_script(script, *version))))
+            .collect();
+        let host_data: BTreeMap<NonceSlot, [u8; 32]> = slots
+            .iter()
+            .zip([[1u8; 32], [2u8; 32]])
+            .map(|(slot, data)| (*slot, data))
+            .collect();
+
+        let commit = AntiExfil::Commit(
+            host_data
+                .iter()
+                .map(|(slot, data)| (*slot, anti_exfil::host_commitment(data)))
+                .collect(),
+        );
+        let options = SignOptions {
+            anti_exfil: Some(&commit),
+            ..SignOptions::default()
+        };
+        let first = sign_psbt_with(&mut psbt.clone(), &keys, &options);
+        assert_eq!(first.signed, vec![0]);
+        assert!(first.leaves.is_empty());
+        let committed: Vec<NonceSlot> = first
+            .nonce_commitments
+            .iter()
+            .map(|(slot, _)| *slot)
+            .collect();
+        assert_eq!(committed, slots);
+
+        let reveal = AntiExfil::Reveal(host_data.clone());
+        let (lcd, buttons) = approving_review();
+        let options = SignOptions {
+            anti_exfil: Some(&reveal),
+            review: Some((&lcd, &buttons)),
+            ..SignOptions::default()
+        };
+        let second = sign_psbt_with(&mut psbt, &keys, &options);
+        assert_eq!(second.leaves.len(), 2);
+        assert_eq!(second.nonce_commitments, first.nonce_commitments);
+        let (pubkey, _) = key.inner.x_only_public_key(&secp);
+        for (slot, commitment) in &first.nonce_commitments {
+            let leaf_hash = slot.1.unwrap();
+            let signature = psbt.inputs[0].tap_script_sigs[&(pubkey, leaf_hash)].signature;
+            anti_exfil::verify_schnorr_nonce(&secp, &signature, commitment, &host_data[slot])
+                .unwrap();
+        }
+    }
 }
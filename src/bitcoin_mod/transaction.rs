@@ -0,0 +1,475 @@
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGADD, OP_CHECKSIGVERIFY};
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::psbt::Input;
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Amount, Network, OutPoint, Psbt, Script, ScriptBuf, Txid, Weight};
+
+use crate::bitcoin_mod::signature::{spent_output, verify_change, KeySource};
+use crate::ui::display::LcdController;
+use crate::ui::input::Buttons;
+use crate::ui::screens::{display_transaction_info, display_tx_fee, display_tx_output};
+
+/// Weight of the outpoint, script length and sequence every input carries
+const INPUT_BASE_WEIGHT: u64 = (32 + 4 + 1 + 4) * 4;
+/// Length-prefixed signature: up to 72 bytes of DER plus the sighash byte
+const ECDSA_SIGNATURE_SIZE: u64 = 1 + 73;
+/// Witness item count, signature and length-prefixed compressed key
+const P2WPKH_WITNESS_WEIGHT: u64 = 1 + ECDSA_SIGNATURE_SIZE + 1 + 33;
+/// Witness item count and a 64-byte schnorr signature with its length
+const P2TR_KEY_WITNESS_WEIGHT: u64 = 1 + 1 + 64;
+
+/// How an input is spent, as far as its previous output and PSBT fields show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2shP2wsh,
+    P2wsh,
+    P2tr,
+    Unknown,
+}
+
+impl ScriptType {
+    pub(crate) fn of(script_pubkey: &Script, input: &Input) -> Self {
+        if script_pubkey.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script_pubkey.is_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script_pubkey.is_p2wsh() {
+            ScriptType::P2wsh
+        } else if script_pubkey.is_p2tr() {
+            ScriptType::P2tr
+        } else if script_pubkey.is_p2sh()
+            && input
+                .redeem_script
+                .as_ref()
+                .is_some_and(|script| script.is_p2wpkh())
+        {
+            ScriptType::P2shP2wpkh
+        } else if script_pubkey.is_p2sh()
+            && input
+                .redeem_script
+                .as_ref()
+                .is_some_and(|script| script.is_p2wsh())
+        {
+            ScriptType::P2shP2wsh
+        } else {
+            ScriptType::Unknown
+        }
+    }
+}
+
+impl fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2shP2wpkh => "p2sh-p2wpkh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2shP2wsh => "p2sh-p2wsh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InputSummary {
+    pub previous_output: OutPoint,
+    /// `None` when the PSBT carries neither `witness_utxo` nor `non_witness_utxo`
+    pub amount: Option<Amount>,
+    pub script_type: ScriptType,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputSummary {
+    pub amount: Amount,
+    pub script_pubkey: ScriptBuf,
+    /// `None` for scripts without an address form, such as OP_RETURN
+    pub address: Option<Address>,
+    pub is_change: bool,
+}
+
+/// Everything the user needs to judge a PSBT, in one place
+#[derive(Debug, Clone)]
+pub struct TxSummary {
+    pub network: Network,
+    pub txid: Txid,
+    pub inputs: Vec<InputSummary>,
+    pub outputs: Vec<OutputSummary>,
+    /// Only known once every input amount is
+    pub fee: Option<Amount>,
+    /// Fee over the estimated signed size, in sat/vB
+    pub fee_rate: Option<f64>,
+    pub estimated_weight: Weight,
+    pub rbf: bool,
+    pub lock_time: LockTime,
+}
+
+impl TxSummary {
+    /// Summary of `psbt`, with the single-key outputs `keys` can prove pay back to it flagged as
+    /// change; multisig change is left to `mark_change`.
+    pub fn from_psbt(psbt: &Psbt, network: Network, keys: &KeySource) -> Result<Self> {
+        let tx = &psbt.unsigned_tx;
+        if psbt.inputs.len() != tx.input.len() || psbt.outputs.len() != tx.output.len() {
+            bail!("PSBT maps do not match the unsigned transaction");
+        }
+
+        let inputs: Vec<InputSummary> = tx
+            .input
+            .iter()
+            .enumerate()
+            .map(|(index, txin)| {
+                let utxo = spent_output(psbt, index);
+                InputSummary {
+                    previous_output: txin.previous_output,
+                    amount: utxo.as_ref().map(|utxo| utxo.value),
+                    script_type: utxo.map_or(ScriptType::Unknown, |utxo| {
+                        ScriptType::of(&utxo.script_pubkey, &psbt.inputs[index])
+                    }),
+                }
+            })
+            .collect();
+
+        let (change, _) = verify_change(&Secp256k1::new(), psbt, keys);
+        let outputs: Vec<OutputSummary> = tx
+            .output
+            .iter()
+            .enumerate()
+            .map(|(index, txout)| OutputSummary {
+                amount: txout.value,
+                script_pubkey: txout.script_pubkey.clone(),
+                address: Address::from_script(&txout.script_pubkey, network).ok(),
+                is_change: change.contains(&index),
+            })
+            .collect();
+
+        // Amounts come from the host, so their sums must not be trusted to fit in a u64
+        let total_in = inputs
+            .iter()
+            .try_fold(Some(Amount::ZERO), |total, input| {
+                match (total, input.amount) {
+                    (Some(total), Some(amount)) => total.checked_add(amount).map(Some),
+                    _ => Some(None),
+                }
+            })
+            .ok_or_else(|| anyhow!("input amounts overflow"))?;
+        let total_out = outputs
+            .iter()
+            .try_fold(Amount::ZERO, |total, output| {
+                total.checked_add(output.amount)
+            })
+            .ok_or_else(|| anyhow!("output amounts overflow"))?;
+        let fee = match total_in {
+            Some(total_in) if total_in < total_out => bail!("outputs spend more than the inputs"),
+            Some(total_in) => Some(total_in - total_out),
+            None => None,
+        };
+
+        let estimated_weight = estimate_signed_weight(psbt, &inputs);
+        let fee_rate = fee.map(|fee| fee.to_sat() as f64 * 4.0 / estimated_weight.to_wu() as f64);
+
+        Ok(TxSummary {
+            network,
+            txid: tx.compute_txid(),
+            inputs,
+            outputs,
+            fee,
+            fee_rate,
+            estimated_weight,
+            rbf: tx.is_explicitly_rbf(),
+            lock_time: tx.lock_time,
+        })
+    }
+
+    /// Flag the outputs at `indices` as coming back to this device, such as verified multisig
+    /// change
+    pub fn mark_change(&mut self, indices: &[usize]) {
+        for &index in indices {
+            if let Some(output) = self.outputs.get_mut(index) {
+                output.is_change = true;
+            }
+        }
+    }
+
+    /// Amount leaving the wallet, change excluded
+    pub fn spend_amount(&self) -> Amount {
+        self.outputs
+            .iter()
+            .filter(|output| !output.is_change)
+            .map(|output| output.amount)
+            .sum()
+    }
+}
+
+/// Walk the user through every payment, the fee and the total; fails unless they approve.
+///
+/// Change outputs are only counted on the fee screen, so whatever is shown one by one leaves
+/// the wallet.
+pub fn review_transaction(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    summary: &TxSummary,
+) -> Result<()> {
+    let payments: Vec<&OutputSummary> = summary
+        .outputs
+        .iter()
+        .filter(|output| !output.is_change)
+        .collect();
+    for (index, output) in payments.iter().enumerate() {
+        display_tx_output(lcd, index, payments.len(), output);
+        buttons.wait_for_press();
+    }
+    display_tx_fee(lcd, summary);
+    buttons.wait_for_press();
+
+    display_transaction_info(
+        lcd,
+        &summary.txid.to_string(),
+        &summary.spend_amount().to_string(),
+    );
+    if !buttons.confirm() {
+        bail!("Transaction rejected on device");
+    }
+    Ok(())
+}
+
+impl fmt::Display for TxSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "network {}", self.network)?;
+        for (index, input) in self.inputs.iter().enumerate() {
+            write!(
+                f,
+                "input {}: {} {}",
+                index, input.previous_output, input.script_type
+            )?;
+            match input.amount {
+                Some(amount) => writeln!(f, " {}", amount)?,
+                None => writeln!(f, " unknown amount")?,
+            }
+        }
+        for (index, output) in self.outputs.iter().enumerate() {
+            match &output.address {
+                Some(address) => write!(f, "output {}: {} {}", index, address, output.amount)?,
+                None => write!(
+                    f,
+                    "output {}: {} {}",
+                    index, output.script_pubkey, output.amount
+                )?,
+            }
+            writeln!(f, "{}", if output.is_change { " (change)" } else { "" })?;
+        }
+        match (self.fee, self.fee_rate) {
+            (Some(fee), Some(fee_rate)) => writeln!(f, "fee {} ({:.1} sat/vB)", fee, fee_rate)?,
+            _ => writeln!(f, "fee unknown")?,
+        }
+        write!(
+            f,
+            "rbf {}, locktime {}",
+            if self.rbf { "yes" } else { "no" },
+            self.lock_time
+        )
+    }
+}
+
+/// Signatures a script needs, counted from its CHECKSIG family opcodes or the multisig threshold
+fn signatures_needed(script: &Script) -> u64 {
+    let instructions: Vec<_> = script.instructions().filter_map(|ins| ins.ok()).collect();
+    if let Some(Instruction::Op(op)) = instructions.first() {
+        if let Class::PushNum(threshold) = op.classify(ClassifyContext::Legacy) {
+            if script.is_multisig() {
+                return threshold as u64;
+            }
+        }
+    }
+    instructions
+        .iter()
+        .filter(|ins| {
+            matches!(ins, Instruction::Op(op)
+                if *op == OP_CHECKSIG || *op == OP_CHECKSIGVERIFY || *op == OP_CHECKSIGADD)
+        })
+        .count()
+        .max(1) as u64
+}
+
+/// Witness weight of a P2WSH multisig spend, or a single-key guess without the witness script
+fn p2wsh_witness_weight(input: &Input) -> u64 {
+    match &input.witness_script {
+        Some(script) => {
+            let script_len = script.len() as u64;
+            // Item count, the CHECKMULTISIG dummy, signatures and the script itself
+            1 + 1
+                + signatures_needed(script) * ECDSA_SIGNATURE_SIZE
+                + VarInt(script_len).size() as u64
+                + script_len
+        }
+        None => P2WPKH_WITNESS_WEIGHT,
+    }
+}
+
+/// Witness weight of the cheapest tapscript leaf the PSBT offers
+fn tapscript_witness_weight(input: &Input) -> Option<u64> {
+    input
+        .tap_scripts
+        .iter()
+        .map(|(control_block, (script, _))| {
+            let control_block_len = control_block.size() as u64;
+            let script_len = script.len() as u64;
+            1 + signatures_needed(script) * 65
+                + VarInt(script_len).size() as u64
+                + script_len
+                + VarInt(control_block_len).size() as u64
+                + control_block_len
+        })
+        .min()
+}
+
+/// Weight of the transaction once every input carries its signatures
+fn estimate_signed_weight(psbt: &Psbt, inputs: &[InputSummary]) -> Weight {
+    let tx = &psbt.unsigned_tx;
+    let mut weight = (4 + 4) * 4
+        + (VarInt(tx.input.len() as u64).size() + VarInt(tx.output.len() as u64).size()) as u64 * 4;
+    let mut segwit = false;
+
+    for (input, summary) in psbt.inputs.iter().zip(inputs) {
+        weight += INPUT_BASE_WEIGHT;
+        weight += match summary.script_type {
+            // Signature and length-prefixed compressed key in the scriptSig
+            ScriptType::P2pkh => (ECDSA_SIGNATURE_SIZE + 1 + 33) * 4,
+            // The scriptSig pushes the 22-byte witness program
+            ScriptType::P2shP2wpkh => 23 * 4 + P2WPKH_WITNESS_WEIGHT,
+            ScriptType::P2wpkh => P2WPKH_WITNESS_WEIGHT,
+            // The scriptSig pushes the 34-byte witness program
+            ScriptType::P2shP2wsh => 35 * 4 + p2wsh_witness_weight(input),
+            ScriptType::P2wsh => p2wsh_witness_weight(input),
+            ScriptType::P2tr
+                if input.tap_internal_key.is_some() || input.tap_scripts.is_empty() =>
+            {
+                P2TR_KEY_WITNESS_WEIGHT
+            }
+            ScriptType::P2tr => tapscript_witness_weight(input).unwrap_or(P2TR_KEY_WITNESS_WEIGHT),
+            // Nothing to go on; leaving it out keeps the fee rate an upper bound
+            ScriptType::Unknown => 0,
+        };
+        segwit |= !matches!(summary.script_type, ScriptType::P2pkh | ScriptType::Unknown);
+    }
+
+    for output in &tx.output {
+        let script_len = output.script_pubkey.len() as u64;
+        weight += (8 + VarInt(script_len).size() as u64 + script_len) * 4;
+    }
+    // Segwit marker and flag, plus the empty witness count of every legacy input
+    if segwit {
+        weight += 2 + inputs
+            .iter()
+            .filter(|input| matches!(input.script_type, ScriptType::P2pkh | ScriptType::Unknown))
+            .count() as u64;
+    }
+    Weight::from_wu(weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::bip32::DerivationPath;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::{
+        transaction, CompressedPublicKey, NetworkKind, PrivateKey, Transaction, TxIn, TxOut,
+        WPubkeyHash,
+    };
+
+    fn keys() -> KeySource {
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        KeySource::Single(PrivateKey::new(secret_key, NetworkKind::Main))
+    }
+
+    fn psbt_with_amounts(inputs: &[u64], outputs: &[u64]) -> Psbt {
+        let script_pubkey = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20]));
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs.iter().map(|_| TxIn::default()).collect(),
+            output: outputs
+                .iter()
+                .map(|amount| TxOut {
+                    value: Amount::from_sat(*amount),
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        for (input, amount) in psbt.inputs.iter_mut().zip(inputs) {
+            input.witness_utxo = Some(TxOut {
+                value: Amount::from_sat(*amount),
+                script_pubkey: script_pubkey.clone(),
+            });
+        }
+        psbt
+    }
+
+    #[test]
+    fn fee_from_amounts() {
+        let psbt = psbt_with_amounts(&[60_000, 50_000], &[100_000]);
+        let summary = TxSummary::from_psbt(&psbt, Network::Bitcoin, &keys()).unwrap();
+        assert_eq!(summary.fee, Some(Amount::from_sat(10_000)));
+    }
+
+    #[test]
+    fn rejects_overflowing_amounts() {
+        let half = u64::MAX / 2 + 1;
+        let psbt = psbt_with_amounts(&[half, half], &[1]);
+        assert!(TxSummary::from_psbt(&psbt, Network::Bitcoin, &keys()).is_err());
+        let psbt = psbt_with_amounts(&[1], &[half, half]);
+        assert!(TxSummary::from_psbt(&psbt, Network::Bitcoin, &keys()).is_err());
+    }
+
+    #[test]
+    fn estimates_weight_with_the_largest_signature() {
+        let psbt = psbt_with_amounts(&[100_000], &[90_000]);
+        let summary = TxSummary::from_psbt(&psbt, Network::Bitcoin, &keys()).unwrap();
+        // 40 of version, locktime and counts, 164 + 109 for the input and its witness with a
+        // 73-byte signature, 124 for the output, 2 for the segwit marker and flag
+        assert_eq!(summary.estimated_weight, Weight::from_wu(439));
+    }
+
+    #[test]
+    fn detects_change_paying_to_the_signing_key() {
+        let secp = Secp256k1::new();
+        let keys = keys();
+        let KeySource::Single(key) = &keys else {
+            unreachable!()
+        };
+        let pubkey = CompressedPublicKey::from_private_key(&secp, key).unwrap();
+        let own_script = ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash());
+
+        let mut psbt = psbt_with_amounts(&[100_000], &[60_000, 30_000, 5_000]);
+        psbt.inputs[0].witness_utxo.as_mut().unwrap().script_pubkey = own_script.clone();
+        psbt.unsigned_tx.output[0].script_pubkey = own_script;
+        let origin = (
+            keys.fingerprint(&secp),
+            "m/0".parse::<DerivationPath>().unwrap(),
+        );
+        // Output 1 claims the same key but pays someone else
+        for output in &mut psbt.outputs[..2] {
+            output.bip32_derivation.insert(pubkey.0, origin.clone());
+        }
+
+        let summary = TxSummary::from_psbt(&psbt, Network::Bitcoin, &keys).unwrap();
+        let change: Vec<bool> = summary
+            .outputs
+            .iter()
+            .map(|output| output.is_change)
+            .collect();
+        assert_eq!(change, vec![true, false, false]);
+        assert_eq!(summary.spend_amount(), Amount::from_sat(35_000));
+    }
+}
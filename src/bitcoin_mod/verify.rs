@@ -0,0 +1,722 @@
+use anyhow::{anyhow, bail, Result};
+use bitcoin::hashes::Hash;
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGADD, OP_CLTV, OP_CSV, OP_DROP, OP_NUMEQUAL};
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::{Message, Secp256k1, Verification};
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TapLeafHash};
+use bitcoin::{
+    absolute, ecdsa, relative, taproot, transaction, CompressedPublicKey, Psbt, PublicKey, Script,
+    ScriptBuf, Transaction, TxOut, WScriptHash,
+};
+
+use crate::bitcoin_mod::signature::spent_output;
+
+/// Pushes of a script sig, which must be push-only
+fn script_sig_pushes(script_sig: &Script) -> Result<Vec<&[u8]>> {
+    script_sig
+        .instructions()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => Ok(bytes.as_bytes()),
+            Ok(Instruction::Op(_)) => bail!("script sig is not push-only"),
+            Err(err) => bail!("malformed script sig: {}", err),
+        })
+        .collect()
+}
+
+/// Timelock a tapscript leaf checks before its signatures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LeafTimelock {
+    /// `<n> OP_CHECKLOCKTIMEVERIFY OP_DROP`
+    After(absolute::LockTime),
+    /// `<n> OP_CHECKSEQUENCEVERIFY OP_DROP`
+    Older(relative::LockTime),
+}
+
+/// Tapscript leaf we know how to satisfy: `<key> OP_CHECKSIG`, or `multi_a` as in
+/// `<key> OP_CHECKSIG <key> OP_CHECKSIGADD ... <k> OP_NUMEQUAL`, either behind an optional timelock
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TapLeafTemplate {
+    pub timelock: Option<LeafTimelock>,
+    pub keys: Vec<XOnlyPublicKey>,
+    pub threshold: usize,
+}
+
+impl TapLeafTemplate {
+    pub fn parse(script: &Script) -> Option<Self> {
+        let instructions: Vec<Instruction> =
+            script.instructions().collect::<Result<_, _>>().ok()?;
+        let is_op = |instruction: &Instruction, expected| matches!(instruction, Instruction::Op(op) if *op == expected);
+        let key = |instruction: &Instruction| {
+            let bytes = instruction.push_bytes()?;
+            XOnlyPublicKey::from_slice(bytes.as_bytes()).ok()
+        };
+
+        let mut rest = &instructions[..];
+        let mut timelock = None;
+        if let [number, op, drop, tail @ ..] = rest {
+            if is_op(drop, OP_DROP) && (is_op(op, OP_CLTV) || is_op(op, OP_CSV)) {
+                let number = u32::try_from(number.script_num()?).ok()?;
+                timelock = Some(if is_op(op, OP_CLTV) {
+                    LeafTimelock::After(absolute::LockTime::from_consensus(number))
+                } else {
+                    LeafTimelock::Older(relative::LockTime::from_consensus(number).ok()?)
+                });
+                rest = tail;
+            }
+        }
+
+        let (keys, threshold) = match rest {
+            [first, checksig] if is_op(checksig, OP_CHECKSIG) => (vec![key(first)?], 1),
+            [first, checksig, adds @ .., threshold, numequal]
+                if is_op(checksig, OP_CHECKSIG) && is_op(numequal, OP_NUMEQUAL) =>
+            {
+                let mut keys = vec![key(first)?];
+                let pairs = adds.chunks_exact(2);
+                if !pairs.remainder().is_empty() {
+                    return None;
+                }
+                for pair in pairs {
+                    if !is_op(&pair[1], OP_CHECKSIGADD) {
+                        return None;
+                    }
+                    keys.push(key(&pair[0])?);
+                }
+                let threshold = usize::try_from(threshold.script_num()?).ok()?;
+                (keys, threshold)
+            }
+            _ => return None,
+        };
+        if threshold == 0 || threshold > keys.len() {
+            return None;
+        }
+        Some(TapLeafTemplate {
+            timelock,
+            keys,
+            threshold,
+        })
+    }
+}
+
+/// Checks the templates the signer produces, with the same rules consensus applies to them.
+///
+/// This is not a general script interpreter: an input that is not one of the templates below
+/// fails verification rather than being waved through.
+struct Verifier<'a, C: Verification> {
+    secp: Secp256k1<C>,
+    tx: &'a Transaction,
+    cache: SighashCache<&'a Transaction>,
+    prevouts: &'a [TxOut],
+}
+
+impl<C: Verification> Verifier<'_, C> {
+    fn check_ecdsa(
+        &self,
+        message: Message,
+        signature: &ecdsa::Signature,
+        pubkey: &PublicKey,
+    ) -> Result<()> {
+        self.secp
+            .verify_ecdsa(&message, &signature.signature, &pubkey.inner)
+            .map_err(|_| anyhow!("invalid signature"))
+    }
+
+    fn verify_p2pkh(&mut self, index: usize, script_pubkey: &Script) -> Result<()> {
+        let tx = self.tx;
+        let pushes = script_sig_pushes(&tx.input[index].script_sig)?;
+        let [signature, pubkey] = pushes[..] else {
+            bail!("expected a signature and a key in the script sig");
+        };
+        let pubkey = PublicKey::from_slice(pubkey)?;
+        if ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()) != *script_pubkey {
+            bail!("key does not match the previous output");
+        }
+        let signature = ecdsa::Signature::from_slice(signature)?;
+        let sighash = self.cache.legacy_signature_hash(
+            index,
+            script_pubkey,
+            signature.sighash_type.to_u32(),
+        )?;
+        self.check_ecdsa(Message::from(sighash), &signature, &pubkey)
+    }
+
+    fn verify_p2wpkh(
+        &mut self,
+        index: usize,
+        program: &Script,
+        value: bitcoin::Amount,
+    ) -> Result<()> {
+        let tx = self.tx;
+        let witness = &tx.input[index].witness;
+        if witness.len() != 2 {
+            bail!("expected a signature and a key in the witness");
+        }
+        let signature = ecdsa::Signature::from_slice(&witness[0])?;
+        // Segwit v0 only accepts compressed keys
+        let pubkey = CompressedPublicKey::from_slice(&witness[1])?;
+        if ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()) != *program {
+            bail!("key does not match the witness program");
+        }
+        let sighash =
+            self.cache
+                .p2wpkh_signature_hash(index, program, value, signature.sighash_type)?;
+        self.check_ecdsa(Message::from(sighash), &signature, &PublicKey::from(pubkey))
+    }
+
+    fn verify_p2sh(
+        &mut self,
+        index: usize,
+        script_pubkey: &Script,
+        value: bitcoin::Amount,
+    ) -> Result<()> {
+        let tx = self.tx;
+        let pushes = script_sig_pushes(&tx.input[index].script_sig)?;
+        let [redeem_script] = pushes[..] else {
+            bail!("expected only the redeem script in the script sig");
+        };
+        let redeem_script = ScriptBuf::from_bytes(redeem_script.to_vec());
+        if ScriptBuf::new_p2sh(&redeem_script.script_hash()) != *script_pubkey {
+            bail!("redeem script does not match the previous output");
+        }
+        if !redeem_script.is_p2wpkh() {
+            bail!("only P2SH-P2WPKH is supported");
+        }
+        self.verify_p2wpkh(index, &redeem_script, value)
+    }
+
+    fn verify_p2wsh(
+        &mut self,
+        index: usize,
+        script_pubkey: &Script,
+        value: bitcoin::Amount,
+    ) -> Result<()> {
+        let tx = self.tx;
+        let witness = &tx.input[index].witness;
+        let Some(witness_script) = witness.last() else {
+            bail!("empty witness");
+        };
+        let witness_script = ScriptBuf::from_bytes(witness_script.to_vec());
+        if ScriptBuf::new_p2wsh(&WScriptHash::hash(witness_script.as_bytes())) != *script_pubkey {
+            bail!("witness script does not match the previous output");
+        }
+        if !witness_script.is_multisig() {
+            bail!("only multisig witness scripts are supported");
+        }
+
+        let mut threshold = None;
+        let mut pubkeys = Vec::new();
+        for instruction in witness_script.instructions() {
+            match instruction? {
+                Instruction::PushBytes(bytes) => {
+                    pubkeys.push(PublicKey::from_slice(bytes.as_bytes())?)
+                }
+                Instruction::Op(op) => {
+                    if let Class::PushNum(number) = op.classify(ClassifyContext::Legacy) {
+                        threshold.get_or_insert(number as usize);
+                    }
+                }
+            }
+        }
+        let threshold = threshold.ok_or_else(|| anyhow!("multisig without threshold"))?;
+
+        // Dummy element, then one signature per required key, then the script
+        let items: Vec<&[u8]> = witness.iter().collect();
+        if items.len() != threshold + 2 || !items[0].is_empty() {
+            bail!("expected an empty dummy and {} signatures", threshold);
+        }
+        // CHECKMULTISIG walks keys and signatures in order; a key that fails is skipped for good
+        let mut keys = pubkeys.iter();
+        for signature in &items[1..=threshold] {
+            let signature = ecdsa::Signature::from_slice(signature)?;
+            let sighash = self.cache.p2wsh_signature_hash(
+                index,
+                &witness_script,
+                value,
+                signature.sighash_type,
+            )?;
+            let message = Message::from(sighash);
+            let matched = keys.any(|pubkey| self.check_ecdsa(message, &signature, pubkey).is_ok());
+            if !matched {
+                bail!("signature does not match any remaining key");
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_p2tr(&mut self, index: usize, script_pubkey: &Script) -> Result<()> {
+        let tx = self.tx;
+        let items: Vec<&[u8]> = tx.input[index].witness.iter().collect();
+        let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])?;
+        match items[..] {
+            [] => bail!("empty witness"),
+            [signature] => self.verify_p2tr_key_path(index, output_key, signature),
+            [.., last] if last.first() == Some(&0x50) => bail!("annex is not supported"),
+            [ref stack @ .., script, control_block] => {
+                self.verify_p2tr_script_path(index, output_key, stack, script, control_block)
+            }
+        }
+    }
+
+    fn verify_p2tr_key_path(
+        &mut self,
+        index: usize,
+        output_key: XOnlyPublicKey,
+        signature: &[u8],
+    ) -> Result<()> {
+        let signature = taproot::Signature::from_slice(signature)?;
+        let sighash = self.cache.taproot_key_spend_signature_hash(
+            index,
+            &Prevouts::All(self.prevouts),
+            signature.sighash_type,
+        )?;
+        self.secp
+            .verify_schnorr(&signature.signature, &Message::from(sighash), &output_key)
+            .map_err(|_| anyhow!("invalid signature"))
+    }
+
+    fn verify_p2tr_script_path(
+        &mut self,
+        index: usize,
+        output_key: XOnlyPublicKey,
+        stack: &[&[u8]],
+        script: &[u8],
+        control_block: &[u8],
+    ) -> Result<()> {
+        let control_block = ControlBlock::decode(control_block)?;
+        if control_block.leaf_version != LeafVersion::TapScript {
+            bail!("unknown leaf version");
+        }
+        let script = ScriptBuf::from_bytes(script.to_vec());
+        if !control_block.verify_taproot_commitment(&self.secp, output_key, &script) {
+            bail!("leaf is not committed to by the previous output");
+        }
+        let template = TapLeafTemplate::parse(&script)
+            .ok_or_else(|| anyhow!("only single-key and multi_a leaves are supported"))?;
+        if stack.len() != template.keys.len() {
+            bail!("expected {} signature slots", template.keys.len());
+        }
+
+        let tx = self.tx;
+        let txin = &tx.input[index];
+        match template.timelock {
+            Some(LeafTimelock::After(lock_time))
+                if !txin.sequence.enables_absolute_lock_time()
+                    || !lock_time.is_implied_by(tx.lock_time) =>
+            {
+                bail!("absolute timelock {} not reached", lock_time);
+            }
+            Some(LeafTimelock::Older(lock_time))
+                if tx.version < transaction::Version::TWO
+                    || !lock_time.is_implied_by_sequence(txin.sequence) =>
+            {
+                bail!("relative timelock {} not reached", lock_time);
+            }
+            _ => {}
+        }
+
+        let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        // The first key checks the top of the stack, so signatures come in reverse key order
+        let mut signed = 0;
+        for (pubkey, signature) in template.keys.iter().zip(stack.iter().rev()) {
+            // An empty signature is a valid "no" from that key
+            if signature.is_empty() {
+                continue;
+            }
+            let signature = taproot::Signature::from_slice(signature)?;
+            let sighash = self.cache.taproot_script_spend_signature_hash(
+                index,
+                &Prevouts::All(self.prevouts),
+                leaf_hash,
+                signature.sighash_type,
+            )?;
+            self.secp
+                .verify_schnorr(&signature.signature, &Message::from(sighash), pubkey)
+                .map_err(|_| anyhow!("invalid signature"))?;
+            signed += 1;
+        }
+        if signed != template.threshold {
+            bail!(
+                "{} signatures where the leaf needs {}",
+                signed,
+                template.threshold
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Run script verification for every input of `tx` against the outputs it spends
+pub fn verify_transaction(tx: &Transaction, prevouts: &[TxOut]) -> Result<()> {
+    if prevouts.len() != tx.input.len() {
+        bail!(
+            "{} previous outputs for {} inputs",
+            prevouts.len(),
+            tx.input.len()
+        );
+    }
+    let mut verifier = Verifier {
+        secp: Secp256k1::verification_only(),
+        tx,
+        cache: SighashCache::new(tx),
+        prevouts,
+    };
+
+    for (index, prevout) in prevouts.iter().enumerate() {
+        let script_pubkey = &prevout.script_pubkey;
+        let is_segwit = script_pubkey.is_witness_program();
+        if is_segwit && !tx.input[index].script_sig.is_empty() {
+            bail!("input {}: native segwit input with a script sig", index);
+        }
+        // P2SH leaves it to the redeem script, which only accepts nested segwit
+        if !is_segwit && !script_pubkey.is_p2sh() && !tx.input[index].witness.is_empty() {
+            bail!("input {}: legacy input with a witness", index);
+        }
+        let result = if script_pubkey.is_p2pkh() {
+            verifier.verify_p2pkh(index, script_pubkey)
+        } else if script_pubkey.is_p2sh() {
+            verifier.verify_p2sh(index, script_pubkey, prevout.value)
+        } else if script_pubkey.is_p2wpkh() {
+            verifier.verify_p2wpkh(index, script_pubkey, prevout.value)
+        } else if script_pubkey.is_p2wsh() {
+            verifier.verify_p2wsh(index, script_pubkey, prevout.value)
+        } else if script_pubkey.is_p2tr() {
+            verifier.verify_p2tr(index, script_pubkey)
+        } else {
+            Err(anyhow!("unsupported script type"))
+        };
+        result.map_err(|err| anyhow!("input {}: {}", index, err))?;
+    }
+    Ok(())
+}
+
+/// Verify the transaction a finalized PSBT would broadcast
+pub fn verify_finalized_psbt(psbt: &Psbt, tx: &Transaction) -> Result<()> {
+    let prevouts = (0..psbt.inputs.len())
+        .map(|index| {
+            spent_output(psbt, index)
+                .ok_or_else(|| anyhow!("input {}: missing previous output", index))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    verify_transaction(tx, &prevouts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin_mod::finalize::{extract_transaction, finalize_psbt};
+    use crate::bitcoin_mod::signature::{sign_psbt_with, KeySource, SignOptions, SAMPLE_PSBT};
+    use crate::security::pin;
+    use crate::ui::approving_review;
+    use bitcoin::consensus::encode::deserialize_hex;
+    use bitcoin::key::{Keypair, TapTweak};
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::sighash::{EcdsaSighashType, TapSighashType};
+    use bitcoin::{absolute, transaction, Amount, NetworkKind, PrivateKey, TxIn};
+
+    /// Signed native P2WPKH example from BIP-143; its first input is a P2PK
+    const BIP143_P2WPKH_SIGNED: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+    /// Spend of the P2WPKH output above alone, with the key BIP-143 gives for it and the
+    /// example's outputs and locktime; sighash and signature computed outside rust-bitcoin
+    const BIP143_P2WPKH_SPEND: &str = "01000000000101ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac02483045022100f66048e2327d4eb2fad2266d279cbf5611f59d22da9bb0f5959897eeea0875cc02202e7bd6cb23fffe1a0b1db10cc223025dc61f267a650b12d2c88e08ee143f4d0c0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+    /// Signed P2SH-P2WPKH example from BIP-143
+    const BIP143_P2SH_P2WPKH_SIGNED: &str = "01000000000101db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a5477010000001716001479091972186c449eb1ded22b78e40d009bdf0089feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac02473044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f0220217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb012103ad1d8e89212f0b92c74d23bb710c00662ad1470198ac48c43f7d6f93a2a2687392040000";
+
+    fn txout(sats: u64, hex_script: &str) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: ScriptBuf::from_hex(hex_script).unwrap(),
+        }
+    }
+
+    fn secret_key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    /// One-input PSBT spending `prevout`, paying most of it back to the same script
+    fn spend(prevout: TxOut) -> Psbt {
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: prevout.value - Amount::from_sat(1_000),
+                script_pubkey: prevout.script_pubkey.clone(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(prevout);
+        psbt
+    }
+
+    #[test]
+    fn verifies_bip143_p2wpkh() {
+        let prevout = txout(600_000_000, "00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1");
+        let tx: Transaction = deserialize_hex(BIP143_P2WPKH_SPEND).unwrap();
+        verify_transaction(&tx, std::slice::from_ref(&prevout)).unwrap();
+
+        // The signed example itself: its first input is a bare P2PK, no template of ours, so
+        // only its P2WPKH input is checked
+        let tx: Transaction = deserialize_hex(BIP143_P2WPKH_SIGNED).unwrap();
+        let prevouts = [
+            txout(
+                625_000_000,
+                "2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac",
+            ),
+            prevout,
+        ];
+        let mut verifier = Verifier {
+            secp: Secp256k1::verification_only(),
+            tx: &tx,
+            cache: SighashCache::new(&tx),
+            prevouts: &prevouts,
+        };
+        let prevout = &prevouts[1];
+        verifier
+            .verify_p2wpkh(1, &prevout.script_pubkey, prevout.value)
+            .unwrap();
+    }
+
+    #[test]
+    fn verifies_bip143_p2sh_p2wpkh() {
+        let tx: Transaction = deserialize_hex(BIP143_P2SH_P2WPKH_SIGNED).unwrap();
+        let prevouts = [txout(
+            1_000_000_000,
+            "a9144733f37cf4db86fbc2efed2500b4f4e49f31202387",
+        )];
+        verify_transaction(&tx, &prevouts).unwrap();
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let mut tx: Transaction = deserialize_hex(BIP143_P2SH_P2WPKH_SIGNED).unwrap();
+        let prevouts = [txout(
+            1_000_000_000,
+            "a9144733f37cf4db86fbc2efed2500b4f4e49f31202387",
+        )];
+        let mut items: Vec<Vec<u8>> = tx.input[0].witness.to_vec();
+        // Last byte of `s`, just before the sighash type, so the encoding stays valid
+        let len = items[0].len();
+        items[0][len - 2] ^= 1;
+        tx.input[0].witness = items.into();
+        let err = verify_transaction(&tx, &prevouts).unwrap_err();
+        assert!(err.to_string().contains("invalid signature"), "{}", err);
+    }
+
+    /// The BIP-174 sample from `sig_example`, its P2PKH input re-keyed to a key we hold
+    #[test]
+    fn signs_and_verifies_sample_psbt() {
+        pin::unlock_for_tests();
+        let secp = Secp256k1::new();
+        let key = PrivateKey::new(secret_key(1), NetworkKind::Main);
+        let mut psbt = Psbt::deserialize(&hex::decode(SAMPLE_PSBT).unwrap()).unwrap();
+        let prev_tx = psbt.inputs[0].non_witness_utxo.as_mut().unwrap();
+        let vout = psbt.unsigned_tx.input[0].previous_output.vout as usize;
+        prev_tx.output[vout].script_pubkey =
+            ScriptBuf::new_p2pkh(&key.public_key(&secp).pubkey_hash());
+        psbt.unsigned_tx.input[0].previous_output.txid = prev_tx.compute_txid();
+
+        let (lcd, buttons) = approving_review();
+        let options = SignOptions {
+            review: Some((&lcd, &buttons)),
+            ..SignOptions::default()
+        };
+        let report = sign_psbt_with(&mut psbt, &KeySource::Single(key), &options);
+        assert!(report.is_complete(), "{}", report);
+        finalize_psbt(&mut psbt).unwrap();
+        let tx = extract_transaction(&psbt).unwrap();
+        verify_finalized_psbt(&psbt, &tx).unwrap();
+
+        // Legacy inputs carry no witness
+        let mut padded = tx.clone();
+        padded.input[0].witness.push([1]);
+        let err = verify_finalized_psbt(&psbt, &padded).unwrap_err();
+        assert!(
+            err.to_string().contains("legacy input with a witness"),
+            "{}",
+            err
+        );
+
+        // The same signature over another key's output does not verify
+        let mut stolen = psbt.clone();
+        let prev_tx = stolen.inputs[0].non_witness_utxo.as_mut().unwrap();
+        prev_tx.output[vout].script_pubkey =
+            ScriptBuf::new_p2pkh(&PublicKey::new(secret_key(2).public_key(&secp)).pubkey_hash());
+        assert!(verify_finalized_psbt(&stolen, &tx).is_err());
+    }
+
+    #[test]
+    fn finalizes_and_verifies_p2wsh_multisig() {
+        let secp = Secp256k1::new();
+        let secret_keys = [secret_key(1), secret_key(2)];
+        let pubkeys: Vec<PublicKey> = secret_keys
+            .iter()
+            .map(|secret_key| PublicKey::new(secret_key.public_key(&secp)))
+            .collect();
+        let witness_script = bitcoin::script::Builder::new()
+            .push_int(2)
+            .push_key(&pubkeys[0])
+            .push_key(&pubkeys[1])
+            .push_int(2)
+            .push_opcode(bitcoin::opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2wsh(&witness_script.wscript_hash()),
+        };
+        let mut psbt = spend(prevout.clone());
+        psbt.inputs[0].witness_script = Some(witness_script.clone());
+
+        let sighash = SighashCache::new(&psbt.unsigned_tx)
+            .p2wsh_signature_hash(0, &witness_script, prevout.value, EcdsaSighashType::All)
+            .unwrap();
+        for (secret_key, pubkey) in secret_keys.iter().zip(&pubkeys) {
+            let signature = secp.sign_ecdsa(&Message::from(sighash), secret_key);
+            psbt.inputs[0]
+                .partial_sigs
+                .insert(*pubkey, ecdsa::Signature::sighash_all(signature));
+        }
+        finalize_psbt(&mut psbt).unwrap();
+        let tx = extract_transaction(&psbt).unwrap();
+        verify_finalized_psbt(&psbt, &tx).unwrap();
+    }
+
+    #[test]
+    fn finalizes_and_verifies_p2tr_key_path() {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &secret_key(1));
+        let (internal_key, _) = keypair.x_only_public_key();
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2tr(&secp, internal_key, None),
+        };
+        let mut psbt = spend(prevout.clone());
+
+        let sighash = SighashCache::new(&psbt.unsigned_tx)
+            .taproot_key_spend_signature_hash(
+                0,
+                &Prevouts::All(&[prevout]),
+                TapSighashType::Default,
+            )
+            .unwrap();
+        let tweaked = Keypair::from(keypair.tap_tweak(&secp, None));
+        let signature = secp.sign_schnorr_no_aux_rand(&Message::from(sighash), &tweaked);
+        psbt.inputs[0].tap_key_sig = Some(taproot::Signature {
+            signature,
+            sighash_type: TapSighashType::Default,
+        });
+        finalize_psbt(&mut psbt).unwrap();
+        let tx = extract_transaction(&psbt).unwrap();
+        verify_finalized_psbt(&psbt, &tx).unwrap();
+    }
+
+    /// Output with a single leaf, returned with the control block that spends through it
+    fn tap_leaf_output(leaf_script: &ScriptBuf) -> (TxOut, ControlBlock) {
+        let secp = Secp256k1::new();
+        let (internal_key, _) = Keypair::from_secret_key(&secp, &secret_key(9)).x_only_public_key();
+        let spend_info = taproot::TaprootBuilder::new()
+            .add_leaf(0, leaf_script.clone())
+            .unwrap()
+            .finalize(&secp, internal_key)
+            .unwrap();
+        let control_block = spend_info
+            .control_block(&(leaf_script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        };
+        (prevout, control_block)
+    }
+
+    /// Add script-path signatures by the keys of `signers` to the only input of `psbt`
+    fn sign_leaf(psbt: &mut Psbt, prevout: &TxOut, leaf_script: &ScriptBuf, signers: &[u8]) {
+        let secp = Secp256k1::new();
+        let leaf_hash = TapLeafHash::from_script(leaf_script, LeafVersion::TapScript);
+        let sighash = SighashCache::new(&psbt.unsigned_tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(std::slice::from_ref(prevout)),
+                leaf_hash,
+                TapSighashType::Default,
+            )
+            .unwrap();
+        for signer in signers {
+            let keypair = Keypair::from_secret_key(&secp, &secret_key(*signer));
+            let signature = secp.sign_schnorr_no_aux_rand(&Message::from(sighash), &keypair);
+            psbt.inputs[0].tap_script_sigs.insert(
+                (keypair.x_only_public_key().0, leaf_hash),
+                taproot::Signature {
+                    signature,
+                    sighash_type: TapSighashType::Default,
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn finalizes_and_verifies_p2tr_multi_a() {
+        let secp = Secp256k1::new();
+        let x_only = |byte| secret_key(byte).x_only_public_key(&secp).0;
+        let leaf_script = bitcoin::script::Builder::new()
+            .push_x_only_key(&x_only(1))
+            .push_opcode(OP_CHECKSIG)
+            .push_x_only_key(&x_only(2))
+            .push_opcode(OP_CHECKSIGADD)
+            .push_x_only_key(&x_only(3))
+            .push_opcode(OP_CHECKSIGADD)
+            .push_int(2)
+            .push_opcode(OP_NUMEQUAL)
+            .into_script();
+        let template = TapLeafTemplate::parse(&leaf_script).unwrap();
+        assert_eq!(template.keys, vec![x_only(1), x_only(2), x_only(3)]);
+        assert_eq!(template.threshold, 2);
+
+        let (prevout, control_block) = tap_leaf_output(&leaf_script);
+        let mut psbt = spend(prevout.clone());
+        psbt.inputs[0]
+            .tap_scripts
+            .insert(control_block, (leaf_script.clone(), LeafVersion::TapScript));
+        sign_leaf(&mut psbt, &prevout, &leaf_script, &[3]);
+        let unchanged = psbt.clone();
+        assert!(finalize_psbt(&mut psbt).is_err());
+        assert_eq!(psbt, unchanged);
+
+        // Three signatures would make the count miss the threshold, so only two are used
+        sign_leaf(&mut psbt, &prevout, &leaf_script, &[1, 2]);
+        finalize_psbt(&mut psbt).unwrap();
+        let witness = psbt.inputs[0].final_script_witness.as_ref().unwrap();
+        assert_eq!(witness.len(), 5);
+        assert!(witness[0].is_empty());
+        let tx = extract_transaction(&psbt).unwrap();
+        verify_finalized_psbt(&psbt, &tx).unwrap();
+    }
+
+    #[test]
+    fn checks_leaf_timelocks() {
+        let secp = Secp256k1::new();
+        let leaf_script = bitcoin::script::Builder::new()
+            .push_int(1_000)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_DROP)
+            .push_x_only_key(&secret_key(1).x_only_public_key(&secp).0)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let (prevout, control_block) = tap_leaf_output(&leaf_script);
+        let spend_with = |sequence: u32| {
+            let mut psbt = spend(prevout.clone());
+            psbt.unsigned_tx.input[0].sequence = bitcoin::Sequence::from_consensus(sequence);
+            psbt.inputs[0].tap_scripts.insert(
+                control_block.clone(),
+                (leaf_script.clone(), LeafVersion::TapScript),
+            );
+            sign_leaf(&mut psbt, &prevout, &leaf_script, &[1]);
+            finalize_psbt(&mut psbt).unwrap();
+            extract_transaction(&psbt)
+        };
+        spend_with(1_000).unwrap();
+        let err = spend_with(999).unwrap_err();
+        assert!(err.to_string().contains("relative timelock"), "{}", err);
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::bip32::{self, ChildNumber, DerivationPath, Fingerprint, Xpub};
+use bitcoin::opcodes::all::OP_CHECKMULTISIG;
+use bitcoin::script::Builder;
+use bitcoin::secp256k1::{self, Secp256k1, Signing, Verification};
+use bitcoin::{PublicKey, ScriptBuf};
+use esp_idf_svc::sys::nvs_handle_t;
+
+use crate::bitcoin_mod::signature::KeySource;
+use crate::nvs::memory::{load_wallet_descriptors, save_wallet_descriptor};
+use crate::ui::display::LcdController;
+use crate::ui::input::Buttons;
+use crate::ui::screens::{display_multisig_wallet, display_policy_key};
+
+/// One cosigner in a multisig descriptor: `[fingerprint/origin]xpub/<0;1>/*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorKey {
+    pub fingerprint: Fingerprint,
+    pub origin_path: DerivationPath,
+    pub xpub: Xpub,
+    /// Unhardened branches below the xpub, usually 0 for receive and 1 for change
+    pub branches: Vec<u32>,
+}
+
+impl DescriptorKey {
+    fn parse(key: &str) -> Result<Self> {
+        let key = key
+            .strip_prefix('[')
+            .ok_or_else(|| anyhow!("key without origin: {}", key))?;
+        let (origin, rest) = key
+            .split_once(']')
+            .ok_or_else(|| anyhow!("unterminated key origin"))?;
+        let (fingerprint, origin_path) = origin.split_once('/').unwrap_or((origin, ""));
+        let fingerprint = Fingerprint::from_str(fingerprint)?;
+        let origin_path = DerivationPath::from_str(origin_path)?;
+
+        let (xpub, branches) = if let Some(xpub) = rest.strip_suffix("/**") {
+            (xpub, vec![0, 1])
+        } else if let Some(xpub) = rest.strip_suffix("/<0;1>/*") {
+            (xpub, vec![0, 1])
+        } else if let Some(xpub) = rest.strip_suffix("/0/*") {
+            (xpub, vec![0])
+        } else if let Some(xpub) = rest.strip_suffix("/1/*") {
+            (xpub, vec![1])
+        } else {
+            bail!("unsupported key derivation: {}", rest);
+        };
+
+        Ok(DescriptorKey {
+            fingerprint,
+            origin_path,
+            xpub: Xpub::from_str(xpub)?,
+            branches,
+        })
+    }
+
+    /// Branch and index of `origin` below this key, if the origin was derived from it
+    fn child_of(&self, origin: &bip32::KeySource) -> Option<(u32, u32)> {
+        let (fingerprint, path) = origin;
+        if *fingerprint != self.fingerprint {
+            return None;
+        }
+        let prefix = self.origin_path.as_ref();
+        let path = path.as_ref();
+        if path.len() != prefix.len() + 2 || !path.starts_with(prefix) {
+            return None;
+        }
+        match (path[prefix.len()], path[prefix.len() + 1]) {
+            (ChildNumber::Normal { index: branch }, ChildNumber::Normal { index })
+                if self.branches.contains(&branch) =>
+            {
+                Some((branch, index))
+            }
+            _ => None,
+        }
+    }
+
+    fn derive<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        branch: u32,
+        index: u32,
+    ) -> Result<secp256k1::PublicKey> {
+        let path = [
+            ChildNumber::from_normal_idx(branch)?,
+            ChildNumber::from_normal_idx(index)?,
+        ];
+        Ok(self.xpub.derive_pub(secp, &path)?.public_key)
+    }
+}
+
+/// A registered `wsh(sortedmulti(...))` wallet this device cosigns for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultisigWallet {
+    pub threshold: usize,
+    pub keys: Vec<DescriptorKey>,
+    descriptor: String,
+}
+
+impl MultisigWallet {
+    /// Parse a `wsh(sortedmulti(k,[fp/path]xpub/<0;1>/*,...))` descriptor, checksum optional
+    pub fn parse(descriptor: &str) -> Result<Self> {
+        let descriptor = descriptor.trim();
+        let body = descriptor.split('#').next().unwrap_or(descriptor);
+        let inner = body
+            .strip_prefix("wsh(sortedmulti(")
+            .and_then(|rest| rest.strip_suffix("))"))
+            .ok_or_else(|| anyhow!("only wsh(sortedmulti(...)) descriptors are supported"))?;
+
+        let mut parts = inner.split(',');
+        let threshold: usize = parts
+            .next()
+            .ok_or_else(|| anyhow!("missing threshold"))?
+            .parse()?;
+        let keys = parts
+            .map(DescriptorKey::parse)
+            .collect::<Result<Vec<_>>>()?;
+
+        if threshold == 0 || threshold > keys.len() || keys.len() > 15 {
+            bail!("invalid {}-of-{} multisig", threshold, keys.len());
+        }
+
+        Ok(MultisigWallet {
+            threshold,
+            keys,
+            descriptor: body.to_string(),
+        })
+    }
+
+    pub fn descriptor(&self) -> &str {
+        &self.descriptor
+    }
+
+    /// Witness script for the given branch and address index
+    pub fn witness_script<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        branch: u32,
+        index: u32,
+    ) -> Result<ScriptBuf> {
+        let mut pubkeys = self
+            .keys
+            .iter()
+            .map(|key| key.derive(secp, branch, index))
+            .collect::<Result<Vec<_>>>()?;
+        pubkeys.sort_by_key(|key| key.serialize());
+
+        let mut builder = Builder::new().push_int(self.threshold as i64);
+        for pubkey in pubkeys {
+            builder = builder.push_key(&PublicKey::new(pubkey));
+        }
+        Ok(builder
+            .push_int(self.keys.len() as i64)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script())
+    }
+
+    /// Branch and index `witness_script` was derived at, if it belongs to this wallet.
+    ///
+    /// The key origins only tell us where to look; the script is rebuilt from the registered
+    /// xpubs, so a host cannot slip in a cosigner key of its own.
+    pub fn locate<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        witness_script: &ScriptBuf,
+        origins: &BTreeMap<secp256k1::PublicKey, bip32::KeySource>,
+    ) -> Option<(u32, u32)> {
+        origins
+            .values()
+            .filter_map(|origin| self.keys.iter().find_map(|key| key.child_of(origin)))
+            .find(|(branch, index)| {
+                self.witness_script(secp, *branch, *index).ok().as_ref() == Some(witness_script)
+            })
+    }
+
+    /// Make sure one of the cosigner keys is ours before the wallet gets registered
+    pub(crate) fn check_own_key<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        keys: &KeySource,
+    ) -> Result<()> {
+        let fingerprint = keys.fingerprint(secp);
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.fingerprint == fingerprint)
+            .ok_or_else(|| anyhow!("descriptor does not contain this device's key"))?;
+        match keys.xpub_at(secp, &key.origin_path) {
+            Some(xpub) if xpub == key.xpub => Ok(()),
+            Some(_) => bail!("xpub for {} does not match this device", fingerprint),
+            None => bail!("multisig registration needs a master key"),
+        }
+    }
+}
+
+/// Show every cosigner of `wallet`, marking the one this device holds
+pub(crate) fn review_wallet_keys(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    wallet: &MultisigWallet,
+    own_fingerprint: Fingerprint,
+) {
+    for (index, key) in wallet.keys.iter().enumerate() {
+        display_policy_key(
+            lcd,
+            index,
+            wallet.keys.len(),
+            key,
+            key.fingerprint == own_fingerprint,
+        );
+        buttons.wait_for_press();
+    }
+}
+
+/// Validate a descriptor against the device key and, once approved on the device, store it
+pub fn register_wallet(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    descriptor: &str,
+    keys: &KeySource,
+) -> Result<MultisigWallet> {
+    let secp = Secp256k1::new();
+    let wallet = MultisigWallet::parse(descriptor)?;
+    wallet.check_own_key(&secp, keys)?;
+
+    display_multisig_wallet(lcd, &wallet);
+    buttons.wait_for_press();
+    review_wallet_keys(lcd, buttons, &wallet, keys.fingerprint(&secp));
+    let summary = format!("{} of {} multisig", wallet.threshold, wallet.keys.len());
+    lcd.write_lines(&["Register wallet", &summary, "Press OK to approve"])?;
+    if !buttons.confirm() {
+        bail!("Wallet rejected on device");
+    }
+
+    save_wallet_descriptor(handle, wallet.descriptor())
+        .map_err(|err| anyhow!("Failed to save wallet descriptor: {}", err))?;
+    Ok(wallet)
+}
+
+/// Wallets previously accepted with `register_wallet`
+pub fn registered_wallets(handle: nvs_handle_t) -> Vec<MultisigWallet> {
+    load_wallet_descriptors(handle)
+        .iter()
+        .filter_map(|descriptor| MultisigWallet::parse(descriptor).ok())
+        .collect()
+}
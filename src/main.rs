@@ -30,9 +30,14 @@ use mipidsi::{models::ST7789, options::*, Builder};
 use bitcoin::consensus::{deserialize, encode, serialize};
 use bitcoin::psbt::{self, serialize};
 use bitcoin::{Psbt, Transaction};
-use nvs::memory::nvs_example;
+use esp_idf_svc::sys::nvs_handle_t;
+use nvs::memory::{nvs_example, open_nvs_partition};
+use security::key_management::{MnemonicLength, SeedSession};
+use security::pin::{setup_device, unlock_device};
+use security::seed_storage::has_seed;
 use std::io::{self, Write};
-use ui::display::{self, example_display};
+use ui::display::{self, example_display, DisplayPins, LcdController};
+use ui::input::Buttons;
 
 extern crate bitcoin;
 
@@ -41,9 +46,13 @@ use hex;
 //mod comm;
 mod bitcoin_mod;
 mod nvs;
+mod security;
 mod ui;
 
 //use comm::wifi::config_and_connect_wifi;
+use bitcoin_mod::anti_exfil::AntiExfilRounds;
+use bitcoin_mod::commands::handle_command;
+use bitcoin_mod::musig::MusigSigner;
 use bitcoin_mod::signature::sig_example;
 
 #[derive(Debug)]
@@ -60,10 +69,74 @@ fn initialize_runtime() {
     esp_idf_svc::log::EspLogger::initialize_default();
 }
 
+/// Unlock the stored seed with its PIN, or set up a new one on a fresh device
+fn unlock_or_setup(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+) -> Result<SeedSession> {
+    if has_seed(handle)? {
+        unlock_device(lcd, buttons, handle)
+    } else {
+        setup_device(lcd, buttons, handle, MnemonicLength::Words24)
+    }
+}
+
 fn main() {
     initialize_runtime();
-    example_display();
+    let peripherals = Peripherals::take().expect("Failed to take peripherals");
+    let pins = peripherals.pins;
+    let lcd = LcdController::new(
+        peripherals.spi2,
+        DisplayPins {
+            rst: pins.gpio23,
+            dc: pins.gpio16,
+            backlight: pins.gpio4,
+            sclk: pins.gpio18,
+            sdo: pins.gpio19,
+            cs: pins.gpio5,
+        },
+    );
+    let buttons = Buttons::new(pins.gpio0, pins.gpio35).expect("Failed to initialize buttons");
+    example_display(&lcd);
      nvs_example();
     //config_and_connect_wifi();
-    sig_example();
+
+    let handle = match open_nvs_partition() {
+        Ok(handle) => handle,
+        Err(err) => {
+            eprintln!("Failed to open NVS partition: {}", err);
+            return;
+        }
+    };
+    // Nothing past this point runs before the PIN has been entered
+    let mut session = match unlock_or_setup(&lcd, &buttons, handle) {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("Failed to unlock: {}", err);
+            return;
+        }
+    };
+    sig_example(&lcd, &buttons, handle, &session);
+
+    // Host requests arrive one per line on the console; musig nonces live until their session
+    // ends, anti-exfil host commitments until they are revealed
+    let mut musig = MusigSigner::new();
+    let mut anti_exfil = AntiExfilRounds::new();
+    for line in io::stdin().lines() {
+        let Ok(line) = line else { break };
+        let reply = handle_command(
+            &lcd,
+            &buttons,
+            handle,
+            &mut session,
+            &mut musig,
+            &mut anti_exfil,
+            &line,
+        );
+        match reply {
+            Ok(reply) => println!("ok {}", reply),
+            Err(err) => println!("error {}", err),
+        }
+    }
 }
@@ -1,14 +1,15 @@
-use bitcoin::{Network, NetworkKind, PrivateKey, PublicKey, key::Secp256k1};
-use esp_idf_svc::sys::{esp_err_t, nvs_flash_init, nvs_flash_init_partition};
 use esp_idf_svc::sys::{
-    nvs_close, nvs_commit, nvs_get_str, nvs_handle_t, nvs_open, nvs_open_mode_t, nvs_set_str,
+    esp_err_t, nvs_flash_init, ESP_ERR_NVS_NOT_ENOUGH_SPACE, ESP_ERR_NVS_NOT_FOUND,
+};
+use esp_idf_svc::sys::{
+    nvs_close, nvs_commit, nvs_erase_all, nvs_erase_key, nvs_get_blob, nvs_get_str, nvs_get_u32,
+    nvs_handle_t, nvs_open, nvs_set_blob, nvs_set_str, nvs_set_u32,
 };
 
-use crate::SecretKey;
-//TODO: add security schemes to NVS
+/// Initialize the default "nvs" partition; with CONFIG_NVS_ENCRYPTION set this also reads the
+/// XTS keys from the flash-encrypted `nvs_keys` partition, which `nvs_flash_init_partition` skips
 pub fn initialize_nvs() -> Result<(), esp_err_t> {
-    let partition_label = std::ffi::CString::new("nvs").unwrap();
-    let result = unsafe { nvs_flash_init_partition(partition_label.as_ptr()) };
+    let result = unsafe { nvs_flash_init() };
     if result == 0 {
         Ok(())
     } else {
@@ -78,25 +79,200 @@ pub fn close_nvs_partition(handle: nvs_handle_t) {
     unsafe { nvs_close(handle) };
 }
 
-pub fn save_bitcoin_private_key(
+/// Number of multisig wallets that can be registered at once
+const MAX_WALLETS: usize = 4;
+
+fn wallet_slot_key(slot: usize) -> String {
+    format!("wallet_{}", slot)
+}
+
+/// Musig wallets get slots of their own, so neither kind can crowd out the other
+fn musig_slot_key(slot: usize) -> String {
+    format!("musig_{}", slot)
+}
+
+/// Store `value` in the first free slot named by `slot_key`, keeping what the others hold.
+///
+/// Only a slot NVS reports as never written is free; any other read error is returned, so a
+/// wallet that failed to load is never overwritten.
+fn save_in_free_slot(
     handle: nvs_handle_t,
-    private_key: PrivateKey,
+    slot_key: fn(usize) -> String,
+    value: &str,
 ) -> Result<(), esp_err_t> {
-    // Parse the private key
+    let mut free_slot = None;
+    for slot in 0..MAX_WALLETS {
+        match get_value(handle, &slot_key(slot)) {
+            Ok(existing) if existing == value => return Ok(()),
+            Ok(_) => {}
+            Err(err) if err == ESP_ERR_NVS_NOT_FOUND as esp_err_t => {
+                free_slot.get_or_insert(slot);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    match free_slot {
+        Some(slot) => save_value(handle, &slot_key(slot), value),
+        None => Err(ESP_ERR_NVS_NOT_ENOUGH_SPACE as esp_err_t),
+    }
+}
+
+fn load_slots(handle: nvs_handle_t, slot_key: fn(usize) -> String) -> Vec<String> {
+    (0..MAX_WALLETS)
+        .filter_map(|slot| get_value(handle, &slot_key(slot)).ok())
+        .collect()
+}
+
+/// Store a multisig wallet descriptor in the first free slot, keeping already registered ones
+pub fn save_wallet_descriptor(handle: nvs_handle_t, descriptor: &str) -> Result<(), esp_err_t> {
+    save_in_free_slot(handle, wallet_slot_key, descriptor)
+}
+
+pub fn load_wallet_descriptors(handle: nvs_handle_t) -> Vec<String> {
+    load_slots(handle, wallet_slot_key)
+}
+
+/// Store a `tr(musig(...))` descriptor in the first free musig slot
+pub fn save_musig_descriptor(handle: nvs_handle_t, descriptor: &str) -> Result<(), esp_err_t> {
+    save_in_free_slot(handle, musig_slot_key, descriptor)
+}
+
+pub fn load_musig_descriptors(handle: nvs_handle_t) -> Vec<String> {
+    load_slots(handle, musig_slot_key)
+}
+
+const FEE_LIMITS_KEY: &str = "fee_limits";
+
+pub fn save_fee_limits(handle: nvs_handle_t, limits: &str) -> Result<(), esp_err_t> {
+    save_value(handle, FEE_LIMITS_KEY, limits)
+}
+
+pub fn load_fee_limits(handle: nvs_handle_t) -> Result<String, esp_err_t> {
+    get_value(handle, FEE_LIMITS_KEY)
+}
+
+/// Encrypted seed blob, see `security::seed_storage`
+const SEED_KEY: &str = "seed";
+/// Random secret generated on first setup, mixed into the seed encryption key
+const DEVICE_SECRET_KEY: &str = "device_secret";
+/// Wrong PINs entered since the last correct one
+const PIN_FAILURES_KEY: &str = "pin_failures";
+/// Where firmware before encrypted storage kept a plaintext, trimmed WIF
+const LEGACY_PRIVATE_KEY: &str = "bitcoin_private_key";
+
+pub fn save_blob(handle: nvs_handle_t, key: &str, value: &[u8]) -> Result<(), esp_err_t> {
+    let key_cstr = std::ffi::CString::new(key).unwrap();
+    let result =
+        unsafe { nvs_set_blob(handle, key_cstr.as_ptr(), value.as_ptr() as *const _, value.len()) };
+    if result == 0 {
+        let commit_result = unsafe { nvs_commit(handle) };
+        if commit_result == 0 {
+            Ok(())
+        } else {
+            Err(commit_result)
+        }
+    } else {
+        Err(result)
+    }
+}
+
+pub fn get_blob(handle: nvs_handle_t, key: &str) -> Result<Vec<u8>, esp_err_t> {
+    let key_cstr = std::ffi::CString::new(key).unwrap();
+    let mut buffer_len: usize = 0;
+    let result =
+        unsafe { nvs_get_blob(handle, key_cstr.as_ptr(), std::ptr::null_mut(), &mut buffer_len) };
+    if result != 0 {
+        return Err(result);
+    }
+    let mut buffer: Vec<u8> = vec![0; buffer_len];
+    let result = unsafe {
+        nvs_get_blob(
+            handle,
+            key_cstr.as_ptr(),
+            buffer.as_mut_ptr() as *mut _,
+            &mut buffer_len,
+        )
+    };
+    if result == 0 {
+        Ok(buffer)
+    } else {
+        Err(result)
+    }
+}
+
+/// Remove `key`; a key that was never written counts as removed
+pub fn erase_value(handle: nvs_handle_t, key: &str) -> Result<(), esp_err_t> {
+    let key_cstr = std::ffi::CString::new(key).unwrap();
+    let result = unsafe { nvs_erase_key(handle, key_cstr.as_ptr()) };
+    if result != 0 && result != ESP_ERR_NVS_NOT_FOUND as esp_err_t {
+        return Err(result);
+    }
+    let commit_result = unsafe { nvs_commit(handle) };
+    if commit_result == 0 {
+        Ok(())
+    } else {
+        Err(commit_result)
+    }
+}
 
-    // Convert the private key to WIF format
-    let wif = private_key.to_wif();
+/// Store the encrypted seed and drop any plaintext key an older firmware left behind
+pub fn save_encrypted_seed(handle: nvs_handle_t, blob: &[u8]) -> Result<(), esp_err_t> {
+    save_blob(handle, SEED_KEY, blob)?;
+    erase_value(handle, LEGACY_PRIVATE_KEY)
+}
 
-    // Extract the first 6 characters
-    let first_six_chars = &wif[..6];
-    println!("First 6 characters: {}", first_six_chars);
+pub fn load_encrypted_seed(handle: nvs_handle_t) -> Result<Vec<u8>, esp_err_t> {
+    get_blob(handle, SEED_KEY)
+}
+
+pub fn save_device_secret(handle: nvs_handle_t, secret: &[u8]) -> Result<(), esp_err_t> {
+    save_blob(handle, DEVICE_SECRET_KEY, secret)
+}
+
+pub fn load_device_secret(handle: nvs_handle_t) -> Result<Vec<u8>, esp_err_t> {
+    get_blob(handle, DEVICE_SECRET_KEY)
+}
+
+/// Wrong PIN count; a device that never saw a wrong PIN has none stored
+pub fn load_pin_failures(handle: nvs_handle_t) -> Result<u32, esp_err_t> {
+    let key_cstr = std::ffi::CString::new(PIN_FAILURES_KEY).unwrap();
+    let mut failures: u32 = 0;
+    let result = unsafe { nvs_get_u32(handle, key_cstr.as_ptr(), &mut failures) };
+    if result == 0 || result == ESP_ERR_NVS_NOT_FOUND as esp_err_t {
+        Ok(failures)
+    } else {
+        Err(result)
+    }
+}
 
-    // Remove the first 6 characters
-    let trimmed_wif = &wif[6..];
+pub fn save_pin_failures(handle: nvs_handle_t, failures: u32) -> Result<(), esp_err_t> {
+    let key_cstr = std::ffi::CString::new(PIN_FAILURES_KEY).unwrap();
+    let result = unsafe { nvs_set_u32(handle, key_cstr.as_ptr(), failures) };
+    if result == 0 {
+        let commit_result = unsafe { nvs_commit(handle) };
+        if commit_result == 0 {
+            Ok(())
+        } else {
+            Err(commit_result)
+        }
+    } else {
+        Err(result)
+    }
+}
 
-    println!("Trimmed WIF: {}", trimmed_wif);
-    // Save the trimmed WIF to NVS
-    save_value(handle, "bitcoin_private_key", trimmed_wif)
+/// Erase every value in the namespace: seed, device secret, wallets and settings
+pub fn erase_all_values(handle: nvs_handle_t) -> Result<(), esp_err_t> {
+    let result = unsafe { nvs_erase_all(handle) };
+    if result == 0 {
+        let commit_result = unsafe { nvs_commit(handle) };
+        if commit_result == 0 {
+            Ok(())
+        } else {
+            Err(commit_result)
+        }
+    } else {
+        Err(result)
+    }
 }
 
 pub fn nvs_example() {
@@ -133,30 +309,5 @@ pub fn nvs_example() {
         Err(err) => eprintln!("Failed to retrieve value: {}", err),
     }
 
-    let private_key: PrivateKey;
-    let hex_key = "3d7eee92f66b5a9c95b0c213d4a4286105d650b39c9e1bc86c0c0c34972370f7";
-    match hex::decode(hex_key) {
-        Ok(decoded) => {
-            match SecretKey::from_slice(&decoded) {
-                Ok(secret_key) => {
-                    let secp = Secp256k1::new();
-                        private_key = PrivateKey {
-                        compressed: true,
-                        network: NetworkKind::Main,
-                        inner: secret_key,
-                    };
-                    println!("Private key: {:?}", private_key);
-                    match save_bitcoin_private_key(handle, private_key) {
-                        Ok(_) => println!("Bitcoin private key saved successfully"),
-                        Err(err) => eprintln!("Failed to save Bitcoin private key: {}", err),
-                    }
-                }
-                Err(err) => eprintln!("Failed to create secret key: {}", err),
-            }
-        }
-        Err(err) => eprintln!("Failed to decode hex key: {}", err),
-    }
-    
-
     close_nvs_partition(handle);
 }
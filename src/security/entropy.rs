@@ -0,0 +1,26 @@
+use esp_idf_svc::sys::{bootloader_random_disable, bootloader_random_enable, esp_fill_random};
+
+/// Fill `buf` from the hardware RNG.
+///
+/// The ESP32 RNG only draws on true noise while the radio or the bootloader entropy source is
+/// running; callers mix its output with secrets rather than relying on it alone.
+pub fn fill_random(buf: &mut [u8]) {
+    unsafe { esp_fill_random(buf.as_mut_ptr() as *mut _, buf.len()) };
+}
+
+/// `N` bytes from the hardware RNG
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    fill_random(&mut bytes);
+    bytes
+}
+
+/// Fill `buf` for key material, with the bootloader entropy source switched on.
+///
+/// The SAR ADC noise source keeps the RNG on true noise even with the radio off. It has to be
+/// switched off again before the ADC or the radio are used.
+pub fn fill_key_material(buf: &mut [u8]) {
+    unsafe { bootloader_random_enable() };
+    fill_random(buf);
+    unsafe { bootloader_random_disable() };
+}
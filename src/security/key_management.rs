@@ -0,0 +1,548 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, Xpriv, Xpub};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, sha512, Hash, HashEngine};
+use bitcoin::secp256k1::{Secp256k1, Signing};
+use bitcoin::NetworkKind;
+
+use crate::bitcoin_mod::signature::KeySource;
+use crate::security::entropy;
+use crate::ui::display::LcdController;
+use crate::ui::input::{Button, Buttons, TEXT_CHARSETS};
+use crate::ui::screens::{
+    display_mnemonic_words, display_passphrase_fingerprint, display_quiz_choice,
+};
+
+/// The BIP-39 English wordlist, one word per line in index order
+const WORDLIST: &str = include_str!("bip39_english.txt");
+/// PBKDF2 rounds BIP-39 uses to stretch a mnemonic into a seed
+const SEED_ROUNDS: u32 = 2048;
+/// Words shown per page while the user writes them down
+const WORDS_PER_PAGE: usize = 4;
+/// Words the user has to pick correctly before the backup counts
+const QUIZ_QUESTIONS: usize = 3;
+/// Candidates offered per quiz question, the right word among them
+const QUIZ_CHOICES: usize = 4;
+/// Longest passphrase accepted at entry
+const MAX_PASSPHRASE_LEN: usize = 64;
+/// Hardened parents kept around; accounts of a few wallets fit, the cache starts over beyond that
+const HARDENED_CACHE_SIZE: usize = 16;
+
+fn word(index: u16) -> &'static str {
+    WORDLIST
+        .lines()
+        .nth(index as usize)
+        .expect("wordlist has 2048 words")
+}
+
+fn word_index(word: &str) -> Option<u16> {
+    WORDLIST
+        .lines()
+        .position(|candidate| candidate == word)
+        .map(|index| index as u16)
+}
+
+/// Uniform random number below `bound`, without modulo bias
+fn random_below(bound: u32) -> u32 {
+    let zone = u32::MAX - u32::MAX % bound;
+    loop {
+        let value = u32::from_le_bytes(entropy::random_bytes());
+        if value < zone {
+            return value % bound;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MnemonicLength {
+    Words12,
+    Words24,
+}
+
+impl MnemonicLength {
+    fn entropy_bytes(self) -> usize {
+        match self {
+            MnemonicLength::Words12 => 16,
+            MnemonicLength::Words24 => 32,
+        }
+    }
+}
+
+/// A BIP-39 mnemonic, kept as word indices and wiped when dropped.
+///
+/// There is deliberately no `Debug` or `Display`, so the words cannot end up in a log line.
+pub struct Mnemonic {
+    indices: Vec<u16>,
+}
+
+impl Drop for Mnemonic {
+    fn drop(&mut self) {
+        self.indices.fill(0);
+    }
+}
+
+impl Mnemonic {
+    /// Fresh mnemonic from the hardware RNG
+    pub fn generate(length: MnemonicLength) -> Self {
+        let mut bytes = [0u8; 32];
+        let bytes = &mut bytes[..length.entropy_bytes()];
+        entropy::fill_key_material(bytes);
+        let mnemonic = Self::from_entropy(bytes).expect("entropy length is valid");
+        bytes.fill(0);
+        mnemonic
+    }
+
+    /// Mnemonic for 16 or 32 bytes of entropy, with its SHA-256 checksum bits appended
+    pub fn from_entropy(entropy: &[u8]) -> Result<Self> {
+        if entropy.len() != 16 && entropy.len() != 32 {
+            bail!("mnemonic entropy must be 16 or 32 bytes");
+        }
+        let checksum = sha256::Hash::hash(entropy).to_byte_array()[0];
+        let bit_count = entropy.len() * 8 + entropy.len() / 4;
+        let bit = |position: usize| {
+            let byte = entropy.get(position / 8).copied().unwrap_or(checksum);
+            byte >> (7 - position % 8) & 1
+        };
+        let indices = (0..bit_count / 11)
+            .map(|word| (0..11).fold(0u16, |index, i| index << 1 | bit(word * 11 + i) as u16))
+            .collect();
+        Ok(Mnemonic { indices })
+    }
+
+    /// Parse a 12 or 24 word phrase, checking every word and the checksum
+    pub fn parse(phrase: &str) -> Result<Self> {
+        let indices = phrase
+            .split_whitespace()
+            .enumerate()
+            .map(|(position, word)| {
+                word_index(&word.to_lowercase())
+                    .ok_or_else(|| anyhow!("word {} is not in the wordlist", position + 1))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if indices.len() != 12 && indices.len() != 24 {
+            bail!("mnemonic must have 12 or 24 words");
+        }
+        let mnemonic = Mnemonic { indices };
+        if Self::from_entropy(&mnemonic.entropy())?.indices != mnemonic.indices {
+            bail!("mnemonic checksum does not match");
+        }
+        Ok(mnemonic)
+    }
+
+    pub fn words(&self) -> Vec<&'static str> {
+        self.indices.iter().map(|index| word(*index)).collect()
+    }
+
+    /// The entropy the words encode, checksum bits dropped
+    pub fn entropy(&self) -> Vec<u8> {
+        let entropy_bits = self.indices.len() * 11 * 32 / 33;
+        let mut entropy = vec![0u8; entropy_bits / 8];
+        for position in 0..entropy_bits {
+            let index = self.indices[position / 11];
+            let bit = (index >> (10 - position % 11) & 1) as u8;
+            entropy[position / 8] |= bit << (7 - position % 8);
+        }
+        entropy
+    }
+
+    /// BIP-39 seed: PBKDF2-HMAC-SHA512 over the phrase, salted with the passphrase.
+    ///
+    /// BIP-39 normalizes both to NFKD first. Without the Unicode tables on the device only ASCII
+    /// passphrases are accepted, where normalization changes nothing.
+    pub fn to_seed(&self, passphrase: &str) -> Result<[u8; 64]> {
+        if !passphrase.is_ascii() {
+            bail!(
+                "passphrase contains non-ASCII characters; only ASCII passphrases are supported"
+            );
+        }
+        let phrase = self.words().join(" ");
+        let salt = format!("mnemonic{}", passphrase);
+        Ok(pbkdf2_hmac_sha512(
+            phrase.as_bytes(),
+            salt.as_bytes(),
+            SEED_ROUNDS,
+        ))
+    }
+
+    /// BIP-32 master key of the seed
+    pub fn master_key(&self, passphrase: &str) -> Result<Xpriv> {
+        let mut seed = self.to_seed(passphrase)?;
+        let master = Xpriv::new_master(NetworkKind::Main, &seed);
+        seed.fill(0);
+        Ok(master?)
+    }
+}
+
+/// A BIP-32 key tree rooted at the device's master key.
+///
+/// Hardened parents are cached, so signing many inputs of one account derives the account key
+/// from the root once and only the unhardened steps per input.
+pub struct KeyTree {
+    master: Xpriv,
+    fingerprint: Fingerprint,
+    hardened: Mutex<BTreeMap<DerivationPath, Xpriv>>,
+}
+
+impl KeyTree {
+    pub fn new(master: Xpriv) -> Self {
+        let fingerprint = master.fingerprint(&Secp256k1::signing_only());
+        KeyTree {
+            master,
+            fingerprint,
+            hardened: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Key tree of a mnemonic; an empty passphrase gives the standard wallet
+    pub fn from_mnemonic(mnemonic: &Mnemonic, passphrase: &str) -> Result<Self> {
+        Ok(Self::new(mnemonic.master_key(passphrase)?))
+    }
+
+    /// Master fingerprint, the first four bytes of HASH160 of the master public key
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    pub(crate) fn master(&self) -> &Xpriv {
+        &self.master
+    }
+
+    /// Extended private key at `path`, e.g. `m/84'/0'/0'/1/5`
+    pub fn derive_priv<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        path: &DerivationPath,
+    ) -> Result<Xpriv> {
+        let children: &[ChildNumber] = path.as_ref();
+        let split = children
+            .iter()
+            .rposition(ChildNumber::is_hardened)
+            .map_or(0, |last| last + 1);
+        let (hardened, unhardened) = children.split_at(split);
+
+        let parent = if hardened.is_empty() {
+            self.master
+        } else {
+            let mut cache = self
+                .hardened
+                .lock()
+                .map_err(|_| anyhow!("key cache is poisoned"))?;
+            let key = DerivationPath::from(hardened);
+            match cache.get(&key) {
+                Some(parent) => *parent,
+                None => {
+                    let parent = self.master.derive_priv(secp, &hardened)?;
+                    if cache.len() >= HARDENED_CACHE_SIZE {
+                        wipe_cache(&mut cache);
+                    }
+                    cache.insert(key, parent);
+                    parent
+                }
+            }
+        };
+        Ok(parent.derive_priv(secp, &unhardened)?)
+    }
+
+    /// Extended public key at `path`
+    pub fn xpub_at<C: Signing>(&self, secp: &Secp256k1<C>, path: &DerivationPath) -> Result<Xpub> {
+        Ok(Xpub::from_priv(secp, &self.derive_priv(secp, path)?))
+    }
+}
+
+fn wipe_cache(cache: &mut BTreeMap<DerivationPath, Xpriv>) {
+    for xpriv in cache.values_mut() {
+        xpriv.private_key.non_secure_erase();
+    }
+    cache.clear();
+}
+
+impl Drop for KeyTree {
+    fn drop(&mut self) {
+        self.master.private_key.non_secure_erase();
+        if let Ok(cache) = self.hardened.get_mut() {
+            wipe_cache(cache);
+        }
+    }
+}
+
+/// The seed in RAM and the wallet currently opened from it.
+///
+/// A passphrase is only held for as long as it takes to derive its key tree. Switching wallets
+/// derives again from the mnemonic, so it needs no reboot and nothing of it reaches NVS.
+pub struct SeedSession {
+    mnemonic: Mnemonic,
+    keys: KeySource,
+    fingerprint: Fingerprint,
+    passphrase: bool,
+}
+
+impl SeedSession {
+    /// Session on the standard wallet, the one without passphrase
+    pub fn new(mnemonic: Mnemonic) -> Result<Self> {
+        let tree = KeyTree::from_mnemonic(&mnemonic, "")?;
+        Ok(SeedSession {
+            mnemonic,
+            fingerprint: tree.fingerprint(),
+            keys: KeySource::Master(tree),
+            passphrase: false,
+        })
+    }
+
+    /// Keys of the wallet currently open, for the signer
+    pub fn keys(&self) -> &KeySource {
+        &self.keys
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    /// Whether the open wallet is a passphrase wallet
+    pub fn has_passphrase(&self) -> bool {
+        self.passphrase
+    }
+
+    fn switch_to(&mut self, tree: KeyTree, passphrase: bool) -> Fingerprint {
+        self.fingerprint = tree.fingerprint();
+        self.keys = KeySource::Master(tree);
+        self.passphrase = passphrase;
+        self.fingerprint
+    }
+
+    /// Open the wallet behind `passphrase`; an empty one is the standard wallet.
+    ///
+    /// A non-ASCII passphrase is an error and leaves the open wallet as it was.
+    pub fn use_passphrase(&mut self, passphrase: &str) -> Result<Fingerprint> {
+        let tree = KeyTree::from_mnemonic(&self.mnemonic, passphrase)?;
+        Ok(self.switch_to(tree, !passphrase.is_empty()))
+    }
+
+    /// Back to the wallet without passphrase
+    pub fn use_standard_wallet(&mut self) -> Result<Fingerprint> {
+        self.use_passphrase("")
+    }
+}
+
+/// Overwrite a secret string before its memory is freed
+fn wipe(text: String) {
+    let mut bytes = text.into_bytes();
+    bytes.fill(0);
+}
+
+/// Let the user type a passphrase and check its fingerprint before the wallet is switched.
+///
+/// Typing nothing goes back to the standard wallet.
+pub fn enter_passphrase(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    session: &mut SeedSession,
+) -> Result<Fingerprint> {
+    loop {
+        let passphrase =
+            buttons.enter_text(lcd, "Passphrase", TEXT_CHARSETS, MAX_PASSPHRASE_LEN, false);
+        if passphrase.is_empty() {
+            return session.use_standard_wallet();
+        }
+        let tree = KeyTree::from_mnemonic(&session.mnemonic, &passphrase);
+        wipe(passphrase);
+        let tree = tree?;
+
+        display_passphrase_fingerprint(lcd, tree.fingerprint());
+        if buttons.confirm() {
+            return Ok(session.switch_to(tree, true));
+        }
+    }
+}
+
+/// PBKDF2 with HMAC-SHA512 for a single 64-byte output block
+pub(crate) fn pbkdf2_hmac_sha512(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 64] {
+    let keyed = HmacEngine::<sha512::Hash>::new(password);
+    let mut engine = keyed.clone();
+    engine.input(salt);
+    engine.input(&1u32.to_be_bytes());
+    let mut block = Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+    let mut output = block;
+    for _ in 1..rounds {
+        let mut engine = keyed.clone();
+        engine.input(&block);
+        block = Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+        for (out, byte) in output.iter_mut().zip(block) {
+            *out ^= byte;
+        }
+    }
+    block.fill(0);
+    output
+}
+
+/// Page through every word so the user can write them down
+fn show_words(lcd: &LcdController, buttons: &Buttons, mnemonic: &Mnemonic) {
+    let words = mnemonic.words();
+    for (page, chunk) in words.chunks(WORDS_PER_PAGE).enumerate() {
+        display_mnemonic_words(lcd, page * WORDS_PER_PAGE, chunk, words.len());
+        buttons.wait_for_press();
+    }
+}
+
+/// Ask for a few random words; left cycles through candidates, right picks one
+fn quiz(lcd: &LcdController, buttons: &Buttons, mnemonic: &Mnemonic) -> bool {
+    let words = mnemonic.words();
+    let mut positions: Vec<usize> = Vec::with_capacity(QUIZ_QUESTIONS);
+    while positions.len() < QUIZ_QUESTIONS {
+        let position = random_below(words.len() as u32) as usize;
+        if !positions.contains(&position) {
+            positions.push(position);
+        }
+    }
+
+    let mut passed = true;
+    for position in positions {
+        let mut choices = vec![words[position]];
+        while choices.len() < QUIZ_CHOICES {
+            let decoy = word(random_below(2048) as u16);
+            if !choices.contains(&decoy) {
+                choices.push(decoy);
+            }
+        }
+        // Fisher-Yates, so the right word is not always first
+        for i in (1..choices.len()).rev() {
+            choices.swap(i, random_below(i as u32 + 1) as usize);
+        }
+
+        let mut choice = 0;
+        loop {
+            display_quiz_choice(lcd, position, choices[choice], choice, choices.len());
+            match buttons.wait_for_press() {
+                Button::Left => choice = (choice + 1) % choices.len(),
+                Button::Right => break,
+            }
+        }
+        // Keep asking after a mistake so the answer does not reveal which word was wrong
+        passed &= choices[choice] == words[position];
+    }
+    passed
+}
+
+/// Show the words, then repeat until the user proves the backup with the quiz
+pub fn backup_mnemonic(lcd: &LcdController, buttons: &Buttons, mnemonic: &Mnemonic) -> Result<()> {
+    loop {
+        show_words(lcd, buttons, mnemonic);
+        if quiz(lcd, buttons, mnemonic) {
+            return Ok(());
+        }
+        lcd.write_lines(&["Wrong word", "Check your backup", "Press to see words"])?;
+        buttons.wait_for_press();
+    }
+}
+
+/// Create a new seed on the device; it is only returned once its backup is verified
+pub fn create_mnemonic(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    length: MnemonicLength,
+) -> Result<Mnemonic> {
+    let mnemonic = Mnemonic::generate(length);
+    backup_mnemonic(lcd, buttons, &mnemonic)?;
+    Ok(mnemonic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entropy, mnemonic and seed under the passphrase "TREZOR", from the BIP-39 test vectors
+    const VECTORS: &[(&str, &str, &str)] = &[
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "80808080808080808080808080808080",
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+            "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+        ),
+        (
+            "f585c11aec520db57dd353c69554b21a89b20fb0650966fa0a9d6f74fd989d8f",
+            "void come effort suffer camp survey warrior heavy shoot primary clutch crush open amazing screen patrol group space point ten exist slush involve unfold",
+            "01f5bced59dec48e362f2c45b5de68b9fd6c92c6634f44d6d40aab69056506f0e35524a518034ddc1192e1dacd32c1ed3eaa3c3b131c88ed8e7e54c49a5d0998",
+        ),
+    ];
+
+    #[test]
+    fn bip39_vectors() {
+        for (entropy, words, seed) in VECTORS {
+            let entropy = hex::decode(entropy).unwrap();
+            let mnemonic = Mnemonic::from_entropy(&entropy).unwrap();
+            assert_eq!(mnemonic.words().join(" "), *words);
+            assert_eq!(hex::encode(mnemonic.to_seed("TREZOR").unwrap()), *seed);
+
+            let parsed = Mnemonic::parse(words).unwrap();
+            assert_eq!(parsed.entropy(), entropy);
+        }
+    }
+
+    #[test]
+    fn bip39_master_key() {
+        let mnemonic = Mnemonic::parse(VECTORS[0].1).unwrap();
+        assert_eq!(
+            mnemonic.master_key("TREZOR").unwrap().to_string(),
+            "xprv9s21ZrQH143K3h3fDYiay8mocZ3afhfULfb5GX8kCBdno77K4HiA15Tg23wpbeF1pLfs1c5SPmYHrEpTuuRhxMwvKDwqdKiGJS9XFKzUsAF"
+        );
+    }
+
+    #[test]
+    fn parse_rejects_bad_mnemonics() {
+        // Wrong checksum, unknown word, wrong length
+        assert!(Mnemonic::parse("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon").is_err());
+        assert!(Mnemonic::parse("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandonn").is_err());
+        assert!(Mnemonic::parse("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").is_err());
+    }
+
+    #[test]
+    fn switches_wallets_by_passphrase() {
+        let mut session = SeedSession::new(Mnemonic::parse(VECTORS[0].1).unwrap()).unwrap();
+        let standard = session.fingerprint();
+        let trezor = session.use_passphrase("TREZOR").unwrap();
+        assert_ne!(trezor, standard);
+        assert!(session.has_passphrase());
+
+        let err = session.use_passphrase("TR\u{c9}ZOR").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "passphrase contains non-ASCII characters; only ASCII passphrases are supported"
+        );
+        assert_eq!(session.fingerprint(), trezor);
+
+        assert_eq!(session.use_standard_wallet().unwrap(), standard);
+        assert!(!session.has_passphrase());
+    }
+
+    #[test]
+    fn pbkdf2_vectors() {
+        assert_eq!(
+            hex::encode(pbkdf2_hmac_sha512(b"password", b"salt", 1)),
+            "867f70cf1ade02cff3752599a3a53dc4af34c7a669815ae5d513554e1c8cf252c02d470a285a0501bad999bfe943c08f050235d7d68b1da55e63f73b60a57fce"
+        );
+        assert_eq!(
+            hex::encode(pbkdf2_hmac_sha512(b"password", b"salt", 2)),
+            "e1d9c16aa681708a45f5c7c4e215ceb66e011a2e9f0040713f18aefdb866d53cf76cab2868a39b9f7840edce4fef5a82be67335c77a6068e04112754f27ccf4e"
+        );
+        assert_eq!(
+            hex::encode(pbkdf2_hmac_sha512(b"password", b"salt", 4096)),
+            "d197b1b33db0143e018b12f3d1d1479e6cdebdcc97c5c0f87f6902e072f457b5143f30602641b3d55cd335988cb36b84376060ecd532e039b742a239434af2d5"
+        );
+    }
+}
@@ -0,0 +1,4 @@
+pub mod entropy;
+pub mod key_management;
+pub mod pin;
+pub mod seed_storage;
\ No newline at end of file
@@ -0,0 +1,324 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::sys::nvs_handle_t;
+
+use crate::nvs::memory::{erase_all_values, load_pin_failures, save_pin_failures};
+use crate::security::key_management::{create_mnemonic, Mnemonic, MnemonicLength, SeedSession};
+use crate::security::seed_storage::{has_seed, read_seed, store_seed, SealedSeed, WrongPin};
+use crate::ui::display::LcdController;
+use crate::ui::input::{Buttons, DIGIT_CHARSET};
+
+const MIN_PIN_LEN: usize = 4;
+const MAX_PIN_LEN: usize = 8;
+/// Wrong PINs in a row after which every secret on the device is erased
+const MAX_PIN_ATTEMPTS: u32 = 10;
+/// Wrong PINs that cost no waiting, so a typo is not punished
+const FREE_ATTEMPTS: u32 = 2;
+/// Upper bound of the wait before an attempt
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Whether the PIN has been entered since boot; every signing entry point checks it
+static UNLOCKED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_unlocked() -> bool {
+    UNLOCKED.load(Ordering::SeqCst)
+}
+
+/// Fail unless the device has been unlocked
+pub fn ensure_unlocked() -> Result<()> {
+    if !is_unlocked() {
+        bail!("Device is locked, enter the PIN first");
+    }
+    Ok(())
+}
+
+/// Lock the device again; signing needs the PIN from here on
+pub fn lock() {
+    UNLOCKED.store(false, Ordering::SeqCst);
+}
+
+/// Unlock without a seed, so tests can reach the signing paths
+#[cfg(test)]
+pub(crate) fn unlock_for_tests() {
+    UNLOCKED.store(true, Ordering::SeqCst);
+}
+
+/// Wait before the attempt that follows `failures` wrong PINs, doubling with every one
+fn attempt_delay(failures: u32) -> Duration {
+    if failures <= FREE_ATTEMPTS {
+        return Duration::ZERO;
+    }
+    let exponent = (failures - FREE_ATTEMPTS - 1).min(16);
+    Duration::from_secs(1 << exponent).min(MAX_DELAY)
+}
+
+/// Where the PIN check keeps the seed, the attempt counter and what a wipe erases
+pub trait PinStore {
+    /// Whether a seed is stored; only a seed that was never written is `Ok(false)`
+    fn has_seed(&self) -> Result<bool>;
+    /// The stored seed, checked as far as that is possible without the PIN
+    fn read_seed(&self) -> Result<SealedSeed>;
+    /// Wrong PINs entered since the last correct one
+    fn load_failures(&self) -> Result<u32>;
+    fn save_failures(&mut self, failures: u32) -> Result<()>;
+    /// Erase every secret on the device
+    fn erase_all(&mut self) -> Result<()>;
+}
+
+/// The PIN state in the device's NVS namespace
+pub struct NvsPinStore(pub nvs_handle_t);
+
+impl PinStore for NvsPinStore {
+    fn has_seed(&self) -> Result<bool> {
+        has_seed(self.0)
+    }
+
+    fn read_seed(&self) -> Result<SealedSeed> {
+        read_seed(self.0)
+    }
+
+    fn load_failures(&self) -> Result<u32> {
+        load_pin_failures(self.0).map_err(|err| anyhow!("Failed to load PIN attempts: {}", err))
+    }
+
+    fn save_failures(&mut self, failures: u32) -> Result<()> {
+        save_pin_failures(self.0, failures)
+            .map_err(|err| anyhow!("Failed to save PIN attempts: {}", err))
+    }
+
+    fn erase_all(&mut self) -> Result<()> {
+        erase_all_values(self.0).map_err(|err| anyhow!("Failed to wipe device: {}", err))
+    }
+}
+
+/// Erase every secret on the device and lock it
+fn wipe(store: &mut impl PinStore) -> Result<()> {
+    lock();
+    store.erase_all()
+}
+
+/// Check `pin` by decrypting the stored seed, counting the attempt before the check.
+///
+/// The counter is written first, so cutting power during the check still costs an attempt and
+/// the delay grows across reboots. After `MAX_PIN_ATTEMPTS` wrong PINs the device is wiped.
+/// Storage errors are returned before the counter is touched; only `WrongPin` counts.
+pub fn unlock(handle: nvs_handle_t, pin: &str) -> Result<Mnemonic> {
+    check_pin(&mut NvsPinStore(handle), pin, thread::sleep)
+}
+
+/// `unlock` against any store, with `wait` serving the delay before the attempt
+fn check_pin(
+    store: &mut impl PinStore,
+    pin: &str,
+    wait: impl FnOnce(Duration),
+) -> Result<Mnemonic> {
+    if !store.has_seed()? {
+        bail!("No seed on this device");
+    }
+    let failures = store.load_failures()?;
+    if failures >= MAX_PIN_ATTEMPTS {
+        wipe(store)?;
+        bail!("Too many wrong PINs, device wiped");
+    }
+    let sealed = store.read_seed()?;
+    store.save_failures(failures + 1)?;
+    wait(attempt_delay(failures));
+
+    match sealed.open(pin) {
+        Ok(mnemonic) => {
+            store.save_failures(0)?;
+            UNLOCKED.store(true, Ordering::SeqCst);
+            Ok(mnemonic)
+        }
+        Err(err) if !err.is::<WrongPin>() => {
+            // The blob passed its checks but did not decode; that is no guess at the PIN
+            store.save_failures(failures)?;
+            Err(err)
+        }
+        Err(_) if failures + 1 >= MAX_PIN_ATTEMPTS => {
+            wipe(store)?;
+            bail!("Too many wrong PINs, device wiped");
+        }
+        Err(_) => bail!("Wrong PIN, {} tries left", MAX_PIN_ATTEMPTS - failures - 1),
+    }
+}
+
+/// Overwrite a PIN before its memory is freed
+fn wipe_pin(pin: String) {
+    let mut bytes = pin.into_bytes();
+    bytes.fill(0);
+}
+
+/// Ask for a new PIN twice until both entries match
+fn choose_pin(lcd: &LcdController, buttons: &Buttons) -> Result<String> {
+    loop {
+        let pin = buttons.enter_text(lcd, "Choose PIN", DIGIT_CHARSET, MAX_PIN_LEN, true);
+        if pin.len() < MIN_PIN_LEN {
+            wipe_pin(pin);
+            lcd.write_lines(&["PIN too short", "Use 4 to 8 digits", "Press to retry"])?;
+            buttons.wait_for_press();
+            continue;
+        }
+        let repeated = buttons.enter_text(lcd, "Repeat PIN", DIGIT_CHARSET, MAX_PIN_LEN, true);
+        let matches = repeated == pin;
+        wipe_pin(repeated);
+        if matches {
+            return Ok(pin);
+        }
+        wipe_pin(pin);
+        lcd.write_lines(&["PINs differ", "Press to retry"])?;
+        buttons.wait_for_press();
+    }
+}
+
+/// First-time setup: a new seed with its backup checked, then a PIN to encrypt it under
+pub fn setup_device(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+    length: MnemonicLength,
+) -> Result<SeedSession> {
+    if has_seed(handle)? {
+        bail!("Device already has a seed");
+    }
+    let mnemonic = create_mnemonic(lcd, buttons, length)?;
+    let pin = choose_pin(lcd, buttons)?;
+    let stored = store_seed(handle, &mnemonic, &pin);
+    wipe_pin(pin);
+    stored?;
+    save_pin_failures(handle, 0).map_err(|err| anyhow!("Failed to save PIN attempts: {}", err))?;
+    UNLOCKED.store(true, Ordering::SeqCst);
+    SeedSession::new(mnemonic)
+}
+
+/// PIN entry screen; returns the unlocked seed or fails once the device has been wiped
+pub fn unlock_device(
+    lcd: &LcdController,
+    buttons: &Buttons,
+    handle: nvs_handle_t,
+) -> Result<SeedSession> {
+    loop {
+        let pin = buttons.enter_text(lcd, "Enter PIN", DIGIT_CHARSET, MAX_PIN_LEN, true);
+        lcd.write_lines(&["Checking PIN..."])?;
+        let unlocked = unlock(handle, &pin);
+        wipe_pin(pin);
+        match unlocked {
+            Ok(mnemonic) => return SeedSession::new(mnemonic),
+            Err(err) if !has_seed(handle)? => {
+                lcd.write_lines(&["Device wiped", "Set up a new seed"])?;
+                return Err(err);
+            }
+            Err(err) => {
+                let message = err.to_string();
+                lcd.write_lines(&[&message, "Press to retry"])?;
+                buttons.wait_for_press();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::seed_storage::seal_seed;
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    /// PIN state in memory; `broken_secret` makes reading the seed fail like a storage fault
+    #[derive(Default)]
+    struct MemoryPinStore {
+        blob: Option<Vec<u8>>,
+        broken_secret: bool,
+        failures: u32,
+    }
+
+    impl MemoryPinStore {
+        fn with_seed(pin: &str) -> Self {
+            let mnemonic = Mnemonic::from_entropy(&[0u8; 16]).unwrap();
+            MemoryPinStore {
+                blob: Some(seal_seed(&mnemonic, pin, &SECRET).unwrap()),
+                ..Self::default()
+            }
+        }
+    }
+
+    impl PinStore for MemoryPinStore {
+        fn has_seed(&self) -> Result<bool> {
+            Ok(self.blob.is_some())
+        }
+
+        fn read_seed(&self) -> Result<SealedSeed> {
+            if self.broken_secret {
+                bail!("device secret has the wrong length");
+            }
+            SealedSeed::new(self.blob.clone().unwrap(), SECRET)
+        }
+
+        fn load_failures(&self) -> Result<u32> {
+            Ok(self.failures)
+        }
+
+        fn save_failures(&mut self, failures: u32) -> Result<()> {
+            self.failures = failures;
+            Ok(())
+        }
+
+        fn erase_all(&mut self) -> Result<()> {
+            *self = Self::default();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_wrong_pins_count_as_attempts() {
+        let mut store = MemoryPinStore::with_seed("1234");
+        assert!(check_pin(&mut store, "4321", |_| {}).is_err());
+        assert_eq!(store.failures, 1);
+
+        // A broken device secret is a storage fault, not a guess
+        store.broken_secret = true;
+        assert!(check_pin(&mut store, "4321", |_| {}).is_err());
+        assert_eq!(store.failures, 1);
+
+        store.broken_secret = false;
+        assert!(check_pin(&mut store, "1234", |_| {}).is_ok());
+        assert_eq!(store.failures, 0);
+    }
+
+    #[test]
+    fn waits_longer_after_every_wrong_pin() {
+        let mut store = MemoryPinStore::with_seed("1234");
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            let _ = check_pin(&mut store, "4321", |delay| delays.push(delay.as_secs()));
+        }
+        assert_eq!(delays, [0, 0, 0, 1, 2, 4]);
+        assert_eq!(attempt_delay(MAX_PIN_ATTEMPTS - 1), Duration::from_secs(64));
+        assert_eq!(attempt_delay(40), MAX_DELAY);
+    }
+
+    #[test]
+    fn wipes_after_the_last_wrong_pin() {
+        let mut store = MemoryPinStore::with_seed("1234");
+        store.failures = MAX_PIN_ATTEMPTS - 2;
+        let err = check_pin(&mut store, "4321", |_| {}).err().unwrap();
+        assert_eq!(err.to_string(), "Wrong PIN, 1 tries left");
+
+        let err = check_pin(&mut store, "4321", |_| {}).err().unwrap();
+        assert_eq!(err.to_string(), "Too many wrong PINs, device wiped");
+        assert!(!store.has_seed().unwrap());
+    }
+
+    #[test]
+    fn wipes_a_device_already_out_of_attempts() {
+        // Power was cut after the last attempt was counted but before the wipe
+        let mut store = MemoryPinStore::with_seed("1234");
+        store.failures = MAX_PIN_ATTEMPTS;
+        let err = check_pin(&mut store, "1234", |_| {}).err().unwrap();
+        assert_eq!(err.to_string(), "Too many wrong PINs, device wiped");
+        assert!(!store.has_seed().unwrap());
+    }
+}
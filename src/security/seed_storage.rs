@@ -0,0 +1,286 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use esp_idf_svc::sys::{
+    esp_err_t, esp_flash_encryption_enabled, nvs_handle_t, ESP_ERR_NVS_NOT_FOUND,
+};
+
+use crate::nvs::memory::{
+    load_device_secret, load_encrypted_seed, save_device_secret, save_encrypted_seed,
+};
+use crate::security::entropy;
+use crate::security::key_management::{pbkdf2_hmac_sha512, Mnemonic};
+
+/// First bytes of every seed blob, so a wrong NVS entry is told apart from a wrong PIN
+const SEED_MAGIC: &[u8; 4] = b"SEED";
+/// Layout written today; a later one can still read and re-seal this one
+const SEED_VERSION: u8 = 1;
+/// PBKDF2 rounds over the PIN, roughly a second on the ESP32
+const KDF_ROUNDS: u32 = 10_000;
+/// Round counts a blob may ask for: never weaker than today, never long enough to hang the device
+const KDF_ROUNDS_RANGE: RangeInclusive<u32> = KDF_ROUNDS..=1_000_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// Magic, version, rounds, salt and nonce
+const HEADER_LEN: usize = 4 + 1 + 4 + SALT_LEN + NONCE_LEN;
+const DEVICE_SECRET_LEN: usize = 32;
+/// Domain of the HMAC that combines the stretched PIN with the device secret
+const SEED_KEY_TAG: &[u8] = b"seed-storage/key";
+
+/// The one failure that says the PIN was wrong: the AEAD tag did not check out.
+///
+/// Missing NVS entries, a bad device secret or an unknown blob layout are reported as other
+/// errors, so they never count as a PIN attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongPin;
+
+impl fmt::Display for WrongPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Wrong PIN or damaged seed")
+    }
+}
+
+impl std::error::Error for WrongPin {}
+
+/// Header in front of the ciphertext; all of it is authenticated as associated data
+struct SeedHeader {
+    version: u8,
+    kdf_rounds: u32,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+}
+
+impl SeedHeader {
+    fn new() -> Self {
+        SeedHeader {
+            version: SEED_VERSION,
+            kdf_rounds: KDF_ROUNDS,
+            salt: entropy::random_bytes(),
+            nonce: entropy::random_bytes(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(SEED_MAGIC);
+        bytes.push(self.version);
+        bytes.extend_from_slice(&self.kdf_rounds.to_be_bytes());
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+
+    /// Split a blob into its header and ciphertext
+    fn parse(blob: &[u8]) -> Result<(Self, &[u8])> {
+        if blob.len() < HEADER_LEN || &blob[..4] != SEED_MAGIC {
+            bail!("stored seed is not a seed blob");
+        }
+        let version = blob[4];
+        if version != SEED_VERSION {
+            bail!("stored seed has unknown version {}", version);
+        }
+        let mut rounds = [0u8; 4];
+        rounds.copy_from_slice(&blob[5..9]);
+        let kdf_rounds = u32::from_be_bytes(rounds);
+        // Checked before PBKDF2 runs; the AEAD would only catch a forged count afterwards
+        if !KDF_ROUNDS_RANGE.contains(&kdf_rounds) {
+            bail!("stored seed asks for {} KDF rounds", kdf_rounds);
+        }
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&blob[9..9 + SALT_LEN]);
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&blob[9 + SALT_LEN..HEADER_LEN]);
+        let header = SeedHeader {
+            version,
+            kdf_rounds,
+            salt,
+            nonce,
+        };
+        Ok((header, &blob[HEADER_LEN..]))
+    }
+
+    /// AEAD key: the PIN stretched with PBKDF2, keyed again with the device secret.
+    ///
+    /// The device secret lives in encrypted NVS, whose keys only the flash encryption key in
+    /// eFuse unlocks, so a flash dump yields neither it nor a blob that can be attacked offline.
+    /// Without the PIN the device alone cannot open it.
+    fn key(&self, pin: &str, device_secret: &[u8; DEVICE_SECRET_LEN]) -> [u8; 32] {
+        let mut stretched = pbkdf2_hmac_sha512(pin.as_bytes(), &self.salt, self.kdf_rounds);
+        let mut engine = HmacEngine::<sha256::Hash>::new(device_secret);
+        engine.input(SEED_KEY_TAG);
+        engine.input(&stretched);
+        stretched.fill(0);
+        Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+    }
+}
+
+/// Encrypt the mnemonic's entropy under `pin` and the device secret
+pub fn seal_seed(
+    mnemonic: &Mnemonic,
+    pin: &str,
+    device_secret: &[u8; DEVICE_SECRET_LEN],
+) -> Result<Vec<u8>> {
+    let header = SeedHeader::new();
+    let mut blob = header.to_bytes();
+    let mut key = header.key(pin, device_secret);
+    let mut plaintext = mnemonic.entropy();
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key)).encrypt(
+        Nonce::from_slice(&header.nonce),
+        Payload {
+            msg: &plaintext,
+            aad: &blob,
+        },
+    );
+    key.fill(0);
+    plaintext.fill(0);
+    blob.extend(ciphertext.map_err(|_| anyhow!("Failed to encrypt seed"))?);
+    Ok(blob)
+}
+
+/// Decrypt a blob from `seal_seed`; a wrong PIN and a tampered blob fail alike
+pub fn open_seed(
+    blob: &[u8],
+    pin: &str,
+    device_secret: &[u8; DEVICE_SECRET_LEN],
+) -> Result<Mnemonic> {
+    let (header, ciphertext) = SeedHeader::parse(blob)?;
+    let mut key = header.key(pin, device_secret);
+    let plaintext = ChaCha20Poly1305::new(Key::from_slice(&key)).decrypt(
+        Nonce::from_slice(&header.nonce),
+        Payload {
+            msg: ciphertext,
+            aad: &blob[..HEADER_LEN],
+        },
+    );
+    key.fill(0);
+    let mut plaintext = plaintext.map_err(|_| WrongPin)?;
+    let mnemonic = Mnemonic::from_entropy(&plaintext);
+    plaintext.fill(0);
+    mnemonic
+}
+
+/// The device secret, created from the hardware RNG the first time a seed is stored
+fn device_secret(handle: nvs_handle_t, create: bool) -> Result<[u8; DEVICE_SECRET_LEN]> {
+    let mut secret = [0u8; DEVICE_SECRET_LEN];
+    match load_device_secret(handle) {
+        Ok(stored) if stored.len() == DEVICE_SECRET_LEN => secret.copy_from_slice(&stored),
+        Ok(_) => bail!("device secret has the wrong length"),
+        Err(err) if create && err == ESP_ERR_NVS_NOT_FOUND as esp_err_t => {
+            entropy::fill_key_material(&mut secret);
+            save_device_secret(handle, &secret)
+                .map_err(|err| anyhow!("Failed to save device secret: {}", err))?;
+        }
+        Err(err) => bail!("Failed to load device secret: {}", err),
+    }
+    Ok(secret)
+}
+
+/// Encrypt the seed under `pin` and store it, replacing any seed or legacy key stored before
+pub fn store_seed(handle: nvs_handle_t, mnemonic: &Mnemonic, pin: &str) -> Result<()> {
+    // Without flash encryption the device secret is plaintext in flash and the PIN is all
+    // that stands between a flash dump and the seed
+    if !unsafe { esp_flash_encryption_enabled() } {
+        bail!("Flash encryption is off, refusing to store a seed");
+    }
+    let mut secret = device_secret(handle, true)?;
+    let blob = seal_seed(mnemonic, pin, &secret);
+    secret.fill(0);
+    save_encrypted_seed(handle, &blob?).map_err(|err| anyhow!("Failed to save seed: {}", err))
+}
+
+/// The stored seed blob and the device secret it was sealed under, checked as far as that is
+/// possible without the PIN
+pub struct SealedSeed {
+    blob: Vec<u8>,
+    device_secret: [u8; DEVICE_SECRET_LEN],
+}
+
+impl SealedSeed {
+    /// A blob from `seal_seed` and its device secret, once the blob header checks out
+    pub fn new(blob: Vec<u8>, device_secret: [u8; DEVICE_SECRET_LEN]) -> Result<Self> {
+        SeedHeader::parse(&blob)?;
+        Ok(SealedSeed {
+            blob,
+            device_secret,
+        })
+    }
+
+    /// Decrypt the seed; only a wrong PIN or a tampered ciphertext fails with `WrongPin`
+    pub fn open(&self, pin: &str) -> Result<Mnemonic> {
+        open_seed(&self.blob, pin, &self.device_secret)
+    }
+}
+
+impl Drop for SealedSeed {
+    fn drop(&mut self) {
+        self.device_secret.fill(0);
+    }
+}
+
+/// Read the stored seed and device secret and check the blob header
+pub fn read_seed(handle: nvs_handle_t) -> Result<SealedSeed> {
+    let blob =
+        load_encrypted_seed(handle).map_err(|err| anyhow!("Failed to load seed: {}", err))?;
+    SeedHeader::parse(&blob)?;
+    SealedSeed::new(blob, device_secret(handle, false)?)
+}
+
+/// Load and decrypt the stored seed
+pub fn load_seed(handle: nvs_handle_t, pin: &str) -> Result<Mnemonic> {
+    read_seed(handle)?.open(pin)
+}
+
+/// Whether a seed has been set up on this device.
+///
+/// Only a seed NVS reports as never written counts as missing; any other read error is returned,
+/// so a seed that failed to load is never taken for a fresh device and overwritten.
+pub fn has_seed(handle: nvs_handle_t) -> Result<bool> {
+    match load_encrypted_seed(handle) {
+        Ok(_) => Ok(true),
+        Err(err) if err == ESP_ERR_NVS_NOT_FOUND as esp_err_t => Ok(false),
+        Err(err) => bail!("Failed to look up seed: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvs::memory::{open_nvs_partition, save_value};
+
+    #[test]
+    fn rejects_kdf_rounds_out_of_range() {
+        let mnemonic = Mnemonic::from_entropy(&[0u8; 16]).unwrap();
+        let secret = [7u8; DEVICE_SECRET_LEN];
+        let blob = seal_seed(&mnemonic, "1234", &secret).unwrap();
+        assert_eq!(
+            open_seed(&blob, "1234", &secret).unwrap().words(),
+            mnemonic.words()
+        );
+
+        for rounds in [0, KDF_ROUNDS - 1, u32::MAX] {
+            let mut forged = blob.clone();
+            forged[5..9].copy_from_slice(&rounds.to_be_bytes());
+            let err = open_seed(&forged, "1234", &secret).err().unwrap();
+            assert!(err.to_string().contains("KDF rounds"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn only_a_missing_seed_means_no_seed() {
+        let handle = open_nvs_partition().unwrap();
+        assert!(!has_seed(handle).unwrap());
+
+        // NVS cannot read this back as a blob; that must stop setup instead of starting it
+        save_value(handle, "seed", "not a blob").unwrap();
+        assert!(has_seed(handle).is_err());
+
+        let mnemonic = Mnemonic::from_entropy(&[0u8; 16]).unwrap();
+        store_seed(handle, &mnemonic, "1234").unwrap();
+        assert!(has_seed(handle).unwrap());
+    }
+}
@@ -33,14 +33,24 @@ pub struct LcdController {
     running: Arc<AtomicBool>,
 }
 
+// Pins the ST7789 is wired to; main hands them over from `Peripherals`
+pub struct DisplayPins {
+    pub rst: Gpio23,
+    pub dc: Gpio16,
+    pub backlight: Gpio4,
+    pub sclk: Gpio18,
+    pub sdo: Gpio19,
+    pub cs: Gpio5,
+}
+
 impl LcdController {
-    pub fn new() -> Self {
+    pub fn new(spi: SPI2, pins: DisplayPins) -> Self {
         let (tx, rx) = mpsc::channel();
         let running = Arc::new(AtomicBool::new(true));
         
         // Spawn the LCD thread
         let lcd_running = Arc::clone(&running);
-        screen_thread(rx, lcd_running);
+        screen_thread(rx, lcd_running, spi, pins);
         
         // Give the thread time to initialize
         thread::sleep(Duration::from_millis(100));
@@ -107,25 +117,27 @@ fn parse_input(input: &str) -> Option<ParsedInput> {
 }
 
 // Update your screen_thread to handle the new actions
-fn screen_thread(rx: mpsc::Receiver<String>, running: Arc<AtomicBool>) {
+fn screen_thread(
+    rx: mpsc::Receiver<String>,
+    running: Arc<AtomicBool>,
+    spi: SPI2,
+    pins: DisplayPins,
+) {
     let builder = thread::Builder::new().stack_size(8192);
     builder
         .spawn(move || {
-            let peripherals = Peripherals::take().expect("Failed to take peripherals");
-            let spi = peripherals.spi2;
-
             let mut delay = Ets;
 
             // Initialize display components
-            let rst = PinDriver::input_output_od(peripherals.pins.gpio23)
+            let rst = PinDriver::input_output_od(pins.rst)
                 .expect("Failed to initialize rst pin");
-            let dc = PinDriver::input_output_od(peripherals.pins.gpio16)
+            let dc = PinDriver::input_output_od(pins.dc)
                 .expect("Failed to initialize dc pin");
-            let mut backlight = PinDriver::output(peripherals.pins.gpio4)
+            let mut backlight = PinDriver::output(pins.backlight)
                 .expect("Failed to initialize backlight pin");
-            let sclk = peripherals.pins.gpio18;
-            let sdo = peripherals.pins.gpio19;
-            let cs = peripherals.pins.gpio5;
+            let sclk = pins.sclk;
+            let sdo = pins.sdo;
+            let cs = pins.cs;
 
             let spi_driver = SpiDriver::new(
                 spi,
@@ -243,10 +255,7 @@ pub fn turn_off_lcd(controller: LcdController) {
 }
 
 // Example usage
-pub fn example_display() -> Result<()> {
-    // Create the LCD controller
-    let lcd = LcdController::new();
-    
+pub fn example_display(lcd: &LcdController) -> Result<()> {
     // Simple message
     lcd.write_message("Hello ESP32!")?;
     thread::sleep(Duration::from_secs(2));
@@ -276,19 +285,6 @@ pub fn example_display() -> Result<()> {
     lcd.write_message("Done!")?;
     thread::sleep(Duration::from_secs(2));
     
-    // Shutdown the LCD
-    lcd.shutdown();
-    
     println!("Main finished.");
     Ok(())
 }
-
-// For your Bitcoin transaction signing workflow, you could use it like:
-pub fn display_transaction_info(lcd: &LcdController, tx_id: &str, amount: &str) {
-    lcd.write_lines(&[
-        "Transaction:",
-        &format!("ID: {}...", &tx_id[..8]),
-        &format!("Amount: {}", amount),
-        "Press OK to sign"
-    ]).expect("Failed to display transaction info");
-}
\ No newline at end of file
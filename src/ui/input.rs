@@ -0,0 +1,143 @@
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use esp_idf_svc::hal::gpio::{Gpio0, Gpio35, Input, PinDriver, Pull};
+
+use crate::ui::display::LcdController;
+use crate::ui::screens::display_text_entry;
+
+/// Printable ASCII in the groups text entry offers, each with the label shown for it
+pub const TEXT_CHARSETS: &[(&str, &str)] = &[
+    ("abc", "abcdefghijklmnopqrstuvwxyz"),
+    ("ABC", "ABCDEFGHIJKLMNOPQRSTUVWXYZ"),
+    ("123", "0123456789"),
+    ("#+=", " !\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~"),
+];
+/// Digits only, for PINs
+pub const DIGIT_CHARSET: &[(&str, &str)] = &[("123", "0123456789")];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    /// GPIO0, used to reject or move to the next option
+    Left,
+    /// GPIO35, used to confirm
+    Right,
+}
+
+/// One entry the user can step to while typing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextChoice {
+    Char(char),
+    Charset(usize),
+    Back,
+    Delete,
+    Done,
+}
+
+/// The two push buttons next to the display
+pub struct Buttons {
+    left: PinDriver<'static, Gpio0, Input>,
+    right: PinDriver<'static, Gpio35, Input>,
+}
+
+impl Buttons {
+    /// Take the button pins handed over from `Peripherals`
+    pub fn new(left: Gpio0, right: Gpio35) -> Result<Self> {
+        let mut left = PinDriver::input(left)?;
+        left.set_pull(Pull::Up)?;
+        // GPIO35 is input only and has an external pull-up on the board
+        let right = PinDriver::input(right)?;
+        Ok(Self { left, right })
+    }
+
+    /// Block until a button is pressed and released again
+    pub fn wait_for_press(&self) -> Button {
+        loop {
+            let pressed = if self.left.is_low() {
+                Some(Button::Left)
+            } else if self.right.is_low() {
+                Some(Button::Right)
+            } else {
+                None
+            };
+
+            if let Some(button) = pressed {
+                // Wait for release so one press is never read twice
+                while self.left.is_low() || self.right.is_low() {
+                    thread::sleep(Duration::from_millis(20));
+                }
+                return button;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Wait for a yes/no answer: right confirms, left rejects
+    pub fn confirm(&self) -> bool {
+        self.wait_for_press() == Button::Right
+    }
+
+    /// Type text with the two buttons: left steps to the next choice, right takes it.
+    ///
+    /// With several charsets the user first opens one, types from it and backs out to delete or
+    /// finish. With `masked` only the length of the text is shown.
+    pub fn enter_text(
+        &self,
+        lcd: &LcdController,
+        title: &str,
+        charsets: &[(&str, &str)],
+        max_len: usize,
+        masked: bool,
+    ) -> String {
+        // Sized up front so typing never reallocates and leaves copies of a secret behind
+        let mut text = String::with_capacity(max_len);
+        let mut open = if charsets.len() == 1 { Some(0) } else { None };
+        let mut position = 0;
+        loop {
+            let mut choices: Vec<TextChoice> = match open {
+                Some(set) => charsets[set].1.chars().map(TextChoice::Char).collect(),
+                None => (0..charsets.len()).map(TextChoice::Charset).collect(),
+            };
+            if open.is_some() && charsets.len() > 1 {
+                choices.push(TextChoice::Back);
+            } else {
+                choices.push(TextChoice::Delete);
+                choices.push(TextChoice::Done);
+            }
+            let choice = choices[position % choices.len()];
+            let label = match choice {
+                TextChoice::Char(' ') => "space".to_string(),
+                TextChoice::Char(c) => c.to_string(),
+                TextChoice::Charset(set) => charsets[set].0.to_string(),
+                TextChoice::Back => "back".to_string(),
+                TextChoice::Delete => "delete".to_string(),
+                TextChoice::Done => "done".to_string(),
+            };
+            if masked {
+                display_text_entry(lcd, title, &"*".repeat(text.len()), &label);
+            } else {
+                display_text_entry(lcd, title, &text, &label);
+            }
+
+            match self.wait_for_press() {
+                Button::Left => position = (position + 1) % choices.len(),
+                Button::Right => match choice {
+                    TextChoice::Char(c) if text.len() < max_len => text.push(c),
+                    TextChoice::Char(_) => {}
+                    TextChoice::Charset(set) => {
+                        open = Some(set);
+                        position = 0;
+                    }
+                    TextChoice::Back => {
+                        position = open.take().unwrap_or(0);
+                    }
+                    TextChoice::Delete => {
+                        text.pop();
+                    }
+                    TextChoice::Done => return text,
+                },
+            }
+        }
+    }
+}
@@ -1 +1,3 @@
-pub mod display;
\ No newline at end of file
+pub mod display;
+pub mod input;
+pub mod screens;
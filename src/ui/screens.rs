@@ -0,0 +1,291 @@
+use bitcoin::bip32::Fingerprint;
+use bitcoin::sighash::TapSighashType;
+use bitcoin::Amount;
+
+use crate::bitcoin_mod::fee_policy::{FeeAction, FeeLimits, FeeRule, FeeViolation};
+use crate::bitcoin_mod::musig::{MusigKey, MusigWallet};
+use crate::bitcoin_mod::policy::WalletPolicy;
+use crate::bitcoin_mod::signature::SignedLeaf;
+use crate::bitcoin_mod::transaction::{OutputSummary, TxSummary};
+use crate::bitcoin_mod::wallet::{DescriptorKey, MultisigWallet};
+use crate::ui::display::LcdController;
+
+/// Characters of an address shown per line
+const ADDRESS_LINE_WIDTH: usize = 22;
+
+// For your Bitcoin transaction signing workflow, you could use it like:
+pub fn display_transaction_info(lcd: &LcdController, tx_id: &str, amount: &str) {
+    lcd.write_lines(&[
+        "Transaction:",
+        &format!("ID: {}...", &tx_id[..8]),
+        &format!("Amount: {}", amount),
+        "Press OK to sign"
+    ]).expect("Failed to display transaction info");
+}
+
+// One payment of a transaction; the address is wrapped so all of it is on screen
+pub fn display_tx_output(lcd: &LcdController, index: usize, total: usize, output: &OutputSummary) {
+    let title = format!("Output {}/{}", index + 1, total);
+    let amount = output.amount.to_string();
+    let destination = match &output.address {
+        Some(address) => address.to_string(),
+        None if output.script_pubkey.is_op_return() => "OP_RETURN data".to_string(),
+        None => format!("Script {}", output.script_pubkey.to_hex_string()),
+    };
+    let chars: Vec<char> = destination.chars().collect();
+    let wrapped: Vec<String> = chars
+        .chunks(ADDRESS_LINE_WIDTH)
+        .map(|chunk| chunk.iter().collect())
+        .collect();
+    let mut lines = vec![title.as_str(), amount.as_str()];
+    lines.extend(wrapped.iter().map(String::as_str));
+    lines.push("Press to continue");
+    lcd.write_lines(&lines).expect("Failed to display output");
+}
+
+// Fee, change and anything else about a transaction that is not a payment
+pub fn display_tx_fee(lcd: &LcdController, summary: &TxSummary) {
+    let fee = match (summary.fee, summary.fee_rate) {
+        (Some(fee), Some(rate)) => vec![format!("Fee {}", fee), format!("{:.1} sat/vB", rate)],
+        _ => vec!["Fee unknown".to_string()],
+    };
+    let change: Vec<Amount> = summary
+        .outputs
+        .iter()
+        .filter(|output| output.is_change)
+        .map(|output| output.amount)
+        .collect();
+    let change = if change.is_empty() {
+        "No change".to_string()
+    } else {
+        format!("Change {}", change.iter().copied().sum::<Amount>())
+    };
+    let mut lines: Vec<&str> = fee.iter().map(String::as_str).collect();
+    lines.push(&change);
+    let lock_time = format!("Locktime {}", summary.lock_time);
+    if summary.lock_time.to_consensus_u32() != 0 {
+        lines.push(&lock_time);
+    }
+    lines.push("Press to continue");
+    lcd.write_lines(&lines).expect("Failed to display fee");
+}
+
+// Name the tapscript leaf a script-path signature commits to
+pub fn display_taproot_leaf(lcd: &LcdController, leaf: &SignedLeaf) {
+    let leaf_hash = leaf.leaf_hash.to_string();
+    lcd.write_lines(&[
+        &format!("Input {} script path", leaf.input),
+        leaf.label(),
+        &format!("Leaf: {}...", &leaf_hash[..8]),
+        "Right: sign",
+        "Left: skip leaf"
+    ]).expect("Failed to display taproot leaf");
+}
+
+// First page of a wallet policy registration
+pub fn display_wallet_policy(lcd: &LcdController, policy: &WalletPolicy, wallet: &MultisigWallet) {
+    lcd.write_lines(&[
+        "Register wallet?",
+        &policy.name,
+        &format!("{} of {} multisig", wallet.threshold, wallet.keys.len()),
+        "Press to review keys"
+    ]).expect("Failed to display wallet policy");
+}
+
+// First page of a multisig descriptor registration
+pub fn display_multisig_wallet(lcd: &LcdController, wallet: &MultisigWallet) {
+    lcd.write_lines(&[
+        "Register wallet?",
+        "wsh sortedmulti",
+        &format!("{} of {} multisig", wallet.threshold, wallet.keys.len()),
+        "Press to review keys"
+    ]).expect("Failed to display multisig wallet");
+}
+
+// One cosigner of a wallet policy; the xpub is shortened to its ends to fit the screen
+pub fn display_policy_key(
+    lcd: &LcdController,
+    index: usize,
+    total: usize,
+    key: &DescriptorKey,
+    ours: bool,
+) {
+    let xpub = key.xpub.to_string();
+    lcd.write_lines(&[
+        &format!("Key {}/{}", index + 1, total),
+        if ours { "This device" } else { "Cosigner" },
+        &format!("[{}]", key.fingerprint),
+        &format!("{}...", &xpub[..16]),
+        &format!("...{}", &xpub[xpub.len() - 16..]),
+    ]).expect("Failed to display policy key");
+}
+
+// First page of a musig descriptor registration
+pub fn display_musig_wallet(lcd: &LcdController, wallet: &MusigWallet) {
+    lcd.write_lines(&[
+        "Register wallet?",
+        "tr musig",
+        &format!("{} participants", wallet.keys.len()),
+        "Press to review keys"
+    ]).expect("Failed to display musig wallet");
+}
+
+// One participant of a musig wallet; the key is wrapped so all of it is on screen
+pub fn display_musig_key(
+    lcd: &LcdController,
+    index: usize,
+    total: usize,
+    key: &MusigKey,
+    ours: bool,
+) {
+    let title = format!("Key {}/{}", index + 1, total);
+    let origin = key.origin.as_ref().map(|(fingerprint, _)| format!("[{}]", fingerprint));
+    let pubkey = key.pubkey.to_string();
+    let mut lines = vec![title.as_str(), if ours { "This device" } else { "Cosigner" }];
+    lines.extend(origin.as_deref());
+    lines.extend(pubkey.as_bytes().chunks(ADDRESS_LINE_WIDTH).map(|chunk| {
+        std::str::from_utf8(chunk).expect("hex is ASCII")
+    }));
+    lcd.write_lines(&lines).expect("Failed to display musig key");
+}
+
+// Fee rules about to replace the stored ones, each with what breaking it does
+pub fn display_fee_limits(lcd: &LcdController, limits: &FeeLimits) {
+    let action = |action: FeeAction| match action {
+        FeeAction::Block => "block",
+        FeeAction::Warn => "warn",
+    };
+    let max_fee = match &limits.max_fee {
+        Some(limit) => format!("Fee {} sat {}", limit.value.to_sat(), action(limit.action)),
+        None => "No fee limit".to_string(),
+    };
+    let max_fee_rate = match &limits.max_fee_rate {
+        Some(limit) => format!(
+            "Rate {} sat/vB {}",
+            limit.value.to_sat_per_vb_ceil(),
+            action(limit.action)
+        ),
+        None => "No rate limit".to_string(),
+    };
+    let max_share = match &limits.max_fee_basis_points {
+        Some(limit) => format!(
+            "Share {}.{:02}% {}",
+            limit.value / 100,
+            limit.value % 100,
+            action(limit.action)
+        ),
+        None => "No share limit".to_string(),
+    };
+    lcd.write_lines(&["New fee limits", &max_fee, &max_fee_rate, &max_share, "Press OK to save"])
+        .expect("Failed to display fee limits");
+}
+
+// A broken fee rule; blocking ones offer no way to continue
+pub fn display_fee_violation(lcd: &LcdController, violation: &FeeViolation) {
+    let (value, limit) = match &violation.rule {
+        FeeRule::UnknownFee => ("Input amounts".to_string(), "are missing".to_string()),
+        FeeRule::AbsoluteFee { fee, limit } => (
+            format!("Fee {} sat", fee.to_sat()),
+            format!("Limit {} sat", limit.to_sat()),
+        ),
+        FeeRule::FeeRate { rate, limit } => (
+            format!("Rate {} sat/vB", rate.to_sat_per_vb_ceil()),
+            format!("Limit {} sat/vB", limit.to_sat_per_vb_ceil()),
+        ),
+        FeeRule::FeeShare { basis_points, limit } => (
+            format!("Fee {}.{:02}% of spend", basis_points / 100, basis_points % 100),
+            format!("Limit {}.{:02}%", limit / 100, limit % 100),
+        ),
+    };
+    let (title, prompt) = match violation.action {
+        FeeAction::Block => ("Fee too high", "Signing blocked"),
+        FeeAction::Warn => ("Fee warning", "Press OK to accept"),
+    };
+    lcd.write_lines(&[title, &value, &limit, prompt])
+        .expect("Failed to display fee violation");
+}
+
+// What a non-standard sighash lets others change after we sign
+pub fn display_sighash_warning(lcd: &LcdController, input: usize, sighash_type: TapSighashType) {
+    let (label, outputs, inputs) = match sighash_type {
+        TapSighashType::None => ("NONE", "Outputs may change", None),
+        TapSighashType::Single => ("SINGLE", "Only its output fixed", None),
+        TapSighashType::AllPlusAnyoneCanPay => {
+            ("ALL|ANYONECANPAY", "Outputs are fixed", Some("Inputs may be added"))
+        }
+        TapSighashType::NonePlusAnyoneCanPay => {
+            ("NONE|ANYONECANPAY", "Outputs may change", Some("Inputs may be added"))
+        }
+        TapSighashType::SinglePlusAnyoneCanPay => {
+            ("SINGLE|ANYONECANPAY", "Only its output fixed", Some("Inputs may be added"))
+        }
+        TapSighashType::All | TapSighashType::Default => ("ALL", "Outputs are fixed", None),
+    };
+    let title = format!("Input {} sighash", input);
+    let mut lines = vec![title.as_str(), label, outputs];
+    lines.extend(inputs);
+    lines.push("Press OK to sign");
+    lcd.write_lines(&lines).expect("Failed to display sighash warning");
+}
+
+// One page of a message to sign; an empty message still gets a page saying so
+pub fn display_message_page(lcd: &LcdController, page: usize, pages: usize, text: &[String]) {
+    let title = format!("Message {}/{}", page + 1, pages.max(1));
+    let mut lines = vec![title.as_str()];
+    if text.iter().all(|line| line.is_empty()) && pages <= 1 {
+        lines.push("(empty message)");
+    }
+    lines.extend(text.iter().map(String::as_str));
+    lines.push("Press to continue");
+    lcd.write_lines(&lines).expect("Failed to display message");
+}
+
+// One page of seed words to write down, numbered from one
+pub fn display_mnemonic_words(lcd: &LcdController, first: usize, words: &[&str], total: usize) {
+    let title = format!("Words {}-{} of {}", first + 1, first + words.len(), total);
+    let numbered: Vec<String> = words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| format!("{:>2}. {}", first + i + 1, word))
+        .collect();
+    let mut lines = vec![title.as_str()];
+    lines.extend(numbered.iter().map(String::as_str));
+    lines.push("Press to continue");
+    lcd.write_lines(&lines).expect("Failed to display seed words");
+}
+
+// One candidate for a backup quiz question
+pub fn display_quiz_choice(
+    lcd: &LcdController,
+    position: usize,
+    word: &str,
+    choice: usize,
+    choices: usize,
+) {
+    let title = format!("Which is word #{}?", position + 1);
+    let option = format!("Option {}/{}", choice + 1, choices);
+    lcd.write_lines(&[&title, word, &option, "Left: next option", "Right: select"])
+        .expect("Failed to display quiz");
+}
+
+// Text being typed with the buttons; long text scrolls so its end stays visible
+pub fn display_text_entry(lcd: &LcdController, title: &str, text: &str, choice: &str) {
+    let chars: Vec<char> = text.chars().collect();
+    let visible: String = chars[chars.len().saturating_sub(24)..].iter().collect();
+    let choice = format!("> {}", choice);
+    lcd.write_lines(&[title, &visible, "", &choice, "Left: next", "Right: select"])
+        .expect("Failed to display text entry");
+}
+
+// Fingerprint of the wallet a passphrase opens; a typo shows up as an unfamiliar value
+pub fn display_passphrase_fingerprint(lcd: &LcdController, fingerprint: Fingerprint) {
+    let fingerprint = fingerprint.to_string();
+    lcd.write_lines(&[
+        "Passphrase wallet",
+        "Fingerprint",
+        &fingerprint,
+        "Right: use wallet",
+        "Left: type again",
+    ])
+    .expect("Failed to display fingerprint");
+}